@@ -0,0 +1,505 @@
+use crate::{
+    patch_validator::Patch,
+    stats::Stats,
+    tester::{self, PatchProcessor, RunReport},
+};
+use futures::stream::{Stream, StreamExt};
+use serde::{de::IgnoredAny, Serialize};
+use std::{
+    collections::HashSet,
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+};
+use tokio::{
+    fs,
+    sync::{mpsc, Mutex, MutexGuard},
+    task::{self, JoinHandle},
+};
+
+#[cfg(feature = "metrics")]
+use crate::metrics::{self, Histogram};
+#[cfg(feature = "metrics")]
+use std::time::Instant;
+
+/// Writes the value as pretty JSON to a file in the given directory.
+/// # Arguments
+/// * dir - output directory. If [None], nothing is written.
+/// * name - name of the file, without the extension.
+/// * report - the value to write.
+/// # Returns
+/// Path to the written file, if any.
+pub async fn write_report<T: Serialize>(
+    dir: Option<&Path>,
+    name: &str,
+    report: &T,
+) -> io::Result<Option<PathBuf>> {
+    let dir = match dir {
+        Some(dir) => dir,
+        None => return Ok(None),
+    };
+
+    let path = dir.join(format!("{}.json", name));
+    write_json(&path, report).await?;
+
+    Ok(Some(path))
+}
+
+/// Atomically writes the value as pretty JSON to the given path, through a temporary file.
+pub async fn write_json<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    let buf = serde_json::to_vec_pretty(value)
+        .map_err(|error| io::Error::other(format!("failed to serialize report: {}", error)))?;
+
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let tmp = tempfile::Builder::new()
+        .prefix(path.file_name().unwrap_or_default())
+        .suffix(".tmp")
+        .tempfile_in(dir)?
+        .into_temp_path();
+    fs::write(&tmp, &buf[..]).await?;
+    tmp.persist(path)?;
+
+    Ok(())
+}
+
+/// A background worker saving [RunReport]s one at a time,
+/// so that report serialization and IO do not contend with the testing process.
+/// It also removes discarded artifacts directories, once the reports are saved.
+struct ReportWriter {
+    sender: mpsc::Sender<(Patch, RunReport, Option<PathBuf>)>,
+    worker: JoinHandle<Vec<Patch>>,
+}
+
+impl ReportWriter {
+    /// Number of reports that can wait in the queue before [ReportWriter::save] blocks.
+    const QUEUE_SIZE: usize = 16;
+
+    /// # Arguments
+    /// * reports_dir - output directory for reports. If [None], reports are discarded.
+    /// # Returns
+    /// A new instance of this struct.
+    fn new(reports_dir: Option<PathBuf>) -> Self {
+        let (sender, mut receiver) =
+            mpsc::channel::<(Patch, RunReport, Option<PathBuf>)>(Self::QUEUE_SIZE);
+
+        let worker = task::spawn(async move {
+            let mut failed = Vec::new();
+
+            while let Some((patch, report, discard)) = receiver.recv().await {
+                match write_report(reports_dir.as_deref(), patch.id(), &report).await {
+                    Ok(Some(path)) => log::info!(
+                        "Successfuly saved report for solution {} at {}.",
+                        patch,
+                        path.display()
+                    ),
+                    Ok(None) => {}
+                    Err(error) => {
+                        log::error!(
+                            "An error occurred when saving the report for solution {}: {}.",
+                            patch,
+                            error
+                        );
+                        failed.push(patch);
+                        continue;
+                    }
+                }
+
+                if let Some(dir) = discard {
+                    match fs::remove_dir_all(&dir).await {
+                        Ok(()) => log::debug!(
+                            "Removed artifacts of solution {} at {}.",
+                            patch,
+                            dir.display()
+                        ),
+                        Err(error) => log::warn!(
+                            "Failed to remove artifacts of solution {} at {}. Error: {}.",
+                            patch,
+                            dir.display(),
+                            error
+                        ),
+                    }
+                }
+            }
+
+            failed
+        });
+
+        Self { sender, worker }
+    }
+
+    /// Queues the report for saving. Waits if the queue is full.
+    /// # Arguments
+    /// * patch - the processed solution.
+    /// * report - report from processing the solution.
+    /// * discard - artifacts directory of the solution to remove after the report is saved.
+    async fn save(&self, patch: Patch, report: RunReport, discard: Option<PathBuf>) {
+        if self.sender.send((patch, report, discard)).await.is_err() {
+            log::error!("Report writer unexpectedly died.");
+        }
+    }
+
+    /// Waits until all queued reports are saved.
+    /// # Returns
+    /// Solutions for which the reports could not be saved.
+    async fn finish(self) -> Vec<Patch> {
+        drop(self.sender);
+        self.worker.await.expect("report writer panicked")
+    }
+}
+
+/// Options of processing a batch of [Patch]es.
+#[derive(Debug, Clone, Default)]
+pub struct BatchOptions {
+    /// Maximal count of solutions processed at once.
+    /// If [None], all solutions from the stream are processed at once.
+    pub max_in_flight: Option<usize>,
+    /// Reports directory of a previous run.
+    /// If set, only the tests that failed in the previous reports are run.
+    pub rerun_failed: Option<PathBuf>,
+    /// Reports directory of a resumed run.
+    /// If set, solutions that already have a valid report there are skipped.
+    pub resume_from: Option<PathBuf>,
+    /// Output directory for reports. If [None], reports are not saved.
+    pub reports_dir: Option<PathBuf>,
+}
+
+/// A batch of [Patch]es processed with a single [PatchProcessor].
+/// Reports are saved in the background and statistics are gathered along the way.
+pub struct Batch {
+    processor: PatchProcessor,
+    options: BatchOptions,
+    report_writer: ReportWriter,
+    stats: Mutex<Stats>,
+    #[cfg(feature = "metrics")]
+    processing_times: Mutex<Histogram>,
+}
+
+impl Batch {
+    /// # Arguments
+    /// * processor - processor used for every solution.
+    /// * options - options of the batch.
+    /// # Returns
+    /// A new instance of this struct.
+    pub fn new(processor: PatchProcessor, options: BatchOptions) -> Self {
+        let report_writer = ReportWriter::new(options.reports_dir.clone());
+
+        Self {
+            processor,
+            options,
+            report_writer,
+            stats: Default::default(),
+            #[cfg(feature = "metrics")]
+            processing_times: Default::default(),
+        }
+    }
+
+    /// # Returns
+    /// The processor used for every solution.
+    pub fn processor(&self) -> &PatchProcessor {
+        &self.processor
+    }
+
+    /// # Returns
+    /// Statistics gathered so far.
+    /// Solutions rejected before entering the batch should be recorded here.
+    pub async fn stats(&self) -> MutexGuard<'_, Stats> {
+        self.stats.lock().await
+    }
+
+    /// # Returns
+    /// Metrics of the batch in the Prometheus text format (see [metrics::render]).
+    #[cfg(feature = "metrics")]
+    pub async fn render_metrics(&self) -> String {
+        let stats = self.stats.lock().await;
+        let processing_times = self.processing_times.lock().await;
+        metrics::render(&stats, self.processor.spawner.running(), &processing_times)
+    }
+
+    /// # Returns
+    /// Names of the tests to rerun for the given solution, based on its previous report.
+    /// [None] if all tests should be run.
+    async fn rerun_filter(&self, patch: &Patch) -> Option<HashSet<String>> {
+        let path = self
+            .options
+            .rerun_failed
+            .as_ref()?
+            .join(format!("{}.json", patch.id()));
+
+        let saved = match fs::read(&path).await {
+            Ok(saved) => saved,
+            Err(error) => {
+                log::info!(
+                    "No previous report for solution {} ({}), running all tests.",
+                    patch,
+                    error
+                );
+                return None;
+            }
+        };
+
+        let filter = tester::tests_to_rerun(&saved);
+        match filter.as_ref() {
+            Some(tests) => log::info!(
+                "Rerunning tests {:?} for solution {}.",
+                tests,
+                patch
+            ),
+            None => log::info!(
+                "Previous report for solution {} is unusable or its build failed, running all tests.",
+                patch
+            ),
+        }
+
+        filter
+    }
+
+    /// # Returns
+    /// Whether the run is resumed and the given solution already has a valid report.
+    async fn already_processed(&self, patch: &Patch) -> bool {
+        let dir = match self.options.resume_from.as_ref() {
+            Some(dir) => dir,
+            None => return false,
+        };
+
+        let path = dir.join(format!("{}.json", patch.id()));
+        match fs::read(&path).await {
+            Ok(saved) => serde_json::from_slice::<IgnoredAny>(&saved).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    async fn process<F, Fut>(&self, patch: Patch, on_result: &F)
+    where
+        F: Fn(&Patch, &RunReport) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        if self.already_processed(&patch).await {
+            log::info!(
+                "Skipping solution {}, already processed in a previous run.",
+                patch
+            );
+            self.stats.lock().await.solution_resumed();
+            return;
+        }
+        log::info!("Starting to process solution {}.", patch);
+
+        let filter = self.rerun_filter(&patch).await;
+        #[cfg(feature = "metrics")]
+        let start = Instant::now();
+        let run_result = self
+            .processor
+            .process_filtered(&patch, filter.as_ref())
+            .await;
+        #[cfg(feature = "metrics")]
+        self.processing_times.lock().await.observe(start.elapsed());
+        self.stats.lock().await.patch_processed(&patch, &run_result);
+        let report = match run_result {
+            Ok(report) => {
+                log::info!("Successfuly tested solution {}.", patch);
+                report
+            }
+            Err(error) => {
+                log::error!(
+                    "An error occurred when testing solution {}: {}.",
+                    patch,
+                    error
+                );
+                return;
+            }
+        };
+
+        on_result(&patch, &report).await;
+        let discard = self.processor.artifacts_to_discard(&patch, &report);
+        self.report_writer.save(patch, report, discard).await;
+    }
+
+    /// Processes all solutions from the given stream, respecting [BatchOptions::max_in_flight].
+    /// Can be called many times. Dropping the returned future cancels the solutions in flight.
+    /// # Arguments
+    /// * patches - solutions to process.
+    /// * on_result - called with every produced report, before it is saved.
+    ///   The returned future is awaited before the report is queued for saving.
+    pub async fn run<S, F, Fut>(&self, patches: S, on_result: F)
+    where
+        S: Stream<Item = Patch>,
+        F: Fn(&Patch, &RunReport) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        patches
+            .for_each_concurrent(self.options.max_in_flight, |patch| {
+                self.process(patch, &on_result)
+            })
+            .await
+    }
+
+    /// Waits until all reports are saved.
+    /// # Returns
+    /// Statistics of the batch.
+    pub async fn finish(self) -> Stats {
+        let mut stats = self.stats.into_inner();
+        for patch in self.report_writer.finish().await {
+            stats.saving_report_failed(&patch);
+        }
+
+        stats
+    }
+}
+
+/// Processes all solutions from the given stream and waits until their reports are saved.
+/// # Arguments
+/// * processor - processor used for every solution.
+/// * patches - solutions to process.
+/// * options - options of the batch.
+/// * on_result - called with every produced report, before it is saved (see [Batch::run]).
+/// # Returns
+/// Statistics of the batch.
+pub async fn run_batch<S, F, Fut>(
+    processor: PatchProcessor,
+    patches: S,
+    options: BatchOptions,
+    on_result: F,
+) -> Stats
+where
+    S: Stream<Item = Patch>,
+    F: Fn(&Patch, &RunReport) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let batch = Batch::new(processor, options);
+    batch.run(patches, on_result).await;
+    batch.finish().await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        executor::ExecutorConfig,
+        patch_validator::PatchValidator,
+        qemu::{ImageBuilder, ImageFormat, QemuConfig, QemuSpawner},
+        ssh::SshAction,
+        test_util::fake_command,
+        tester::{ArtifactsLayout, KeepArtifacts, RunConfig, Scenario, Step},
+    };
+    use futures::stream;
+    use std::{sync::Mutex as SyncMutex, time::Duration};
+
+    /// # Returns
+    /// A processor with fake QEMU commands. Every build fails, as the QEMU process exits right away.
+    async fn fake_processor(dir: &Path) -> PatchProcessor {
+        let qemu_img = fake_command(dir, "qemu-img", "exit 0\n").await;
+        let qemu_system = fake_command(dir, "qemu-system", "exit 1\n").await;
+        let artifacts_root = dir.join("artifacts");
+        fs::create_dir(&artifacts_root).await.unwrap();
+
+        PatchProcessor {
+            spawner: QemuSpawner::new(
+                1,
+                QemuConfig {
+                    cmd: qemu_system.into_os_string(),
+                    ..QemuConfig::test()
+                },
+            ),
+            builder: ImageBuilder {
+                cmd: qemu_img.into_os_string(),
+            },
+            base_image: dir.join("base.img"),
+            base_image_format: ImageFormat::Raw,
+            run_config: RunConfig {
+                execution: ExecutorConfig::test(),
+                bootstrap: None,
+                build: Scenario {
+                    retries: 0,
+                    steps: vec![vec![Step::Action {
+                        action: SshAction::Exec {
+                            cmd: "true".into(),
+                            expected_exit_code: None,
+                            env: vec![],
+                        },
+                        timeout: Duration::from_secs(1),
+                        retries: 0,
+                    }]],
+                    ..Default::default()
+                },
+                tests: Default::default(),
+                max_total_retries: None,
+                max_kept_attempts: None,
+                attempt_name: RunConfig::DEFAULT_ATTEMPT_NAME.into(),
+            },
+            artifacts_root,
+            test_filter: None,
+            bootstrapped_image: None,
+            compress_artifacts: false,
+            artifacts_layout: ArtifactsLayout::Flat,
+            keep_artifacts: KeepArtifacts::All,
+            isolate_tests: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn run_batch_resume_and_callback() {
+        let tmp = tempfile::tempdir().unwrap();
+        let processor = fake_processor(tmp.path()).await;
+
+        let mut validator = PatchValidator::default();
+        let mut patches = Vec::new();
+        for id in ["aa111111", "bb222222"] {
+            let path = tmp.path().join(format!("{}.patch", id));
+            fs::write(&path, "").await.unwrap();
+            patches.push(validator.validate(&path).await.unwrap());
+        }
+
+        let resume_from = tmp.path().join("previous");
+        fs::create_dir(&resume_from).await.unwrap();
+        fs::write(resume_from.join("aa111111.json"), "{}")
+            .await
+            .unwrap();
+        let reports_dir = tmp.path().join("reports");
+        fs::create_dir(&reports_dir).await.unwrap();
+
+        let results = SyncMutex::new(Vec::new());
+        let stats = run_batch(
+            processor,
+            stream::iter(patches),
+            BatchOptions {
+                resume_from: Some(resume_from),
+                reports_dir: Some(reports_dir.clone()),
+                ..Default::default()
+            },
+            |patch, report| {
+                results
+                    .lock()
+                    .unwrap()
+                    .push((patch.id().to_string(), report.build().success()));
+                async {}
+            },
+        )
+        .await;
+
+        assert_eq!(stats.resumed_solutions, 1);
+        assert_eq!(stats.builds_failed, 1);
+        assert_eq!(results.into_inner().unwrap(), [("bb222222".into(), false)]);
+        assert!(reports_dir.join("bb222222.json").exists());
+        assert!(!reports_dir.join("aa111111.json").exists());
+    }
+
+    #[tokio::test]
+    async fn write_report_dotted_names() {
+        let tmp = tempfile::tempdir().unwrap();
+
+        let path_1 = write_report(Some(tmp.path()), "x.1", &1).await.unwrap();
+        let path_2 = write_report(Some(tmp.path()), "x.2", &2).await.unwrap();
+        assert_eq!(path_1, Some(tmp.path().join("x.1.json")));
+        assert_eq!(path_2, Some(tmp.path().join("x.2.json")));
+        assert_eq!(
+            fs::read_to_string(tmp.path().join("x.1.json"))
+                .await
+                .unwrap(),
+            "1"
+        );
+
+        assert_eq!(write_report(None, "x", &1).await.unwrap(), None);
+    }
+}
@@ -1,10 +1,19 @@
 use crate::{
-    executor::ExecutorConfig,
-    ssh::SshAction,
-    tester::{RunConfig, Scenario, Step},
+    executor::{CommandPolicy, ExecutorConfig},
+    ssh::{SshAction, SshAuth},
+    tester::{DataDisk, Expect, RunConfig, Scenario, Step},
+};
+use regex::Regex;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    num::NonZeroUsize,
+    path::Component,
+    path::Path,
+    path::PathBuf,
+    time::Duration,
 };
-use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, io, path::Path, path::PathBuf, time::Duration};
 use tokio::fs;
 
 /// An error that can occur when reading [RunConfig] from a file.
@@ -12,10 +21,14 @@ use tokio::fs;
 pub enum ConfigError {
     /// A deserialization error.
     Serde(serde_json::Error),
+    /// A deserialization error in a TOML file.
+    Toml(toml::de::Error),
     /// An IO error.
     Io(io::Error),
     /// The path to the file had no parent.
     NoParent,
+    /// The configuration is not valid.
+    Invalid(String),
 }
 
 impl From<serde_json::Error> for ConfigError {
@@ -24,6 +37,12 @@ impl From<serde_json::Error> for ConfigError {
     }
 }
 
+impl From<toml::de::Error> for ConfigError {
+    fn from(error: toml::de::Error) -> Self {
+        Self::Toml(error)
+    }
+}
+
 impl From<io::Error> for ConfigError {
     fn from(error: io::Error) -> Self {
         Self::Io(error)
@@ -54,6 +73,233 @@ mod defaults {
     pub fn timeout_5_s() -> u64 {
         5 * 1000
     }
+
+    pub fn poll_interval() -> u64 {
+        100
+    }
+
+    pub fn stop_on_failure() -> bool {
+        true
+    }
+
+    pub fn graceful_shutdown() -> bool {
+        true
+    }
+
+    pub fn attempt_name() -> String {
+        crate::tester::RunConfig::DEFAULT_ATTEMPT_NAME.into()
+    }
+}
+
+/// Deserialization of file modes.
+/// Besides plain numbers, strings with octal numbers are accepted (e.g. `"0755"`, `"644"`).
+mod file_mode {
+    use serde::{de::Error, Deserialize, Deserializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RawMode {
+        Number(i32),
+        Octal(String),
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<i32>, D::Error> {
+        let mode = match Option::<RawMode>::deserialize(deserializer)? {
+            Some(RawMode::Number(mode)) => mode,
+            Some(RawMode::Octal(mode)) => i32::from_str_radix(&mode, 8)
+                .map_err(|_| D::Error::custom(format!("invalid octal file mode '{}'", mode)))?,
+            None => return Ok(None),
+        };
+
+        if !(0..=0o7777).contains(&mode) {
+            return Err(D::Error::custom(format!("invalid file mode {:o}", mode)));
+        }
+
+        Ok(Some(mode))
+    }
+}
+
+/// (De)serialization of durations expressed in milliseconds.
+/// Besides plain numbers, strings with units are accepted (e.g. `"500ms"`, `"30s"`, `"1m30s"`, `"2h"`).
+mod duration_ms {
+    use serde::{de::Error, Deserialize, Deserializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RawDuration {
+        Millis(u64),
+        Human(String),
+    }
+
+    impl RawDuration {
+        fn into_millis(self) -> Result<u64, String> {
+            match self {
+                Self::Millis(millis) => Ok(millis),
+                Self::Human(s) => parse(&s),
+            }
+        }
+    }
+
+    /// Parses a duration string into milliseconds.
+    pub fn parse(s: &str) -> Result<u64, String> {
+        let invalid = || format!("invalid duration '{}'", s);
+
+        let mut rest = s.trim();
+        if rest.is_empty() {
+            return Err(invalid());
+        }
+
+        let mut total: u64 = 0;
+        while !rest.is_empty() {
+            let digits = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .ok_or_else(|| format!("missing unit in duration '{}'", s))?;
+            let value: u64 = rest[..digits].parse().map_err(|_| invalid())?;
+            rest = &rest[digits..];
+
+            let letters = rest
+                .find(|c: char| !c.is_ascii_alphabetic())
+                .unwrap_or(rest.len());
+            let multiplier = match &rest[..letters] {
+                "ms" => 1,
+                "s" => 1000,
+                "m" => 60 * 1000,
+                "h" => 60 * 60 * 1000,
+                unit => return Err(format!("unknown unit '{}' in duration '{}'", unit, s)),
+            };
+            rest = &rest[letters..];
+
+            total = value
+                .checked_mul(multiplier)
+                .and_then(|millis| total.checked_add(millis))
+                .ok_or_else(|| format!("duration '{}' is too long", s))?;
+        }
+
+        Ok(total)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<u64, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        RawDuration::deserialize(deserializer)?
+            .into_millis()
+            .map_err(D::Error::custom)
+    }
+
+    pub fn deserialize_option<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Option::<RawDuration>::deserialize(deserializer)?
+            .map(RawDuration::into_millis)
+            .transpose()
+            .map_err(D::Error::custom)
+    }
+}
+
+/// Expansion of environment variables in strings from the suite file.
+/// `${VAR}` is replaced with the value of `VAR`, `${VAR:-default}` falls back to `default`
+/// if `VAR` is unset or empty, and `$${` is an escaped `${`.
+mod env_vars {
+    use std::path::PathBuf;
+
+    /// Expands all tokens in the given string.
+    /// # Arguments
+    /// * s - string to expand.
+    /// * lookup - returns values of variables.
+    /// # Returns
+    /// The expanded string or a description of the error.
+    pub fn expand<F>(s: &str, lookup: F) -> Result<String, String>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut out = String::with_capacity(s.len());
+        let mut rest = s;
+
+        while let Some(start) = rest.find("${") {
+            if rest[..start].ends_with('$') {
+                out.push_str(&rest[..start - 1]);
+                out.push_str("${");
+                rest = &rest[start + 2..];
+                continue;
+            }
+            out.push_str(&rest[..start]);
+
+            let token = &rest[start + 2..];
+            let end = token
+                .find('}')
+                .ok_or_else(|| format!("unterminated variable reference in '{}'", s))?;
+            let (name, default) = match token[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&token[..end], None),
+            };
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(format!("invalid variable name '{}'", name));
+            }
+
+            let value = match (lookup(name), default) {
+                (Some(value), Some(default)) if value.is_empty() => default.to_string(),
+                (Some(value), _) => value,
+                (None, Some(default)) => default.to_string(),
+                (None, None) => return Err(format!("environment variable {} is not set", name)),
+            };
+            out.push_str(&value);
+            rest = &token[end + 1..];
+        }
+        out.push_str(rest);
+
+        Ok(out)
+    }
+
+    /// Expands all tokens in the given string in place, using the environment of this process.
+    /// # Arguments
+    /// * value - string to expand.
+    /// * key - name of the configuration key, used in the error message.
+    pub fn expand_string(value: &mut String, key: &str) -> Result<(), super::ConfigError> {
+        *value = expand(value, |name| std::env::var(name).ok())
+            .map_err(|error| super::ConfigError::Invalid(format!("{} (in {})", error, key)))?;
+        Ok(())
+    }
+
+    /// Like [expand_string], but for paths. Paths that are not valid UTF-8 are left unchanged.
+    pub fn expand_path(value: &mut PathBuf, key: &str) -> Result<(), super::ConfigError> {
+        if let Some(s) = value.to_str() {
+            let mut s = s.to_string();
+            expand_string(&mut s, key)?;
+            *value = s.into();
+        }
+        Ok(())
+    }
+}
+
+/// (De)serialization of lists of regular expressions.
+mod regexes {
+    use regex::Regex;
+    use serde::{de::Error, ser::SerializeSeq, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(regexes: &[Regex], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut seq = serializer.serialize_seq(Some(regexes.len()))?;
+        for regex in regexes {
+            seq.serialize_element(regex.as_str())?;
+        }
+        seq.end()
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<Regex>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|pattern| Regex::new(pattern).map_err(D::Error::custom))
+            .collect()
+    }
 }
 
 /// A configuration for a single step executed in a QEMU process.
@@ -61,74 +307,309 @@ mod defaults {
 #[serde(tag = "type", rename_all = "snake_case")]
 enum StepConfig {
     /// File transfer from host to guest over SSH.
-    /// Destination file will have permissions set to 0o777.
     FileTransfer {
         /// Path to the source file on the host machine.
         from: PathBuf,
         /// Path to the destination file on the guest machine.
         to: PathBuf,
+        /// Permissions of the destination file.
+        #[serde(default, deserialize_with = "file_mode::deserialize")]
+        mode: Option<i32>,
         /// Timeout for the file transfer (milliseconds).
+        #[serde(default, deserialize_with = "duration_ms::deserialize_option")]
+        timeout_ms: Option<u64>,
+    },
+    /// File transfer from guest to host over SSH.
+    FileDownload {
+        /// Path to the source file on the guest machine.
+        from: PathBuf,
+        /// Path to the destination file on the host machine.
+        /// Relative paths are resolved against the scratch directory of the scenario attempt.
+        to: PathBuf,
+        /// Timeout for the file transfer (milliseconds).
+        #[serde(default, deserialize_with = "duration_ms::deserialize_option")]
         timeout_ms: Option<u64>,
     },
     /// Patch file transfer from host to guest over SSH.
     PatchTransfer {
         /// Path to the destination file on the guest machine.
         to: PathBuf,
+        /// Permissions of the destination file.
+        #[serde(default, deserialize_with = "file_mode::deserialize")]
+        mode: Option<i32>,
         /// Timeout for the file transfer (milliseconds).
+        #[serde(default, deserialize_with = "duration_ms::deserialize_option")]
         timeout_ms: Option<u64>,
     },
     /// Command execution over SSH.
     Command {
         /// Command to execute.
         command: String,
+        /// Exit code that means success.
+        #[serde(default)]
+        expected_exit_code: Option<i32>,
+        /// Environment variables for the command.
+        #[serde(default)]
+        env: BTreeMap<String, String>,
+        /// Number of times the command is executed again if it fails.
+        #[serde(default)]
+        retries: Option<usize>,
         /// Timeout for the command (milliseconds).
+        #[serde(default, deserialize_with = "duration_ms::deserialize_option")]
+        timeout_ms: Option<u64>,
+    },
+    /// Waiting before the next step, without interacting with the guest machine.
+    Sleep {
+        /// Duration of the wait (milliseconds).
+        #[serde(deserialize_with = "duration_ms::deserialize")]
+        duration_ms: u64,
+    },
+    /// Reboot of the guest machine.
+    /// Closes the current phase of the scenario and opens a new one.
+    Reboot {
+        /// Timeout for the SSH connection after the reboot (milliseconds).
+        #[serde(default, deserialize_with = "duration_ms::deserialize_option")]
         timeout_ms: Option<u64>,
     },
 }
 
 impl StepConfig {
-    fn into_step(self, default_timeout: Duration) -> Step {
-        match self {
+    /// # Returns
+    /// The [Step] described by this config, or [None] for [StepConfig::Reboot].
+    fn into_step(self, default_timeout: Duration) -> Option<Step> {
+        let step = match self {
             Self::FileTransfer {
+                from,
+                to,
+                mode,
+                timeout_ms,
+            } => Step::Action {
+                action: SshAction::Send { from, to, mode },
+                timeout: timeout_ms
+                    .map(Duration::from_millis)
+                    .unwrap_or(default_timeout),
+                retries: 0,
+            },
+            Self::FileDownload {
                 from,
                 to,
                 timeout_ms,
             } => Step::Action {
-                action: SshAction::Send { from, to },
+                action: SshAction::Receive { from, to },
                 timeout: timeout_ms
                     .map(Duration::from_millis)
                     .unwrap_or(default_timeout),
+                retries: 0,
             },
-            Self::PatchTransfer { to, timeout_ms } => Step::TransferPatch {
+            Self::PatchTransfer {
+                to,
+                mode,
+                timeout_ms,
+            } => Step::TransferPatch {
                 to,
+                mode,
                 timeout: timeout_ms
                     .map(Duration::from_millis)
                     .unwrap_or(default_timeout),
             },
             Self::Command {
                 command,
+                expected_exit_code,
+                env,
+                retries,
                 timeout_ms,
             } => Step::Action {
-                action: SshAction::Exec { cmd: command },
+                action: SshAction::Exec {
+                    cmd: command,
+                    expected_exit_code,
+                    env: env.into_iter().collect(),
+                },
                 timeout: timeout_ms
                     .map(Duration::from_millis)
                     .unwrap_or(default_timeout),
+                retries: retries.unwrap_or(0),
+            },
+            Self::Sleep { duration_ms } => Step::Sleep {
+                duration: Duration::from_millis(duration_ms),
             },
+            Self::Reboot { .. } => return None,
+        };
+
+        Some(step)
+    }
+
+    /// Expands environment variables in host paths of this step (see [env_vars]).
+    /// Strings used in the guest machine (commands, their environment and remote paths)
+    /// are left unchanged, so that they can use shell syntax like `${VAR%.c}`.
+    /// # Arguments
+    /// * key - name of the configuration key of this step, used in error messages.
+    fn expand_env(&mut self, key: &str) -> Result<(), ConfigError> {
+        match self {
+            Self::FileTransfer { from, .. } => {
+                env_vars::expand_path(from, &format!("{}.from", key))?;
+            }
+            Self::FileDownload { to, .. } => {
+                env_vars::expand_path(to, &format!("{}.to", key))?;
+            }
+            Self::PatchTransfer { .. }
+            | Self::Command { .. }
+            | Self::Sleep { .. }
+            | Self::Reboot { .. } => {}
+        }
+
+        Ok(())
+    }
+
+    /// Expands a glob pattern in the source path of a [StepConfig::FileTransfer].
+    /// Every match is sent to the destination path treated as a directory.
+    /// A source path that exists is never treated as a pattern, even if it contains
+    /// glob characters (e.g. `a[1].c`).
+    /// # Arguments
+    /// * base - directory against which relative patterns are resolved.
+    /// # Returns
+    /// One [StepConfig::FileTransfer] for every match (in alphabetical order),
+    /// or [None] if this is not a [StepConfig::FileTransfer] with a glob pattern.
+    async fn expand_glob(&self, base: &Path) -> io::Result<Option<Vec<Self>>> {
+        let (from, to, mode, timeout_ms) = match self {
+            Self::FileTransfer {
+                from,
+                to,
+                mode,
+                timeout_ms,
+            } if from.to_string_lossy().contains(['*', '?', '[']) => (from, to, *mode, *timeout_ms),
+            _ => return Ok(None),
+        };
+
+        let pattern = base.join(from);
+        if fs::metadata(&pattern).await.is_ok() {
+            return Ok(None);
+        }
+
+        let invalid = |error: &dyn std::fmt::Display| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid glob pattern {}: {}", pattern.display(), error),
+            )
+        };
+        let matches = glob::glob(&pattern.to_string_lossy())
+            .map_err(|error| invalid(&error))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|error| invalid(&error))?;
+        if matches.is_empty() {
+            log::error!("Glob pattern {} matched no files.", pattern.display());
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("glob pattern {} matched no files", pattern.display()),
+            ));
         }
+
+        let mut steps = Vec::with_capacity(matches.len());
+        for path in matches {
+            let to = match path.file_name() {
+                Some(file_name) => to.join(file_name),
+                None => to.clone(),
+            };
+            steps.push(Self::FileTransfer {
+                from: canonicalize(path).await?,
+                to,
+                mode,
+                timeout_ms,
+            });
+        }
+
+        Ok(Some(steps))
     }
 
     async fn normalize_path(&mut self, base: &Path) -> io::Result<()> {
-        if let Self::FileTransfer { from, .. } = self {
-            match fs::canonicalize(base.join(from.as_path())).await {
-                Ok(normalized) => *from = normalized,
-                Err(error) => {
-                    log::error!(
-                        "Failed to canonicalize path {}. Error: {}.",
-                        from.display(),
-                        error
-                    );
-                    return Err(error);
+        match self {
+            Self::FileTransfer { from, .. } => {
+                *from = canonicalize(base.join(from.as_path())).await?;
+            }
+            // Relative destinations are resolved against scratch directories when the step is executed.
+            // Absolute ones are not canonicalized, as their parent directories may not exist yet.
+            Self::FileDownload { to, .. } if to.is_absolute() => {
+                *to = normalize_lexically(to);
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+/// Removes `.` components from the given path and resolves `..` components
+/// without accessing the filesystem.
+fn normalize_lexically(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+
+    normalized
+}
+
+async fn canonicalize(path: PathBuf) -> io::Result<PathBuf> {
+    fs::canonicalize(&path).await.inspect_err(|error| {
+        log::error!(
+            "Failed to canonicalize path {}. Error: {}.",
+            path.display(),
+            error
+        )
+    })
+}
+
+/// A configuration of SSH authentication.
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum AuthConfig {
+    /// Authentication with a password.
+    Password { password: String },
+    /// Authentication with a key pair.
+    /// Paths are relative to the parent directory of the suite file.
+    PublicKey {
+        private_key: PathBuf,
+        passphrase: Option<String>,
+        public_key: Option<PathBuf>,
+    },
+}
+
+impl AuthConfig {
+    fn expand_env(&mut self) -> Result<(), ConfigError> {
+        match self {
+            Self::Password { password } => env_vars::expand_string(password, "auth.password"),
+            Self::PublicKey {
+                private_key,
+                passphrase,
+                public_key,
+            } => {
+                env_vars::expand_path(private_key, "auth.private_key")?;
+                if let Some(passphrase) = passphrase {
+                    env_vars::expand_string(passphrase, "auth.passphrase")?;
+                }
+                if let Some(public_key) = public_key {
+                    env_vars::expand_path(public_key, "auth.public_key")?;
                 }
+                Ok(())
+            }
+        }
+    }
+
+    async fn normalize_paths(&mut self, base: &Path) -> io::Result<()> {
+        if let Self::PublicKey {
+            private_key,
+            public_key,
+            ..
+        } = self
+        {
+            *private_key = canonicalize(base.join(private_key.as_path())).await?;
+            if let Some(public_key) = public_key {
+                *public_key = canonicalize(base.join(public_key.as_path())).await?;
             }
         }
 
@@ -136,36 +617,162 @@ impl StepConfig {
     }
 }
 
+impl From<AuthConfig> for SshAuth {
+    fn from(config: AuthConfig) -> Self {
+        match config {
+            AuthConfig::Password { password } => Self::Password(password),
+            AuthConfig::PublicKey {
+                private_key,
+                passphrase,
+                public_key,
+            } => Self::PublicKey {
+                private_key,
+                passphrase,
+                public_key,
+            },
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct DiskConfig {
+    size_mb: u64,
+    interface: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+enum ExpectConfig {
+    #[default]
+    Pass,
+    Fail,
+}
+
+impl From<ExpectConfig> for Expect {
+    fn from(config: ExpectConfig) -> Self {
+        match config {
+            ExpectConfig::Pass => Self::Pass,
+            ExpectConfig::Fail => Self::Fail,
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 struct ScenarioConfig {
     retries: Option<usize>,
+    #[serde(default, deserialize_with = "duration_ms::deserialize_option")]
+    retry_delay_ms: Option<u64>,
     steps: Vec<Vec<StepConfig>>,
+    #[serde(default)]
+    on_failure: Vec<StepConfig>,
+    #[serde(default)]
+    disks: Vec<DiskConfig>,
+    #[serde(default)]
+    depends_on: Vec<String>,
+    #[serde(default)]
+    expect: ExpectConfig,
+    #[serde(default, deserialize_with = "duration_ms::deserialize_option")]
+    total_timeout_ms: Option<u64>,
+    #[serde(default = "defaults::stop_on_failure")]
+    stop_on_failure: bool,
 }
 
 impl ScenarioConfig {
-    fn into_scenario(self, default_retries: usize, default_timeout: Duration) -> Scenario {
-        let steps = self
-            .steps
+    fn into_scenario(
+        self,
+        default_retries: usize,
+        default_retry_delay: Duration,
+        default_timeout: Duration,
+    ) -> Scenario {
+        let mut steps = Vec::with_capacity(self.steps.len());
+        let mut boot_timeouts = Vec::with_capacity(self.steps.len());
+        for phase_config in self.steps {
+            let mut phase = Vec::with_capacity(phase_config.len());
+            boot_timeouts.push(None);
+            for step_config in phase_config {
+                match step_config {
+                    StepConfig::Reboot { timeout_ms } => {
+                        steps.push(std::mem::take(&mut phase));
+                        boot_timeouts.push(timeout_ms.map(Duration::from_millis));
+                    }
+                    step_config => phase.extend(step_config.into_step(default_timeout)),
+                }
+            }
+            steps.push(phase);
+        }
+        let on_failure = self
+            .on_failure
             .into_iter()
-            .map(|phase_config| {
-                phase_config
-                    .into_iter()
-                    .map(|step_config| step_config.into_step(default_timeout))
-                    .collect()
-            })
+            .filter_map(|step_config| step_config.into_step(default_timeout))
             .collect();
 
         Scenario {
             retries: self.retries.unwrap_or(default_retries),
+            retry_delay: self
+                .retry_delay_ms
+                .map(Duration::from_millis)
+                .unwrap_or(default_retry_delay),
             steps,
+            boot_timeouts,
+            on_failure,
+            disks: self
+                .disks
+                .into_iter()
+                .map(|disk| DataDisk {
+                    size_mb: disk.size_mb,
+                    interface: disk.interface,
+                })
+                .collect(),
+            depends_on: self.depends_on,
+            expect: self.expect.into(),
+            total_timeout: self.total_timeout_ms.map(Duration::from_millis),
+            continue_on_failure: !self.stop_on_failure,
+        }
+    }
+
+    fn check_steps(&self) -> Result<(), ConfigError> {
+        if self
+            .on_failure
+            .iter()
+            .any(|step| matches!(step, StepConfig::Reboot { .. }))
+        {
+            return Err(ConfigError::Invalid(
+                "reboot steps are not allowed in on_failure".into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Expands environment variables in host paths of all steps (see [StepConfig::expand_env]).
+    /// # Arguments
+    /// * key - name of the configuration key of this scenario, used in error messages.
+    fn expand_env(&mut self, key: &str) -> Result<(), ConfigError> {
+        for (i, phase) in self.steps.iter_mut().enumerate() {
+            for (j, step) in phase.iter_mut().enumerate() {
+                step.expand_env(&format!("{}.steps[{}][{}]", key, i, j))?;
+            }
+        }
+        for (i, step) in self.on_failure.iter_mut().enumerate() {
+            step.expand_env(&format!("{}.on_failure[{}]", key, i))?;
         }
+
+        Ok(())
     }
 
     async fn normalize_paths(&mut self, base: &Path) -> io::Result<()> {
-        for steps in &mut self.steps {
-            for step in steps {
-                step.normalize_path(base).await?;
+        for steps in self.steps.iter_mut().chain([&mut self.on_failure]) {
+            let mut normalized = Vec::with_capacity(steps.len());
+            for mut step in steps.drain(..) {
+                match step.expand_glob(base).await? {
+                    Some(expanded) => normalized.extend(expanded),
+                    None => {
+                        step.normalize_path(base).await?;
+                        normalized.push(step);
+                    }
+                }
             }
+            *steps = normalized;
         }
 
         Ok(())
@@ -178,19 +785,214 @@ struct Config {
     user: String,
     #[serde(default = "defaults::password")]
     password: String,
-    #[serde(default = "defaults::timeout_20_s")]
+    auth: Option<AuthConfig>,
+    #[serde(
+        default = "defaults::timeout_20_s",
+        deserialize_with = "duration_ms::deserialize"
+    )]
     ssh_timeout_ms: u64,
-    #[serde(default = "defaults::timeout_20_s")]
+    known_hosts: Option<PathBuf>,
+    #[serde(
+        default = "defaults::poll_interval",
+        deserialize_with = "duration_ms::deserialize"
+    )]
+    poll_interval_ms: u64,
+    max_connection_attempts: Option<usize>,
+    #[serde(
+        default = "defaults::timeout_20_s",
+        deserialize_with = "duration_ms::deserialize"
+    )]
     poweroff_timeout_ms: u64,
     #[serde(default = "defaults::poweroff_command")]
     poweroff_command: String,
+    #[serde(default, deserialize_with = "duration_ms::deserialize")]
+    pre_poweroff_delay_ms: u64,
+    #[serde(default)]
+    use_monitor_powerdown: bool,
+    #[serde(default = "defaults::graceful_shutdown")]
+    graceful_shutdown: bool,
+    #[serde(default)]
+    snapshot_stacks: bool,
     #[serde(default = "defaults::retries")]
     retries: usize,
-    #[serde(default = "defaults::timeout_5_s")]
+    #[serde(default, deserialize_with = "duration_ms::deserialize")]
+    retry_delay_ms: u64,
+    #[serde(
+        default = "defaults::timeout_5_s",
+        deserialize_with = "duration_ms::deserialize"
+    )]
     step_timeout_ms: u64,
+    ready_command: Option<String>,
+    #[serde(
+        default = "defaults::timeout_20_s",
+        deserialize_with = "duration_ms::deserialize"
+    )]
+    ready_timeout_ms: u64,
+    shell: Option<String>,
+    bootstrap: Option<ScenarioConfig>,
     build: Option<ScenarioConfig>,
+    #[serde(default)]
     tests: HashMap<String, ScenarioConfig>,
+    #[serde(default)]
+    include: Vec<PathBuf>,
     output_limit: Option<u64>,
+    stdout_limit: Option<u64>,
+    stderr_limit: Option<u64>,
+    #[serde(default)]
+    stream_output: bool,
+    transfer_buffer_size: Option<NonZeroUsize>,
+    #[serde(default)]
+    require_actions: bool,
+    max_total_retries: Option<usize>,
+    max_kept_attempts: Option<usize>,
+    #[serde(default = "defaults::attempt_name")]
+    attempt_name: String,
+    #[serde(default, with = "regexes")]
+    allowed_commands: Vec<Regex>,
+    #[serde(default, with = "regexes")]
+    denied_commands: Vec<Regex>,
+}
+
+impl Config {
+    /// Expands environment variables in host-side strings of this config (see [env_vars]):
+    /// credentials, host paths and host paths of steps.
+    /// Commands executed in the guest machine are left unchanged.
+    fn expand_env(&mut self) -> Result<(), ConfigError> {
+        env_vars::expand_string(&mut self.user, "user")?;
+        env_vars::expand_string(&mut self.password, "password")?;
+        if let Some(auth) = self.auth.as_mut() {
+            auth.expand_env()?;
+        }
+        if let Some(known_hosts) = self.known_hosts.as_mut() {
+            env_vars::expand_path(known_hosts, "known_hosts")?;
+        }
+        if let Some(bootstrap) = self.bootstrap.as_mut() {
+            bootstrap.expand_env("bootstrap")?;
+        }
+        if let Some(build) = self.build.as_mut() {
+            build.expand_env("build")?;
+        }
+        for (name, test) in self.tests.iter_mut() {
+            test.expand_env(&format!("tests.{}", name))?;
+        }
+        for (i, include) in self.include.iter_mut().enumerate() {
+            env_vars::expand_path(include, &format!("include[{}]", i))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks that the suite has tests, that all scenarios have steps, that the patch is never
+/// transferred in the bootstrap scenario, and that the patch is not transferred in tests
+/// if it is not transferred in the build scenario.
+/// # Arguments
+/// bootstrap - the bootstrap scenario configuration, if any.
+/// build - the build scenario configuration, if any.
+/// tests - test configurations by name.
+fn check_scenarios(
+    bootstrap: Option<&ScenarioConfig>,
+    build: Option<&ScenarioConfig>,
+    tests: &HashMap<String, ScenarioConfig>,
+) -> Result<(), ConfigError> {
+    if tests.is_empty() {
+        return Err(ConfigError::Invalid("the suite has no tests".into()));
+    }
+
+    let has_steps = |scenario: &ScenarioConfig| {
+        scenario
+            .steps
+            .iter()
+            .flatten()
+            .any(|step| !matches!(step, StepConfig::Reboot { .. }))
+    };
+    let transfers_patch = |scenario: &ScenarioConfig| {
+        scenario
+            .steps
+            .iter()
+            .flatten()
+            .chain(&scenario.on_failure)
+            .any(|step| matches!(step, StepConfig::PatchTransfer { .. }))
+    };
+
+    for (name, scenario) in [("bootstrap", bootstrap), ("build", build)] {
+        if scenario.is_some_and(|scenario| !has_steps(scenario)) {
+            return Err(ConfigError::Invalid(format!(
+                "{} scenario has no steps",
+                name
+            )));
+        }
+    }
+
+    if bootstrap.is_some_and(transfers_patch) {
+        return Err(ConfigError::Invalid(
+            "bootstrap scenario cannot transfer the patch".into(),
+        ));
+    }
+
+    let build_transfers_patch = build.is_some_and(transfers_patch);
+    for (name, test) in tests {
+        if !has_steps(test) {
+            return Err(ConfigError::Invalid(format!("test {} has no steps", name)));
+        }
+        if transfers_patch(test) && !build_transfers_patch {
+            return Err(ConfigError::Invalid(format!(
+                "test {} transfers the patch, but the build scenario does not",
+                name
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that all test dependencies exist and that there are no dependency cycles.
+/// # Arguments
+/// tests - test configurations by name.
+fn check_dependencies(tests: &HashMap<String, ScenarioConfig>) -> Result<(), ConfigError> {
+    #[derive(Clone, Copy, PartialEq)]
+    enum Mark {
+        Visiting,
+        Done,
+    }
+
+    fn visit<'a>(
+        test: &'a str,
+        tests: &'a HashMap<String, ScenarioConfig>,
+        marks: &mut HashMap<&'a str, Mark>,
+    ) -> Result<(), ConfigError> {
+        match marks.get(test) {
+            Some(Mark::Done) => return Ok(()),
+            Some(Mark::Visiting) => {
+                return Err(ConfigError::Invalid(format!(
+                    "dependency cycle involving test {}",
+                    test
+                )))
+            }
+            None => {}
+        }
+
+        marks.insert(test, Mark::Visiting);
+        for dependency in &tests[test].depends_on {
+            if !tests.contains_key(dependency) {
+                return Err(ConfigError::Invalid(format!(
+                    "test {} depends on an unknown test {}",
+                    test, dependency
+                )));
+            }
+            visit(dependency, tests, marks)?;
+        }
+        marks.insert(test, Mark::Done);
+
+        Ok(())
+    }
+
+    let mut marks = HashMap::new();
+    for test in tests.keys() {
+        visit(test, tests, &mut marks)?;
+    }
+
+    Ok(())
 }
 
 impl From<Config> for RunConfig {
@@ -198,6 +1000,7 @@ impl From<Config> for RunConfig {
         let make_scenario = move |scenario_config: ScenarioConfig| {
             scenario_config.into_scenario(
                 config.retries,
+                Duration::from_millis(config.retry_delay_ms),
                 Duration::from_millis(config.step_timeout_ms),
             )
         };
@@ -205,32 +1008,123 @@ impl From<Config> for RunConfig {
         RunConfig {
             execution: ExecutorConfig {
                 user: config.user,
-                password: config.password,
+                auth: config
+                    .auth
+                    .map(SshAuth::from)
+                    .unwrap_or(SshAuth::Password(config.password)),
                 connection_timeout: Duration::from_millis(config.ssh_timeout_ms),
+                known_hosts: config.known_hosts,
+                poll_interval: Duration::from_millis(config.poll_interval_ms),
+                max_connection_attempts: config.max_connection_attempts,
                 poweroff_timeout: Duration::from_millis(config.poweroff_timeout_ms),
                 poweroff_command: config.poweroff_command,
-                output_limit: config.output_limit,
+                use_monitor_powerdown: config.use_monitor_powerdown,
+                pre_poweroff_delay: Duration::from_millis(config.pre_poweroff_delay_ms),
+                graceful_shutdown: config.graceful_shutdown,
+                ready_command: config.ready_command,
+                ready_timeout: Duration::from_millis(config.ready_timeout_ms),
+                shell: config.shell,
+                stdout_limit: config.stdout_limit.or(config.output_limit),
+                stderr_limit: config.stderr_limit.or(config.output_limit),
+                stream_output: config.stream_output,
+                transfer_buffer_size: config.transfer_buffer_size,
+                require_actions: config.require_actions,
+                command_policy: CommandPolicy {
+                    allow: config.allowed_commands,
+                    deny: config.denied_commands,
+                },
+                keep_instance: Default::default(),
+                snapshot_stacks: config.snapshot_stacks,
+                ssh_worker_permits: None,
             },
+            bootstrap: config.bootstrap.map(make_scenario),
             build: config.build.map(make_scenario).unwrap_or_default(),
             tests: config
                 .tests
                 .into_iter()
                 .map(|(name, scenario_config)| (name, make_scenario(scenario_config)))
                 .collect(),
+            max_total_retries: config.max_total_retries,
+            max_kept_attempts: config.max_kept_attempts,
+            attempt_name: config.attempt_name,
         }
     }
 }
 
+/// Parses a JSON file, or a TOML file if the file has the `.toml` extension.
+async fn parse_file<T: DeserializeOwned>(path: &Path) -> Result<T, ConfigError> {
+    let bytes = fs::read(path).await?;
+    if path.extension().is_some_and(|ext| ext == "toml") {
+        Ok(toml::from_slice(&bytes[..])?)
+    } else {
+        Ok(serde_json::from_slice(&bytes[..])?)
+    }
+}
+
+/// Checks the naming and retention of attempt artifacts.
+/// # Arguments
+/// attempt_name - template of the names of files created for attempts (see [RunConfig::attempt_name]).
+/// max_kept_attempts - number of attempts whose images are kept (see [RunConfig::max_kept_attempts]).
+fn check_attempts(attempt_name: &str, max_kept_attempts: Option<usize>) -> Result<(), ConfigError> {
+    if !attempt_name.contains("{attempt}") {
+        return Err(ConfigError::Invalid(format!(
+            "attempt_name '{}' does not contain {{attempt}}",
+            attempt_name
+        )));
+    }
+    if attempt_name.contains('/') {
+        return Err(ConfigError::Invalid(format!(
+            "attempt_name '{}' contains a path separator",
+            attempt_name
+        )));
+    }
+    if max_kept_attempts == Some(0) {
+        return Err(ConfigError::Invalid(
+            "max_kept_attempts must be positive".into(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Reads tests from the files listed under `include` and merges them into `tests` of the given config.
+/// Paths in the included tests are relative to the parent directories of their files.
+/// # Arguments
+/// config - the config to merge the tests into.
+/// base - directory against which relative paths of the included files are resolved.
+async fn merge_includes(config: &mut Config, base: &Path) -> Result<(), ConfigError> {
+    for include in std::mem::take(&mut config.include) {
+        let path = canonicalize(base.join(include)).await?;
+        let parent = path.parent().ok_or(ConfigError::NoParent)?;
+
+        let tests: HashMap<String, ScenarioConfig> = parse_file(&path).await?;
+        for (name, mut test) in tests {
+            if config.tests.contains_key(&name) {
+                return Err(ConfigError::Invalid(format!(
+                    "test {} from {} is defined more than once",
+                    name,
+                    path.display()
+                )));
+            }
+
+            test.expand_env(&format!("tests.{} (in {})", name, path.display()))?;
+            test.check_steps()?;
+            test.normalize_paths(parent).await?;
+            config.tests.insert(name, test);
+        }
+    }
+
+    Ok(())
+}
+
 impl RunConfig {
     /// # Arguments
-    /// * path - path to the file containing a json description of the config
+    /// * path - path to the file containing a json or toml (with the `.toml` extension) description of the config
     /// # Returns
     /// A new instance of this struct.
     pub async fn from_file(path: &Path) -> Result<Self, ConfigError> {
-        let mut config: Config = {
-            let bytes = fs::read(path).await?;
-            serde_json::from_slice(&bytes[..])?
-        };
+        let mut config: Config = parse_file(path).await?;
+        config.expand_env()?;
 
         let path = fs::canonicalize(path).await?;
         let parent = path.parent().ok_or_else(|| {
@@ -238,14 +1132,36 @@ impl RunConfig {
             ConfigError::NoParent
         })?;
 
-        if let Some(scenario) = config.build.as_mut() {
+        if let Some(auth) = config.auth.as_mut() {
+            auth.normalize_paths(parent).await?;
+        }
+        if let Some(known_hosts) = config.known_hosts.as_mut() {
+            *known_hosts = canonicalize(parent.join(known_hosts.as_path())).await?;
+        }
+
+        for scenario in [config.bootstrap.as_mut(), config.build.as_mut()]
+            .into_iter()
+            .flatten()
+        {
+            scenario.check_steps()?;
             scenario.normalize_paths(parent).await?;
         }
 
         for scenario in config.tests.values_mut() {
+            scenario.check_steps()?;
             scenario.normalize_paths(parent).await?;
         }
 
+        merge_includes(&mut config, parent).await?;
+
+        check_scenarios(
+            config.bootstrap.as_ref(),
+            config.build.as_ref(),
+            &config.tests,
+        )?;
+        check_dependencies(&config.tests)?;
+        check_attempts(&config.attempt_name, config.max_kept_attempts)?;
+
         Ok(config.into())
     }
 }
@@ -259,12 +1175,129 @@ mod tests {
         let val = StepConfig::FileTransfer {
             from: "./wow".into(),
             to: "./not/wow".into(),
+            mode: None,
             timeout_ms: 12.into(),
         };
         let serialized = "{\"type\": \"file_transfer\", \"from\": \"./wow\", \"to\": \"./not/wow\", \"timeout_ms\": 12}";
         let deserialized: StepConfig =
             serde_json::from_str(serialized).expect("failed to deserialize");
         assert_eq!(deserialized, val);
+
+        let serialized =
+            "{\"type\": \"patch_transfer\", \"to\": \"solution.patch\", \"mode\": \"0600\"}";
+        let deserialized: StepConfig =
+            serde_json::from_str(serialized).expect("failed to deserialize");
+        assert_eq!(
+            deserialized,
+            StepConfig::PatchTransfer {
+                to: "solution.patch".into(),
+                mode: Some(0o600),
+                timeout_ms: None,
+            }
+        );
+
+        let serialized =
+            "{\"type\": \"patch_transfer\", \"to\": \"solution.patch\", \"mode\": \"0800\"}";
+        serde_json::from_str::<StepConfig>(serialized).expect_err("invalid mode should fail");
+
+        let val = StepConfig::FileDownload {
+            from: "output.txt".into(),
+            to: "results/output.txt".into(),
+            timeout_ms: None,
+        };
+        let serialized = "{\"type\": \"file_download\", \"from\": \"output.txt\", \"to\": \"results/output.txt\"}";
+        let deserialized: StepConfig =
+            serde_json::from_str(serialized).expect("failed to deserialize");
+        assert_eq!(deserialized, val);
+    }
+
+    #[test]
+    fn auth_deserialize() {
+        let serialized = "{\"type\": \"public_key\", \"private_key\": \"id_ed25519\"}";
+        let deserialized: AuthConfig =
+            serde_json::from_str(serialized).expect("failed to deserialize");
+        assert!(matches!(
+            SshAuth::from(deserialized),
+            SshAuth::PublicKey { private_key, passphrase: None, public_key: None } if private_key == Path::new("id_ed25519")
+        ));
+
+        let serialized = "{\"type\": \"password\", \"password\": \"secret\"}";
+        let deserialized: AuthConfig =
+            serde_json::from_str(serialized).expect("failed to deserialize");
+        assert!(
+            matches!(SshAuth::from(deserialized), SshAuth::Password(password) if password == "secret")
+        );
+    }
+
+    #[test]
+    fn duration_parsing() {
+        assert_eq!(duration_ms::parse("500ms"), Ok(500));
+        assert_eq!(duration_ms::parse("30s"), Ok(30 * 1000));
+        assert_eq!(duration_ms::parse("5m"), Ok(5 * 60 * 1000));
+        assert_eq!(duration_ms::parse("1h"), Ok(60 * 60 * 1000));
+        assert_eq!(duration_ms::parse("1m30s"), Ok(90 * 1000));
+        assert!(duration_ms::parse("").is_err());
+        assert!(duration_ms::parse("30").is_err());
+        assert!(duration_ms::parse("30 s").is_err());
+        assert!(duration_ms::parse("3d").is_err());
+        assert!(duration_ms::parse("s").is_err());
+    }
+
+    #[test]
+    fn duration_deserialize() {
+        let serialized = "{\"type\": \"command\", \"command\": \"ls\", \"timeout_ms\": \"2s\"}";
+        let deserialized: StepConfig =
+            serde_json::from_str(serialized).expect("failed to deserialize");
+        assert_eq!(
+            deserialized,
+            StepConfig::Command {
+                command: "ls".into(),
+                expected_exit_code: None,
+                env: Default::default(),
+                retries: None,
+                timeout_ms: Some(2000),
+            }
+        );
+
+        let serialized = "{\"type\": \"command\", \"command\": \"ls\", \"timeout_ms\": 2000}";
+        let deserialized: StepConfig =
+            serde_json::from_str(serialized).expect("failed to deserialize");
+        assert_eq!(
+            deserialized,
+            StepConfig::Command {
+                command: "ls".into(),
+                expected_exit_code: None,
+                env: Default::default(),
+                retries: None,
+                timeout_ms: Some(2000),
+            }
+        );
+
+        let serialized =
+            "{\"type\": \"command\", \"command\": \"ls\", \"env\": {\"B\": \"2\", \"A\": \"1\"}}";
+        let deserialized: StepConfig =
+            serde_json::from_str(serialized).expect("failed to deserialize");
+        match deserialized.into_step(Duration::ZERO) {
+            Some(Step::Action {
+                action: SshAction::Exec { env, .. },
+                ..
+            }) => assert_eq!(
+                env,
+                vec![("A".into(), "1".into()), ("B".into(), "2".into())]
+            ),
+            other => panic!("unexpected step {:?}", other),
+        }
+
+        let serialized = "{\"type\": \"command\", \"command\": \"ls\", \"retries\": 2}";
+        let deserialized: StepConfig =
+            serde_json::from_str(serialized).expect("failed to deserialize");
+        assert!(matches!(
+            deserialized.into_step(Duration::ZERO),
+            Some(Step::Action { retries: 2, .. })
+        ));
+
+        let serialized = "{\"type\": \"command\", \"command\": \"ls\", \"timeout_ms\": \"2 days\"}";
+        serde_json::from_str::<StepConfig>(serialized).expect_err("invalid duration should fail");
     }
 
     #[test]
@@ -272,27 +1305,65 @@ mod tests {
         let config = Config {
             user: "".into(),
             password: "".into(),
+            auth: None,
             ssh_timeout_ms: 1,
+            known_hosts: None,
+            poll_interval_ms: 100,
+            max_connection_attempts: None,
             poweroff_timeout_ms: 0,
             poweroff_command: "".into(),
+            pre_poweroff_delay_ms: 0,
+            use_monitor_powerdown: false,
+            graceful_shutdown: true,
+            snapshot_stacks: false,
             retries: 1,
+            retry_delay_ms: 3,
             step_timeout_ms: 1,
+            ready_command: None,
+            ready_timeout_ms: 1,
+            shell: None,
+            bootstrap: None,
             build: Some(ScenarioConfig {
                 retries: None,
+                retry_delay_ms: None,
                 steps: vec![vec![StepConfig::PatchTransfer {
                     to: "./wow".into(),
+                    mode: None,
                     timeout_ms: None,
                 }]],
+                on_failure: vec![],
+                disks: vec![],
+                depends_on: vec![],
+                expect: Default::default(),
+                total_timeout_ms: None,
+                stop_on_failure: true,
             }),
             tests: Default::default(),
-            output_limit: None,
+            include: vec![],
+            output_limit: Some(10),
+            stdout_limit: Some(5),
+            stderr_limit: None,
+            stream_output: false,
+            transfer_buffer_size: None,
+            require_actions: false,
+            max_total_retries: None,
+            max_kept_attempts: None,
+            attempt_name: defaults::attempt_name(),
+            allowed_commands: vec![],
+            denied_commands: vec![],
         };
 
         let run_config = RunConfig::from(config);
 
+        assert!(
+            matches!(&run_config.execution.auth, SshAuth::Password(password) if password.is_empty())
+        );
+        assert_eq!(run_config.execution.stdout_limit, Some(5));
+        assert_eq!(run_config.execution.stderr_limit, Some(10));
         assert_eq!(run_config.build.retries, 1);
+        assert_eq!(run_config.build.retry_delay, Duration::from_millis(3));
         match &run_config.build.steps[0][0] {
-            Step::TransferPatch { to, timeout } => {
+            Step::TransferPatch { to, timeout, .. } => {
                 assert_eq!(to, &PathBuf::from("./wow"));
                 assert_eq!(timeout.as_millis(), 1);
             }
@@ -300,6 +1371,248 @@ mod tests {
         }
     }
 
+    fn test_with_dependencies(depends_on: &[&str]) -> ScenarioConfig {
+        ScenarioConfig {
+            retries: None,
+            retry_delay_ms: None,
+            steps: vec![],
+            on_failure: vec![],
+            disks: vec![],
+            depends_on: depends_on.iter().map(|test| test.to_string()).collect(),
+            expect: Default::default(),
+            total_timeout_ms: None,
+            stop_on_failure: true,
+        }
+    }
+
+    #[test]
+    fn dependencies() {
+        let tests = HashMap::from([
+            ("a".to_string(), test_with_dependencies(&[])),
+            ("b".to_string(), test_with_dependencies(&["a"])),
+            ("c".to_string(), test_with_dependencies(&["a", "b"])),
+        ]);
+        check_dependencies(&tests).expect("valid dependencies should be accepted");
+
+        let tests = HashMap::from([
+            ("a".to_string(), test_with_dependencies(&["c"])),
+            ("b".to_string(), test_with_dependencies(&["a"])),
+            ("c".to_string(), test_with_dependencies(&["b"])),
+        ]);
+        assert!(matches!(
+            check_dependencies(&tests),
+            Err(ConfigError::Invalid(_))
+        ));
+
+        let tests = HashMap::from([("a".to_string(), test_with_dependencies(&["a"]))]);
+        assert!(matches!(
+            check_dependencies(&tests),
+            Err(ConfigError::Invalid(_))
+        ));
+
+        let tests = HashMap::from([("a".to_string(), test_with_dependencies(&["b"]))]);
+        assert!(matches!(
+            check_dependencies(&tests),
+            Err(ConfigError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn scenarios_validation() {
+        let command = StepConfig::Command {
+            command: "ls".into(),
+            expected_exit_code: None,
+            env: Default::default(),
+            retries: None,
+            timeout_ms: None,
+        };
+        let patch_transfer = StepConfig::PatchTransfer {
+            to: "solution.patch".into(),
+            mode: None,
+            timeout_ms: None,
+        };
+        let scenario = |steps: Vec<Vec<StepConfig>>| ScenarioConfig {
+            steps,
+            ..test_with_dependencies(&[])
+        };
+        let tests =
+            |steps: Vec<Vec<StepConfig>>| HashMap::from([("a".to_string(), scenario(steps))]);
+
+        let build = scenario(vec![vec![patch_transfer.clone(), command.clone()]]);
+        check_scenarios(
+            None,
+            Some(&build),
+            &tests(vec![vec![patch_transfer.clone(), command.clone()]]),
+        )
+        .expect("valid scenarios should be accepted");
+        check_scenarios(None, None, &tests(vec![vec![command.clone()]]))
+            .expect("the build scenario should not be required");
+
+        // No tests.
+        assert!(matches!(
+            check_scenarios(None, Some(&build), &HashMap::new()),
+            Err(ConfigError::Invalid(_))
+        ));
+
+        // Scenarios without steps.
+        for steps in [
+            vec![],
+            vec![vec![]],
+            vec![vec![StepConfig::Reboot { timeout_ms: None }], vec![]],
+        ] {
+            assert!(matches!(
+                check_scenarios(None, Some(&build), &tests(steps.clone())),
+                Err(ConfigError::Invalid(_))
+            ));
+            assert!(matches!(
+                check_scenarios(
+                    None,
+                    Some(&scenario(steps.clone())),
+                    &tests(vec![vec![command.clone()]])
+                ),
+                Err(ConfigError::Invalid(_))
+            ));
+            assert!(matches!(
+                check_scenarios(
+                    Some(&scenario(steps)),
+                    Some(&build),
+                    &tests(vec![vec![command.clone()]])
+                ),
+                Err(ConfigError::Invalid(_))
+            ));
+        }
+
+        // Patch transfer in the bootstrap scenario.
+        assert!(matches!(
+            check_scenarios(
+                Some(&scenario(vec![vec![
+                    patch_transfer.clone(),
+                    command.clone()
+                ]])),
+                Some(&build),
+                &tests(vec![vec![command.clone()]])
+            ),
+            Err(ConfigError::Invalid(_))
+        ));
+
+        // Patch transfer in a test, but not in the build scenario.
+        let build = scenario(vec![vec![command.clone()]]);
+        for build in [None, Some(&build)] {
+            assert!(matches!(
+                check_scenarios(
+                    None,
+                    build,
+                    &tests(vec![vec![command.clone(), patch_transfer.clone()]])
+                ),
+                Err(ConfigError::Invalid(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn reboot_steps() {
+        let command = |command: &str| StepConfig::Command {
+            command: command.into(),
+            expected_exit_code: None,
+            env: Default::default(),
+            retries: None,
+            timeout_ms: None,
+        };
+
+        let deserialized: StepConfig =
+            serde_json::from_str("{\"type\": \"reboot\"}").expect("failed to deserialize");
+        assert_eq!(deserialized, StepConfig::Reboot { timeout_ms: None });
+        let deserialized: StepConfig =
+            serde_json::from_str("{\"type\": \"reboot\", \"timeout_ms\": 60000}")
+                .expect("failed to deserialize");
+        assert_eq!(
+            deserialized,
+            StepConfig::Reboot {
+                timeout_ms: Some(60000)
+            }
+        );
+
+        let scenario = ScenarioConfig {
+            steps: vec![
+                vec![command("a"), deserialized, command("b")],
+                vec![command("c")],
+            ],
+            ..test_with_dependencies(&[])
+        };
+        scenario
+            .check_steps()
+            .expect("reboot steps should be accepted in phases");
+        let scenario = scenario.into_scenario(0, Duration::ZERO, Duration::ZERO);
+        let lengths = scenario.steps.iter().map(Vec::len).collect::<Vec<_>>();
+        assert_eq!(lengths, vec![1, 1, 1]);
+        assert_eq!(
+            scenario.boot_timeouts,
+            vec![None, Some(Duration::from_secs(60)), None]
+        );
+
+        let scenario = ScenarioConfig {
+            on_failure: vec![StepConfig::Reboot { timeout_ms: None }],
+            ..test_with_dependencies(&[])
+        };
+        assert!(matches!(
+            scenario.check_steps(),
+            Err(ConfigError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn attempts_checks() {
+        assert!(check_attempts(&defaults::attempt_name(), None).is_ok());
+        assert!(check_attempts("{scenario}_{attempt}", Some(1)).is_ok());
+        assert!(matches!(
+            check_attempts("attempt", None),
+            Err(ConfigError::Invalid(_))
+        ));
+        assert!(matches!(
+            check_attempts("dir/{attempt}", None),
+            Err(ConfigError::Invalid(_))
+        ));
+        assert!(matches!(
+            check_attempts("{attempt}", Some(0)),
+            Err(ConfigError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn stop_on_failure() {
+        let scenario: ScenarioConfig =
+            serde_json::from_str("{\"steps\": []}").expect("failed to deserialize");
+        assert!(scenario.stop_on_failure);
+        assert!(
+            !scenario
+                .into_scenario(0, Duration::ZERO, Duration::ZERO)
+                .continue_on_failure
+        );
+
+        let scenario: ScenarioConfig =
+            serde_json::from_str("{\"steps\": [], \"stop_on_failure\": false}")
+                .expect("failed to deserialize");
+        assert!(
+            scenario
+                .into_scenario(0, Duration::ZERO, Duration::ZERO)
+                .continue_on_failure
+        );
+    }
+
+    #[test]
+    fn sleep_step() {
+        let deserialized: StepConfig =
+            serde_json::from_str("{\"type\": \"sleep\", \"duration_ms\": \"2s\"}")
+                .expect("failed to deserialize");
+        assert_eq!(deserialized, StepConfig::Sleep { duration_ms: 2000 });
+
+        let step = deserialized.into_step(Duration::ZERO);
+        assert!(matches!(
+            step,
+            Some(Step::Sleep { duration }) if duration == Duration::from_secs(2)
+        ));
+    }
+
     impl StepConfig {
         fn transfer_from(&self) -> &Path {
             match self {
@@ -319,28 +1632,39 @@ mod tests {
 
         let mut scenario = ScenarioConfig {
             retries: Some(4),
+            retry_delay_ms: None,
             steps: vec![vec![
                 StepConfig::FileTransfer {
                     from: dir.clone(),
                     to: "wow".into(),
+                    mode: None,
                     timeout_ms: None,
                 },
                 StepConfig::FileTransfer {
                     from: "wow".into(),
                     to: "wow".into(),
+                    mode: None,
                     timeout_ms: None,
                 },
                 StepConfig::FileTransfer {
                     from: "./wow".into(),
                     to: "wow".into(),
+                    mode: None,
                     timeout_ms: None,
                 },
                 StepConfig::FileTransfer {
                     from: "../wow".into(),
                     to: "../wow".into(),
+                    mode: None,
                     timeout_ms: None,
                 },
             ]],
+            on_failure: vec![],
+            disks: vec![],
+            depends_on: vec![],
+            expect: Default::default(),
+            total_timeout_ms: None,
+            stop_on_failure: true,
         };
 
         scenario
@@ -359,4 +1683,289 @@ mod tests {
         );
         assert_eq!(scenario.steps[0][3].transfer_from(), tmp.path().join("wow"));
     }
+
+    #[tokio::test]
+    async fn download_paths() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("dir");
+        fs::create_dir(&dir).await.unwrap();
+
+        let download = |to: PathBuf| StepConfig::FileDownload {
+            from: "output.txt".into(),
+            to,
+            timeout_ms: None,
+        };
+        let mut scenario = ScenarioConfig {
+            retries: None,
+            retry_delay_ms: None,
+            steps: vec![vec![
+                download("output.txt".into()),
+                download(dir.join("..").join("output.txt")),
+                download(dir.join("missing").join(".").join("output.txt")),
+            ]],
+            on_failure: vec![],
+            disks: vec![],
+            depends_on: vec![],
+            expect: Default::default(),
+            total_timeout_ms: None,
+            stop_on_failure: true,
+        };
+
+        scenario
+            .normalize_paths(dir.as_path())
+            .await
+            .expect("normalization should not fail");
+
+        let to = |step: &StepConfig| match step {
+            StepConfig::FileDownload { from, to, .. } => {
+                assert_eq!(from, Path::new("output.txt"));
+                to.clone()
+            }
+            other => panic!("unexpected enum variant: {:?}", other),
+        };
+        assert_eq!(to(&scenario.steps[0][0]), Path::new("output.txt"));
+        assert_eq!(to(&scenario.steps[0][1]), tmp.path().join("output.txt"));
+        assert_eq!(
+            to(&scenario.steps[0][2]),
+            dir.join("missing").join("output.txt")
+        );
+    }
+
+    #[tokio::test]
+    async fn toml_suite() {
+        let tmp = tempfile::tempdir().unwrap();
+
+        let json = r#"{
+            "user": "student",
+            "build": {
+                "retries": 2,
+                "steps": [[
+                    {"type": "patch_transfer", "to": "solution.patch", "mode": "0600"},
+                    {"type": "command", "command": "./build.sh", "timeout_ms": "30s"}
+                ]]
+            },
+            "tests": {
+                "test_1": {
+                    "steps": [[{"type": "command", "command": "./test_1"}]]
+                }
+            }
+        }"#;
+        let toml = r#"
+            user = "student"
+
+            [build]
+            retries = 2
+            steps = [[
+                { type = "patch_transfer", to = "solution.patch", mode = "0600" },
+                { type = "command", command = "./build.sh", timeout_ms = "30s" },
+            ]]
+
+            [tests.test_1]
+            steps = [[{ type = "command", command = "./test_1" }]]
+        "#;
+
+        let json_path = tmp.path().join("suite.json");
+        fs::write(&json_path, json).await.unwrap();
+        let toml_path = tmp.path().join("suite.toml");
+        fs::write(&toml_path, toml).await.unwrap();
+
+        let from_json = RunConfig::from_file(&json_path)
+            .await
+            .expect("failed to read the JSON suite");
+        let from_toml = RunConfig::from_file(&toml_path)
+            .await
+            .expect("failed to read the TOML suite");
+        assert_eq!(format!("{:?}", from_json), format!("{:?}", from_toml));
+
+        fs::write(&toml_path, "user = ").await.unwrap();
+        assert!(matches!(
+            RunConfig::from_file(&toml_path).await,
+            Err(ConfigError::Toml(_))
+        ));
+    }
+
+    #[test]
+    fn env_expansion() {
+        let vars = HashMap::from([("DIR", "/fixtures"), ("EMPTY", "")]);
+        let lookup = |name: &str| vars.get(name).map(|value| value.to_string());
+
+        assert_eq!(
+            env_vars::expand("${DIR}/tests.zip", lookup),
+            Ok("/fixtures/tests.zip".into())
+        );
+        assert_eq!(
+            env_vars::expand("${UNSET:-root}:${EMPTY:-x}:${EMPTY}", lookup),
+            Ok("root:x:".into())
+        );
+        assert_eq!(
+            env_vars::expand("echo $${HOME} $HOME", lookup),
+            Ok("echo ${HOME} $HOME".into())
+        );
+        assert!(env_vars::expand("${UNSET}", lookup).is_err());
+        assert!(env_vars::expand("${DIR", lookup).is_err());
+        assert!(env_vars::expand("${A-B}", lookup).is_err());
+
+        let mut step = StepConfig::FileTransfer {
+            from: "${QEMU_TEST_RUNNER_SURELY_UNSET}/tests.zip".into(),
+            to: "tests.zip".into(),
+            mode: None,
+            timeout_ms: None,
+        };
+        match step.expand_env("tests.x.steps[0][1]") {
+            Err(ConfigError::Invalid(message)) => {
+                assert!(message.contains("QEMU_TEST_RUNNER_SURELY_UNSET"));
+                assert!(message.contains("tests.x.steps[0][1].from"));
+            }
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn env_expansion_skips_guest_strings() {
+        let command = "for f in *.c; do cc -o ${f%.c} $f; done; echo ${#HOME} ${HOME} ${QEMU_TEST_RUNNER_SURELY_UNSET}";
+        let mut step = StepConfig::Command {
+            command: command.into(),
+            expected_exit_code: None,
+            env: BTreeMap::from([("OUT".into(), "${HOME}/out".into())]),
+            retries: None,
+            timeout_ms: None,
+        };
+        step.expand_env("tests.x.steps[0][0]")
+            .expect("guest commands should not be expanded");
+        match step {
+            StepConfig::Command {
+                command: expanded,
+                env,
+                ..
+            } => {
+                assert_eq!(expanded, command);
+                assert_eq!(env["OUT"], "${HOME}/out");
+            }
+            other => panic!("unexpected step: {:?}", other),
+        }
+
+        let mut step = StepConfig::FileTransfer {
+            from: "tests.zip".into(),
+            to: "${HOME}/tests.zip".into(),
+            mode: None,
+            timeout_ms: None,
+        };
+        step.expand_env("tests.x.steps[0][1]").unwrap();
+        assert!(
+            matches!(step, StepConfig::FileTransfer { to, .. } if to == Path::new("${HOME}/tests.zip"))
+        );
+    }
+
+    #[tokio::test]
+    async fn glob_paths() {
+        let tmp = tempfile::tempdir().unwrap();
+        let fixtures = tmp.path().join("fixtures");
+        fs::create_dir(&fixtures).await.unwrap();
+        for name in ["b.c", "a.c", "a.h", "a[1].c"] {
+            fs::write(fixtures.join(name), &[]).await.unwrap();
+        }
+
+        let transfer = |from: &str| StepConfig::FileTransfer {
+            from: from.into(),
+            to: "tests".into(),
+            mode: Some(0o644),
+            timeout_ms: None,
+        };
+        let mut scenario = ScenarioConfig {
+            retries: None,
+            retry_delay_ms: None,
+            steps: vec![vec![transfer("fixtures/[ab].c"), transfer("fixtures/a.h")]],
+            on_failure: vec![],
+            disks: vec![],
+            depends_on: vec![],
+            expect: Default::default(),
+            total_timeout_ms: None,
+            stop_on_failure: true,
+        };
+        scenario
+            .normalize_paths(tmp.path())
+            .await
+            .expect("normalization should not fail");
+
+        let fixtures = fs::canonicalize(&fixtures).await.unwrap();
+        let expected = |name: &str, to: &str| StepConfig::FileTransfer {
+            from: fixtures.join(name),
+            to: to.into(),
+            mode: Some(0o644),
+            timeout_ms: None,
+        };
+        assert_eq!(
+            scenario.steps,
+            vec![vec![
+                expected("a.c", "tests/a.c"),
+                expected("b.c", "tests/b.c"),
+                expected("a.h", "tests"),
+            ]]
+        );
+
+        // An existing file with glob characters in its name is sent as it is.
+        scenario.steps = vec![vec![transfer("fixtures/a[1].c")]];
+        scenario
+            .normalize_paths(tmp.path())
+            .await
+            .expect("normalization should not fail");
+        assert_eq!(scenario.steps, vec![vec![expected("a[1].c", "tests")]]);
+
+        scenario.steps = vec![vec![transfer("fixtures/*.rs")]];
+        let error = scenario
+            .normalize_paths(tmp.path())
+            .await
+            .expect_err("glob without matches should fail");
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn includes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("tests");
+        fs::create_dir(&dir).await.unwrap();
+        fs::write(dir.join("data"), &[]).await.unwrap();
+
+        let suite = r#"{
+            "tests": {"a": {"steps": [[{"type": "command", "command": "./a"}]]}},
+            "include": ["tests/b.json", "c.toml"]
+        }"#;
+        fs::write(tmp.path().join("suite.json"), suite)
+            .await
+            .unwrap();
+        let included = r#"{
+            "b": {"steps": [[{"type": "file_transfer", "from": "data", "to": "data"}]]}
+        }"#;
+        fs::write(dir.join("b.json"), included).await.unwrap();
+        let included = r#"
+            [c]
+            retries = 3
+            steps = [[{ type = "command", command = "./c" }]]
+        "#;
+        fs::write(tmp.path().join("c.toml"), included)
+            .await
+            .unwrap();
+
+        let config = RunConfig::from_file(&tmp.path().join("suite.json"))
+            .await
+            .expect("failed to read the suite");
+        let mut names = config.tests.keys().cloned().collect::<Vec<_>>();
+        names.sort_unstable();
+        assert_eq!(names, ["a", "b", "c"]);
+        assert_eq!(config.tests["c"].retries, 3);
+        match &config.tests["b"].steps[0][0] {
+            Step::Action {
+                action: SshAction::Send { from, .. },
+                ..
+            } => assert_eq!(from, &fs::canonicalize(dir.join("data")).await.unwrap()),
+            other => panic!("unexpected step {:?}", other),
+        }
+
+        let included = r#"{"a": {"steps": [[{"type": "command", "command": "./a"}]]}}"#;
+        fs::write(dir.join("b.json"), included).await.unwrap();
+        assert!(matches!(
+            RunConfig::from_file(&tmp.path().join("suite.json")).await,
+            Err(ConfigError::Invalid(_))
+        ));
+    }
 }
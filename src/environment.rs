@@ -0,0 +1,151 @@
+use regex::Regex;
+use serde::Serialize;
+use std::{ffi::OsStr, fs::OpenOptions, path::Path};
+use tokio::{fs, process::Command};
+
+/// A fingerprint of the environment the runner is executed in.
+/// Useful for explaining differences between results obtained on different machines.
+#[derive(Debug, Serialize)]
+pub struct Environment {
+    /// Version of this program.
+    pub runner_version: &'static str,
+    /// First line of the `--version` output of the QEMU command, if available.
+    pub qemu_system_version: Option<String>,
+    /// First line of the `--version` output of the qemu-img command, if available.
+    pub qemu_img_version: Option<String>,
+    /// Version number parsed from `qemu_system_version` (e.g. `8.2.2`), if available.
+    pub qemu_system_release: Option<String>,
+    /// Version number parsed from `qemu_img_version`, if available.
+    pub qemu_img_release: Option<String>,
+    /// Release of the host kernel, if available.
+    pub kernel: Option<String>,
+    /// Whether KVM can be used by this process.
+    pub kvm_available: bool,
+    /// Errors that occurred when querying the versions of the QEMU commands.
+    #[serde(skip)]
+    tool_errors: Vec<String>,
+}
+
+impl Environment {
+    /// Path to the KVM device.
+    const KVM_DEVICE: &'static str = "/dev/kvm";
+
+    /// Path to the file containing the release of the running kernel.
+    const KERNEL_RELEASE: &'static str = "/proc/sys/kernel/osrelease";
+
+    /// Gathers information about the current environment.
+    /// # Arguments
+    /// * qemu_system - command used to spawn QEMU processes.
+    /// * qemu_img - command used to create images.
+    /// # Returns
+    /// A new instance of this struct.
+    pub async fn probe(qemu_system: &OsStr, qemu_img: &OsStr) -> Self {
+        let kernel = fs::read_to_string(Self::KERNEL_RELEASE)
+            .await
+            .ok()
+            .map(|release| release.trim().to_string());
+        let kvm_available = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(Path::new(Self::KVM_DEVICE))
+            .is_ok();
+
+        let mut tool_errors = Vec::new();
+        let mut version = |result: Result<String, String>| match result {
+            Ok(version) => Some(version),
+            Err(error) => {
+                tool_errors.push(error);
+                None
+            }
+        };
+        let qemu_system_version = version(Self::version(qemu_system).await);
+        let qemu_img_version = version(Self::version(qemu_img).await);
+
+        Self {
+            runner_version: env!("CARGO_PKG_VERSION"),
+            qemu_system_release: qemu_system_version.as_deref().and_then(Self::release),
+            qemu_img_release: qemu_img_version.as_deref().and_then(Self::release),
+            qemu_system_version,
+            qemu_img_version,
+            kernel,
+            kvm_available,
+            tool_errors,
+        }
+    }
+
+    /// # Returns
+    /// Errors that occurred when querying the versions of the QEMU commands.
+    /// If not empty, at least one of the commands cannot be used.
+    pub fn tool_errors(&self) -> &[String] {
+        &self.tool_errors
+    }
+
+    /// # Returns
+    /// The first line of the `--version` output of the given command,
+    /// or an error if the command could not be executed successfully.
+    async fn version(cmd: &OsStr) -> Result<String, String> {
+        let output = Command::new(cmd)
+            .arg("--version")
+            .output()
+            .await
+            .map_err(|error| format!("failed to execute {:?}: {}", cmd, error))?;
+        if !output.status.success() {
+            return Err(format!(
+                "{:?} --version failed ({}): {}",
+                cmd,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .next()
+            .map(|line| line.trim().to_string())
+            .filter(|line| !line.is_empty())
+            .ok_or_else(|| format!("{:?} --version printed no version", cmd))
+    }
+
+    /// # Returns
+    /// The version number from the given `--version` output line
+    /// (e.g. `8.2.2` from `QEMU emulator version 8.2.2 (Debian 1:8.2.2+ds-0ubuntu1)`).
+    fn release(line: &str) -> Option<String> {
+        let regex = Regex::new(r"version (\d+(?:\.\d+)*)").expect("invalid regex");
+        regex.captures(line).map(|captures| captures[1].to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::fake_command;
+    use test_case::test_case;
+
+    #[test_case(
+        "QEMU emulator version 8.2.2 (Debian 1:8.2.2+ds-0ubuntu1)",
+        Some("8.2.2")
+    )]
+    #[test_case("qemu-img version 7.0.0", Some("7.0.0"))]
+    #[test_case("qemu-img version unknown", None)]
+    #[test_case("", None)]
+    fn release(line: &str, expected: Option<&str>) {
+        assert_eq!(Environment::release(line).as_deref(), expected);
+    }
+
+    #[tokio::test]
+    async fn tool_errors() {
+        let tmp = tempfile::tempdir().unwrap();
+        let silent = fake_command(tmp.path(), "qemu-img", "exit 0\n").await;
+
+        let environment = Environment::probe("false".as_ref(), silent.as_os_str()).await;
+        assert!(environment.qemu_system_version.is_none());
+        assert!(environment.qemu_img_version.is_none());
+        assert_eq!(environment.tool_errors().len(), 2);
+        assert!(environment.tool_errors()[0].contains("\"false\" --version failed"));
+        assert!(environment.tool_errors()[1].contains("printed no version"));
+
+        let missing = tmp.path().join("qemu-system");
+        let environment = Environment::probe(missing.as_os_str(), missing.as_os_str()).await;
+        assert!(environment.tool_errors()[0].contains("failed to execute"));
+    }
+}
@@ -1,7 +1,7 @@
-use super::{ActionReport, ExecutorConfig, ExecutorReport};
+use super::{ActionReport, ExecutorConfig, ExecutorReport, KeepInstance};
 use crate::{
     qemu::QemuInstance,
-    ssh::{SshAction, SshHandle},
+    ssh::{self, OutputLimits, SshAction, SshHandle, SshOptions},
     Output,
 };
 use std::{
@@ -15,7 +15,13 @@ pub struct BaseExecutor<'a> {
     qemu: QemuInstance,
     config: &'a ExecutorConfig,
     ssh: Option<SshHandle>,
+    ssh_attempts: usize,
+    ssh_error: Option<io::Error>,
+    ready: bool,
+    died: bool,
     reports: Vec<ActionReport>,
+    last: bool,
+    may_keep: bool,
 }
 
 impl<'a> BaseExecutor<'a> {
@@ -24,27 +30,87 @@ impl<'a> BaseExecutor<'a> {
     /// * config - configuration for SSH and timeouts.
     /// # Returns
     /// A new instance of this struct.
-    pub async fn new(mut qemu: QemuInstance, config: &'a ExecutorConfig) -> BaseExecutor<'a> {
-        let ssh = time::timeout(config.connection_timeout, async {
+    pub async fn new(qemu: QemuInstance, config: &'a ExecutorConfig) -> BaseExecutor<'a> {
+        Self::with_connection_timeout(qemu, config, config.connection_timeout).await
+    }
+
+    /// # Arguments
+    /// * qemu - the QEMU process to wrap.
+    /// * config - configuration for SSH and timeouts.
+    /// * connection_timeout - timeout for the SSH connection, used instead of
+    ///   [ExecutorConfig::connection_timeout].
+    /// # Returns
+    /// A new instance of this struct.
+    pub async fn with_connection_timeout(
+        mut qemu: QemuInstance,
+        config: &'a ExecutorConfig,
+        connection_timeout: Duration,
+    ) -> BaseExecutor<'a> {
+        let mut ssh_attempts = 0;
+        let mut ssh_error = None;
+        let mut ssh = time::timeout(connection_timeout, async {
             while qemu.try_wait().transpose().is_none() {
+                if config
+                    .max_connection_attempts
+                    .is_some_and(|max| ssh_attempts >= max)
+                {
+                    log::debug!(
+                        "Reached the limit of SSH connection attempts to the QEMU instance [{}].",
+                        qemu.image_path().to_string_lossy()
+                    );
+                    break;
+                }
+
                 let handle = match qemu.ssh().await {
                     Ok(addr) => {
-                        SshHandle::new(
+                        let options = SshOptions {
+                            output_limits: OutputLimits {
+                                stdout: config.stdout_limit,
+                                stderr: config.stderr_limit,
+                            },
+                            shell: config.shell.clone(),
+                            transfer_buffer_size: config.transfer_buffer_size,
+                            known_hosts: config.known_hosts.clone(),
+                            stream_output: config.stream_output,
+                            poll_interval: config.poll_interval,
+                            worker_permits: config.ssh_worker_permits.clone(),
+                        };
+                        let connect = SshHandle::connect(
                             addr,
                             config.user.clone(),
-                            config.password.clone(),
-                            config.output_limit,
-                        )
-                        .await
+                            config.auth.clone(),
+                            options,
+                        );
+
+                        ssh_attempts += 1;
+                        tokio::select! {
+                            handle = connect => handle,
+                            _ = qemu.exited() => break,
+                        }
+                    }
+                    Err(error) if error.kind() == io::ErrorKind::TimedOut => {
+                        // The monitor is not available, the QEMU process is not going to expose SSH.
+                        ssh_error = Some(error);
+                        break;
                     }
-                    Err(e) => Err(e),
+                    Err(error) => Err(error),
                 };
 
-                if let Ok(handle) = handle {
-                    return Some(handle);
+                match handle {
+                    Ok(handle) => return Some(handle),
+                    Err(error) if ssh::is_permanent(&error) => {
+                        log::warn!(
+                            "Failed to connect to the QEMU instance [{}], not retrying. Error: {}.",
+                            qemu.image_path().to_string_lossy(),
+                            error
+                        );
+                        ssh_error = Some(error);
+                        break;
+                    }
+                    Err(error) => ssh_error = Some(error),
                 }
 
-                time::sleep(Duration::from_millis(100)).await;
+                time::sleep(config.poll_interval).await;
             }
 
             None
@@ -52,51 +118,232 @@ impl<'a> BaseExecutor<'a> {
         .await
         .ok()
         .flatten();
-
         if ssh.is_some() {
+            ssh_error = None;
+        }
+
+        let died = ssh.is_none() && matches!(qemu.try_wait(), Ok(Some(_)));
+        if died {
             log::debug!(
-                "Established an SSH connection to the QEMU instance [{}].",
+                "QEMU instance [{}] exited before an SSH connection was established.",
                 qemu.image_path().to_string_lossy()
             );
         }
 
+        let ready = match ssh.as_mut() {
+            Some(ssh) => {
+                log::debug!(
+                    "Established an SSH connection to the QEMU instance [{}].",
+                    qemu.image_path().to_string_lossy()
+                );
+
+                match config.ready_command.as_ref() {
+                    Some(cmd) => {
+                        let ready = Self::wait_until_ready(
+                            ssh,
+                            cmd,
+                            config.ready_timeout,
+                            config.poll_interval,
+                        )
+                        .await;
+                        if !ready {
+                            log::debug!(
+                                "QEMU instance [{}] did not become ready on time.",
+                                qemu.image_path().to_string_lossy()
+                            );
+                        }
+                        ready
+                    }
+                    None => true,
+                }
+            }
+            None => false,
+        };
+
         Self {
             qemu,
             config,
             ssh,
+            ssh_attempts,
+            ssh_error,
+            ready,
+            died,
             reports: Default::default(),
+            last: false,
+            may_keep: true,
+        }
+    }
+
+    /// Marks this executor as the last one operating on its image.
+    /// Only such executors leave the QEMU instance running with [KeepInstance::Always].
+    pub fn mark_last(&mut self) {
+        self.last = true;
+    }
+
+    /// Prevents this executor from leaving the QEMU instance running,
+    /// regardless of [ExecutorConfig::keep_instance].
+    /// Used when the image is later used as a backing image, which a running instance could still modify.
+    pub fn forbid_keeping(&mut self) {
+        self.may_keep = false;
+    }
+
+    /// # Returns
+    /// Whether the wrapped QEMU instance should be left running instead of powered off.
+    fn should_keep(&self) -> bool {
+        if !self.may_keep {
+            return false;
+        }
+
+        let failed = !self.ready
+            || self
+                .reports
+                .iter()
+                .any(|report| !report.diagnostic() && !report.retried() && !report.success());
+
+        match self.config.keep_instance {
+            KeepInstance::Never => false,
+            KeepInstance::Failure => failed,
+            KeepInstance::Always => failed || self.last,
         }
     }
 
+    /// Repeatedly executes the readiness command until it succeeds.
+    /// # Arguments
+    /// * ssh - handle to the guest machine.
+    /// * cmd - the readiness command.
+    /// * timeout - a timeout for the whole process.
+    /// * poll_interval - delay between executions.
+    /// # Returns
+    /// Whether the readiness command succeeded on time.
+    async fn wait_until_ready(
+        ssh: &mut SshHandle,
+        cmd: &str,
+        timeout: Duration,
+        poll_interval: Duration,
+    ) -> bool {
+        time::timeout(timeout, async {
+            loop {
+                let action = SshAction::Exec {
+                    cmd: cmd.into(),
+                    expected_exit_code: None,
+                    env: vec![],
+                };
+                match ssh.exec(action).await {
+                    Ok(output) if output.success() => break true,
+                    Ok(_) => time::sleep(poll_interval).await,
+                    Err(_) => break false,
+                }
+            }
+        })
+        .await
+        .unwrap_or(false)
+    }
+
     /// # Arguments
     /// * action - an [SshAction] to run on the wrapped QEMU process.
     /// * timeout - a timeout for this action.
     /// # Returns
     /// Whether the execution was successful.
     pub async fn run(&mut self, action: SshAction, timeout: Duration) -> io::Result<bool> {
+        self.run_inner(action, timeout, false).await
+    }
+
+    /// Runs an [SshAction], executing it again if it fails.
+    /// Reports from failed attempts are collected and marked as retried (see [ActionReport::retried]),
+    /// so they do not affect the success of the whole execution.
+    /// # Arguments
+    /// * action - an [SshAction] to run on the wrapped QEMU process.
+    /// * timeout - a timeout for a single attempt.
+    /// * retries - maximal number of additional attempts.
+    /// # Returns
+    /// Whether the last attempt was successful.
+    pub async fn run_with_retries(
+        &mut self,
+        action: SshAction,
+        timeout: Duration,
+        retries: usize,
+    ) -> io::Result<bool> {
+        for retry in 0..retries {
+            let reports = self.reports.len();
+            if self.run(action.clone(), timeout).await? {
+                return Ok(true);
+            }
+            if self.reports.len() == reports {
+                // The action was not executed at all, e.g. because the guest machine is not ready.
+                return Ok(false);
+            }
+
+            if let Some(report) = self.reports.last_mut() {
+                report.retried = true;
+            }
+            log::debug!(
+                "Retrying an action {:?} on the QEMU instance [{}] ({}/{}).",
+                action,
+                self.qemu.image_path().to_string_lossy(),
+                retry + 1,
+                retries
+            );
+        }
+
+        self.run(action, timeout).await
+    }
+
+    /// Runs a diagnostic [SshAction]. Its report is collected,
+    /// but does not affect the success of the whole execution.
+    /// # Arguments
+    /// * action - an [SshAction] to run on the wrapped QEMU process.
+    /// * timeout - a timeout for this action.
+    /// # Returns
+    /// Whether the execution was successful.
+    pub async fn run_diagnostic(
+        &mut self,
+        action: SshAction,
+        timeout: Duration,
+    ) -> io::Result<bool> {
+        self.run_inner(action, timeout, true).await
+    }
+
+    async fn run_inner(
+        &mut self,
+        action: SshAction,
+        timeout: Duration,
+        diagnostic: bool,
+    ) -> io::Result<bool> {
         let ssh = match self.ssh.as_mut() {
-            Some(ssh) => ssh,
-            None => return Ok(false),
+            Some(ssh) if self.ready => ssh,
+            _ => return Ok(false),
         };
 
-        let start = Instant::now();
-        let res = time::timeout(timeout, ssh.exec(action.clone())).await;
-        let elapsed_time = start.elapsed();
+        let rejection = match &action {
+            SshAction::Exec { cmd, .. } => self.config.command_policy.check(cmd).err(),
+            _ => None,
+        };
 
-        let output = match res {
-            Ok(res) => res?,
-            Err(_) => Output::Error {
-                error: io::Error::new(io::ErrorKind::TimedOut, "action timed out"),
+        let start = Instant::now();
+        let output = match rejection {
+            Some(reason) => Output::Error {
+                error: io::Error::new(io::ErrorKind::PermissionDenied, reason),
+            },
+            None => match &action {
+                // Sleeping does not need an SSH channel.
+                SshAction::Sleep { duration_ms } => {
+                    time::sleep(Duration::from_millis(*duration_ms)).await;
+                    Output::empty_success()
+                }
+                _ => ssh.exec_with_timeout(action.clone(), timeout).await?,
             },
         };
-        let success = output.success();
+        let elapsed_time = start.elapsed();
 
         let report = ActionReport {
             action,
             timeout_ms: timeout.as_millis(),
             elapsed_time_ms: elapsed_time.as_millis(),
             output,
+            diagnostic,
+            retried: false,
         };
+        let success = report.success();
         log::debug!(
             "Executed an action {:?} on the QEMU instance [{}].",
             report,
@@ -109,13 +356,45 @@ impl<'a> BaseExecutor<'a> {
 
     /// Executes a poweroff command (configured with the [ExecutorConfig]) on the wrapped QEMU process
     /// and waits for the process to exit.
+    /// If the process should be kept running (see [KeepInstance]), it is detached instead.
+    /// If a graceful shutdown is disabled (see [ExecutorConfig::graceful_shutdown]), it is killed instead.
     /// # Returns
     /// A report from all [SshAction]s performed through this struct.
     pub async fn finish(mut self) -> io::Result<ExecutorReport> {
         let image = self.qemu.image_path().to_os_string();
-
-        let (ssh_ok, exit_ok) = match self.ssh.as_mut() {
+        let extra_drives = self.qemu.extra_drives().to_vec();
+        let kvm = self.qemu.kvm();
+        let spawned_at = self.qemu.spawned_at();
+        let console_log = self.qemu.console_log();
+        let stderr = self.qemu.stderr();
+
+        let keep = self.ssh.is_some() && self.should_keep();
+        let hard_kill = self.ssh.is_some() && !keep && !self.config.graceful_shutdown;
+
+        let (ssh_ok, exit_ok, qemu_exit) = match self.ssh.as_mut() {
+            Some(_) if keep => {
+                let addr = self.qemu.ssh().await?;
+                log::warn!(
+                    "Leaving the QEMU instance [{}] running, SSH address: {}.",
+                    image.to_string_lossy(),
+                    addr
+                );
+                self.qemu.detach();
+                (true, false, None)
+            }
+            Some(_) if hard_kill => {
+                log::debug!(
+                    "Killing the QEMU instance [{}] without a poweroff.",
+                    image.to_string_lossy()
+                );
+                self.qemu.kill().await.ok();
+                (true, false, self.qemu.wait().await.ok())
+            }
             Some(ssh) => {
+                if !self.config.pre_poweroff_delay.is_zero() {
+                    time::sleep(self.config.pre_poweroff_delay).await;
+                }
+
                 log::debug!(
                     "Executing a poweroff command '{}' on the QEMU instance [{}].",
                     self.config.poweroff_command,
@@ -123,6 +402,8 @@ impl<'a> BaseExecutor<'a> {
                 );
                 let action = SshAction::Exec {
                     cmd: self.config.poweroff_command.clone(),
+                    expected_exit_code: None,
+                    env: vec![],
                 };
 
                 let res: Result<Result<_, io::Error>, _> =
@@ -130,7 +411,7 @@ impl<'a> BaseExecutor<'a> {
                         ssh.exec(action.clone()).await?;
 
                         while self.qemu.try_wait()?.is_none() {
-                            time::sleep(Duration::from_millis(100)).await;
+                            time::sleep(self.config.poll_interval).await;
                         }
 
                         Ok(())
@@ -140,8 +421,8 @@ impl<'a> BaseExecutor<'a> {
                 match res {
                     Ok(Ok(_)) => {
                         log::debug!("QEMU process [{}] exited on time.", image.to_string_lossy());
-                        self.qemu.wait().await?;
-                        (true, true)
+                        let exit = self.qemu.wait().await?;
+                        (true, exit.clean(), Some(exit))
                     }
                     Ok(Err(error)) => return Err(error),
                     Err(_) => {
@@ -150,31 +431,147 @@ impl<'a> BaseExecutor<'a> {
                             image.to_string_lossy()
                         );
                         self.qemu.kill().await.ok();
-                        self.qemu.wait().await.ok();
-                        (true, false)
+                        (true, false, self.qemu.wait().await.ok())
+                    }
+                }
+            }
+            None if self.config.use_monitor_powerdown && !self.died => {
+                log::debug!(
+                    "Requesting a powerdown of the QEMU instance [{}] through the monitor.",
+                    image.to_string_lossy()
+                );
+
+                let res: Result<Result<_, io::Error>, _> =
+                    time::timeout(self.config.poweroff_timeout, async {
+                        self.qemu.powerdown().await?;
+                        self.qemu.exited().await
+                    })
+                    .await;
+
+                match res {
+                    Ok(Ok(_)) => {
+                        log::debug!("QEMU process [{}] exited on time.", image.to_string_lossy());
+                        let exit = self.qemu.wait().await?;
+                        (false, exit.clean(), Some(exit))
+                    }
+                    _ => {
+                        log::debug!(
+                            "QEMU process [{}] did not power down on time, killing the process.",
+                            image.to_string_lossy()
+                        );
+                        self.qemu.kill().await.ok();
+                        (false, false, self.qemu.wait().await.ok())
                     }
                 }
             }
             None => {
                 self.qemu.kill().await.ok();
-                self.qemu.wait().await.ok();
-                (false, false)
+                (false, false, self.qemu.wait().await.ok())
             }
         };
 
+        let no_actions = ssh_ok && self.config.require_actions && self.reports.is_empty();
+        if no_actions {
+            log::debug!(
+                "No actions were executed on the QEMU instance [{}].",
+                image.to_string_lossy()
+            );
+        }
+
         Ok(ExecutorReport {
             image: image.into(),
+            extra_drives,
+            kvm,
+            spawned_at: Some(spawned_at),
             ssh_ok,
             action_reports: self.reports,
+            ready_ok: self.ready,
             exit_ok,
+            qemu_exit,
+            no_actions,
+            kept: keep,
+            reused: false,
+            hard_killed: hard_kill,
+            died_before_ssh: self.died,
+            ssh_attempts: self.ssh_attempts,
+            ssh_error: self.ssh_error.map(|error| error.to_string()),
+            console_log: console_log.contents(),
+            qemu_stderr: if exit_ok || keep || hard_kill {
+                None
+            } else {
+                stderr.tail()
+            },
         })
     }
+
+    /// # Returns
+    /// Whether an SSH connection was established, the guest machine became ready
+    /// and all non-diagnostic [SshAction]s performed through this struct were successful.
+    pub fn success(&self) -> bool {
+        self.ready()
+            && !(self.config.require_actions && self.reports.is_empty())
+            && self
+                .reports
+                .iter()
+                .filter(|report| !report.diagnostic() && !report.retried())
+                .all(ActionReport::success)
+    }
+
+    /// # Returns
+    /// Whether an SSH connection was established and the guest machine became ready.
+    pub fn ready(&self) -> bool {
+        self.ssh.is_some() && self.ready
+    }
+
+    /// Saves the state of the wrapped QEMU process as a snapshot with the given name.
+    /// See [QemuInstance::savevm].
+    pub async fn savevm(&self, name: &str) -> io::Result<()> {
+        log::debug!(
+            "Saving snapshot {} of the QEMU instance [{}].",
+            name,
+            self.qemu.image_path().to_string_lossy()
+        );
+        self.qemu.savevm(name).await
+    }
+
+    /// Closes the SSH connection, but leaves the wrapped QEMU process running,
+    /// so that it can be restored from a snapshot and used again.
+    /// # Returns
+    /// A report from all [SshAction]s performed through this struct and the wrapped QEMU process.
+    pub fn suspend(self) -> (ExecutorReport, QemuInstance) {
+        let report = ExecutorReport {
+            image: self.qemu.image_path().into(),
+            extra_drives: self.qemu.extra_drives().to_vec(),
+            kvm: self.qemu.kvm(),
+            spawned_at: Some(self.qemu.spawned_at()),
+            ssh_ok: self.ssh.is_some(),
+            action_reports: self.reports,
+            ready_ok: self.ready,
+            exit_ok: false,
+            qemu_exit: None,
+            no_actions: false,
+            kept: false,
+            reused: true,
+            hard_killed: false,
+            died_before_ssh: self.died,
+            ssh_attempts: self.ssh_attempts,
+            ssh_error: self.ssh_error.map(|error| error.to_string()),
+            console_log: self.qemu.console_log().contents(),
+            qemu_stderr: None,
+        };
+
+        (report, self.qemu)
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::{qemu::Image, test_util::Env};
+    use crate::{
+        qemu::{Image, QemuConfig, QemuExit, QemuSpawner},
+        test_util::{fake_command, Env},
+    };
+    use test_case::test_case;
 
     async fn run_executor(
         config: &ExecutorConfig,
@@ -190,7 +587,7 @@ mod test {
             .expect("failed to build the image");
         let qemu = env
             .spawner(1)
-            .spawn(image.into())
+            .spawn(image.into(), vec![])
             .await
             .expect("failed to spawn the QEMU process");
 
@@ -207,12 +604,8 @@ mod test {
     #[tokio::test]
     async fn ssh_timeout() {
         let config = ExecutorConfig {
-            user: "root".into(),
-            password: "root".into(),
             connection_timeout: Duration::from_secs(1),
-            poweroff_timeout: Duration::from_secs(20),
-            poweroff_command: "/sbin/poweroff".into(),
-            output_limit: None,
+            ..ExecutorConfig::test()
         };
         let actions = vec![];
 
@@ -226,20 +619,137 @@ mod test {
         assert!(!report.exit_ok);
     }
 
-    #[ignore]
     #[tokio::test]
-    async fn faulty_command() {
+    async fn qemu_died_before_ssh() {
+        let spawner = QemuSpawner::new(
+            1,
+            QemuConfig {
+                cmd: "false".into(),
+                ..QemuConfig::test()
+            },
+        );
+        let qemu = spawner
+            .spawn("image.qcow2".into(), vec![])
+            .await
+            .expect("failed to spawn the process");
+        let config = ExecutorConfig::test();
+
+        let start = Instant::now();
+        let report = BaseExecutor::new(qemu, &config)
+            .await
+            .finish()
+            .await
+            .unwrap();
+
+        assert!(start.elapsed() < config.connection_timeout);
+        assert!(!report.success());
+        assert!(!report.ssh_ok);
+        assert!(report.died_before_ssh);
+        assert_eq!(report.ssh_attempts, 0);
+        assert!(!report.exit_ok);
+        assert_eq!(report.qemu_exit, Some(QemuExit::Code(1)));
+    }
+
+    #[test_case(true ; "with kvm")]
+    #[test_case(false ; "without kvm")]
+    #[tokio::test]
+    async fn kvm_recorded(enable_kvm: bool) {
+        let spawner = QemuSpawner::new(
+            1,
+            QemuConfig {
+                cmd: "false".into(),
+                enable_kvm,
+                ..QemuConfig::test()
+            },
+        );
+        let qemu = spawner
+            .spawn("image.qcow2".into(), vec![])
+            .await
+            .expect("failed to spawn the process");
+        let config = ExecutorConfig::test();
+
+        let report = BaseExecutor::new(qemu, &config)
+            .await
+            .finish()
+            .await
+            .unwrap();
+
+        assert_eq!(report.kvm(), enable_kvm);
+        let serialized = serde_json::to_value(&report).unwrap();
+        assert_eq!(serialized["kvm_enabled"], enable_kvm);
+    }
+
+    #[tokio::test]
+    async fn qemu_stderr() {
+        let tmp = tempfile::tempdir().unwrap();
+        let script = fake_command(
+            tmp.path(),
+            "qemu-system",
+            "echo 'no such device' >&2\nexit 1\n",
+        )
+        .await;
+
+        let spawner = QemuSpawner::new(
+            1,
+            QemuConfig {
+                cmd: script.into_os_string(),
+                ..QemuConfig::test()
+            },
+        );
+        let qemu = spawner
+            .spawn("image.qcow2".into(), vec![])
+            .await
+            .expect("failed to spawn the process");
+        let config = ExecutorConfig::test();
+
+        let report = BaseExecutor::new(qemu, &config)
+            .await
+            .finish()
+            .await
+            .unwrap();
+
+        assert!(!report.exit_ok);
+        assert_eq!(report.qemu_stderr(), Some("no such device"));
+    }
+
+    #[tokio::test]
+    async fn forbid_keeping() {
+        let tmp = tempfile::tempdir().unwrap();
+        let script = fake_command(tmp.path(), "qemu-system", "exit 1\n").await;
+
+        let spawner = QemuSpawner::new(
+            1,
+            QemuConfig {
+                cmd: script.into_os_string(),
+                ..QemuConfig::test()
+            },
+        );
         let config = ExecutorConfig {
-            user: "root".into(),
-            password: "root".into(),
-            connection_timeout: Duration::from_secs(20),
-            poweroff_timeout: Duration::from_secs(20),
-            poweroff_command: "/sbin/poweroff".into(),
-            output_limit: None,
+            keep_instance: KeepInstance::Always,
+            ..ExecutorConfig::test()
         };
+
+        let qemu = spawner
+            .spawn("image.qcow2".into(), vec![])
+            .await
+            .expect("failed to spawn the process");
+        let mut executor = BaseExecutor::new(qemu, &config).await;
+        assert!(executor.should_keep());
+        executor.forbid_keeping();
+        assert!(!executor.should_keep());
+        executor.mark_last();
+        assert!(!executor.should_keep());
+    }
+
+    #[ignore]
+    #[tokio::test]
+    async fn faulty_command() {
+        let config = ExecutorConfig::test();
         let actions = vec![(
             SshAction::Exec {
                 cmd: "idonotexist".into(),
+                expected_exit_code: None,
+                env: vec![],
             },
             Duration::from_secs(2),
         )];
@@ -255,16 +765,40 @@ mod test {
         assert!(report.exit_ok);
     }
 
+    #[ignore]
+    #[tokio::test]
+    async fn partial_output_on_timeout() {
+        let config = ExecutorConfig {
+            stream_output: true,
+            ..ExecutorConfig::test()
+        };
+        let actions = vec![(
+            SshAction::Exec {
+                cmd: "echo started && sleep 30".into(),
+                expected_exit_code: None,
+                env: vec![],
+            },
+            Duration::from_secs(2),
+        )];
+
+        let report = time::timeout(Duration::from_secs(60), run_executor(&config, actions))
+            .await
+            .expect("timeout");
+
+        assert!(!report.success());
+        match report.action_reports()[0].output() {
+            Output::Timeout { stdout, .. } => assert_eq!(stdout, b"started\n"),
+            other => panic!("unexpected output {:?}", other),
+        }
+        assert!(report.exit_ok);
+    }
+
     #[ignore]
     #[tokio::test]
     async fn invalid_poweroff() {
         let config = ExecutorConfig {
-            user: "root".into(),
-            password: "root".into(),
-            connection_timeout: Duration::from_secs(20),
-            poweroff_timeout: Duration::from_secs(20),
             poweroff_command: "/i/do/not/work".into(),
-            output_limit: None,
+            ..ExecutorConfig::test()
         };
         let actions = vec![];
 
@@ -280,21 +814,81 @@ mod test {
 
     #[ignore]
     #[tokio::test]
-    async fn all_good() {
+    async fn hard_kill() {
+        let config = ExecutorConfig {
+            poweroff_command: "/i/do/not/work".into(),
+            graceful_shutdown: false,
+            ..ExecutorConfig::test()
+        };
+        let actions = vec![(
+            SshAction::Exec {
+                cmd: "ls".into(),
+                expected_exit_code: None,
+                env: vec![],
+            },
+            Duration::from_secs(1),
+        )];
+
+        let start = Instant::now();
+        let report = time::timeout(Duration::from_secs(60), run_executor(&config, actions))
+            .await
+            .expect("timeout");
+
+        assert!(start.elapsed() < Duration::from_secs(60) - config.poweroff_timeout);
+        assert!(report.success());
+        assert!(report.hard_killed);
+        assert!(!report.exit_ok);
+    }
+
+    #[ignore]
+    #[tokio::test]
+    async fn never_ready() {
         let config = ExecutorConfig {
-            user: "root".into(),
-            password: "root".into(),
-            connection_timeout: Duration::from_secs(20),
-            poweroff_timeout: Duration::from_secs(20),
-            poweroff_command: "/sbin/poweroff".into(),
-            output_limit: None,
+            ready_command: Some("false".into()),
+            ready_timeout: Duration::from_secs(2),
+            ..ExecutorConfig::test()
         };
+        let actions = vec![(
+            SshAction::Exec {
+                cmd: "ls".into(),
+                expected_exit_code: None,
+                env: vec![],
+            },
+            Duration::from_secs(1),
+        )];
+
+        let report = time::timeout(Duration::from_secs(60), run_executor(&config, actions))
+            .await
+            .expect("timeout");
+
+        assert!(!report.success());
+        assert!(report.ssh_ok);
+        assert!(!report.ready_ok);
+        assert!(report.action_reports.is_empty());
+        assert!(report.exit_ok);
+    }
+
+    #[ignore]
+    #[tokio::test]
+    async fn all_good() {
+        let config = ExecutorConfig::test();
         let actions = vec![
             (
-                SshAction::Exec { cmd: "pwd".into() },
+                SshAction::Exec {
+                    cmd: "pwd".into(),
+                    expected_exit_code: None,
+                    env: vec![],
+                },
+                Duration::from_secs(1),
+            ),
+            (
+                SshAction::Exec {
+                    cmd: "ls".into(),
+                    expected_exit_code: None,
+                    env: vec![],
+                },
                 Duration::from_secs(1),
             ),
-            (SshAction::Exec { cmd: "ls".into() }, Duration::from_secs(1)),
         ];
 
         let report = time::timeout(Duration::from_secs(60), run_executor(&config, actions))
@@ -307,4 +901,23 @@ mod test {
         assert!(report.action_reports.iter().all(|report| report.success()));
         assert!(report.exit_ok);
     }
+
+    #[ignore]
+    #[tokio::test]
+    async fn required_actions_missing() {
+        let config = ExecutorConfig {
+            require_actions: true,
+            ..ExecutorConfig::test()
+        };
+        let actions = vec![];
+
+        let report = time::timeout(Duration::from_secs(60), run_executor(&config, actions))
+            .await
+            .expect("timeout");
+
+        assert!(!report.success());
+        assert!(report.ssh_ok);
+        assert!(report.no_actions);
+        assert!(report.exit_ok);
+    }
 }
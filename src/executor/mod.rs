@@ -1,29 +1,148 @@
-use crate::{ssh::SshAction, Output};
+use crate::{
+    qemu::{Drive, QemuExit},
+    ssh::{SshAction, SshAuth},
+    Output,
+};
+use regex::Regex;
 use serde::Serialize;
 use std::{
+    num::NonZeroUsize,
+    ops::Not,
     path::{Path, PathBuf},
-    time::Duration,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
 };
+use tokio::sync::Semaphore;
 
 pub mod base;
 pub mod stack;
 
+/// Decides when a [crate::qemu::QemuInstance] is left running after its [SshAction]s are executed,
+/// instead of being powered off.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum KeepInstance {
+    /// Instances are always powered off.
+    #[default]
+    Never,
+    /// Instances are left running when one of their [SshAction]s fails.
+    Failure,
+    /// Instances are left running after the last stack of [SshAction]s or a failure.
+    Always,
+}
+
+impl FromStr for KeepInstance {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(Self::Never),
+            "failure" => Ok(Self::Failure),
+            "always" => Ok(Self::Always),
+            other => Err(format!(
+                "invalid mode '{}', expected one of: never, failure, always",
+                other
+            )),
+        }
+    }
+}
+
+/// Patterns restricting commands executed with [SshAction::Exec].
+#[derive(Debug, Default)]
+pub struct CommandPolicy {
+    /// If not empty, every command must match at least one of these patterns.
+    pub allow: Vec<Regex>,
+    /// Commands matching any of these patterns are rejected.
+    pub deny: Vec<Regex>,
+}
+
+impl CommandPolicy {
+    /// # Arguments
+    /// * cmd - the command to check.
+    /// # Returns
+    /// The reason the command is not allowed, if it is not.
+    pub fn check(&self, cmd: &str) -> Result<(), String> {
+        if let Some(pattern) = self.deny.iter().find(|pattern| pattern.is_match(cmd)) {
+            return Err(format!(
+                "command matches a denied pattern '{}'",
+                pattern.as_str()
+            ));
+        }
+
+        if !self.allow.is_empty() && !self.allow.iter().any(|pattern| pattern.is_match(cmd)) {
+            return Err("command does not match any allowed pattern".into());
+        }
+
+        Ok(())
+    }
+}
+
 /// Config for running an executor.
 #[derive(Debug)]
 pub struct ExecutorConfig {
     /// The user executing [SshAction]s.
     pub user: String,
-    /// The password for the user.
-    pub password: String,
+    /// The method of authenticating the user.
+    pub auth: SshAuth,
     /// Timeout for opening an SSH connection with the [crate::qemu::QemuInstance].
     pub connection_timeout: Duration,
+    /// Path to an OpenSSH known hosts file used to verify the host key of the guest machine.
+    /// If [None], the host key is not verified.
+    pub known_hosts: Option<PathBuf>,
+    /// Interval between checks in loops waiting for the [crate::qemu::QemuInstance]
+    /// (e.g. between SSH connection attempts).
+    pub poll_interval: Duration,
+    /// Limit for the number of attempts to open an SSH connection with the [crate::qemu::QemuInstance].
+    /// If [None], attempts are made until the `connection_timeout` elapses.
+    pub max_connection_attempts: Option<usize>,
     /// Timeout for [crate::qemu::QemuInstance] shutdown after executing a poweroff command.
     pub poweroff_timeout: Duration,
     /// The command that will be used to shutdown the [crate::qemu::QemuInstance].
     pub poweroff_command: String,
-    /// A limit for stdout and stderr of executed commands.
+    /// Whether to request a powerdown through the QEMU monitor when no SSH connection is available,
+    /// instead of killing the [crate::qemu::QemuInstance] right away.
+    /// The `poweroff_timeout` applies.
+    pub use_monitor_powerdown: bool,
+    /// Delay before executing the `poweroff_command`.
+    /// Gives the guest machine time to flush its filesystems.
+    pub pre_poweroff_delay: Duration,
+    /// Whether to power off the [crate::qemu::QemuInstance] with the `poweroff_command` when done.
+    /// If false, the process is killed right away, which is faster,
+    /// but writes not yet flushed by the guest machine may be lost.
+    pub graceful_shutdown: bool,
+    /// A command that must succeed before any [SshAction] is executed.
+    /// It is retried until it succeeds or the `ready_timeout` elapses.
+    pub ready_command: Option<String>,
+    /// Timeout for the `ready_command` to succeed.
+    pub ready_timeout: Duration,
+    /// Shell used to execute commands, invoked as `<shell> -c '<command>'`.
+    /// If [None], commands are executed directly by the SSH server.
+    pub shell: Option<String>,
+    /// A limit for stdout of executed commands.
+    /// The outputs will be truncated to this length.
+    pub stdout_limit: Option<u64>,
+    /// A limit for stderr of executed commands.
     /// The outputs will be truncated to this length.
-    pub output_limit: Option<u64>,
+    pub stderr_limit: Option<u64>,
+    /// Whether to collect outputs of commands while they are running,
+    /// so that commands that time out are reported with their partial outputs.
+    pub stream_output: bool,
+    /// Size of the buffer used for file transfers (bytes).
+    /// If [None], a default size is used.
+    pub transfer_buffer_size: Option<NonZeroUsize>,
+    /// Whether a stack in which no actions were executed should be treated as a failure.
+    pub require_actions: bool,
+    /// Restrictions for executed commands.
+    pub command_policy: CommandPolicy,
+    /// When to leave the [crate::qemu::QemuInstance] running for inspection.
+    pub keep_instance: KeepInstance,
+    /// Whether to save a snapshot of the [crate::qemu::QemuInstance] after the first boot
+    /// and restore it between stacks instead of rebooting (see [stack::StackExecutor]).
+    /// Changes made in a stack are not visible in the following stacks.
+    pub snapshot_stacks: bool,
+    /// Permits limiting the number of SSH workers running at once (see [crate::ssh::SshOptions::worker_permits]).
+    /// If [None], the number of workers is not limited.
+    pub ssh_worker_permits: Option<Arc<Semaphore>>,
 }
 
 /// Report from running an [SshAction].
@@ -33,6 +152,10 @@ pub struct ActionReport {
     timeout_ms: u128,
     elapsed_time_ms: u128,
     output: Output,
+    #[serde(skip_serializing_if = "Not::not")]
+    diagnostic: bool,
+    #[serde(skip_serializing_if = "Not::not")]
+    retried: bool,
 }
 
 impl ActionReport {
@@ -60,10 +183,31 @@ impl ActionReport {
         &self.output
     }
 
+    /// # Returns
+    /// Whether the action was run only to collect diagnostics.
+    /// Such actions do not affect the success of the execution.
+    pub fn diagnostic(&self) -> bool {
+        self.diagnostic
+    }
+
+    /// # Returns
+    /// Whether the action failed and was executed again.
+    /// Such attempts do not affect the success of the execution.
+    pub fn retried(&self) -> bool {
+        self.retried
+    }
+
     /// # Returns
     /// Whether the execution was successful.
+    /// Commands with an expected exit code (see [SshAction::Exec]) must exit with exactly this code.
     pub fn success(&self) -> bool {
-        self.output.success()
+        match self.action {
+            SshAction::Exec {
+                expected_exit_code: Some(expected),
+                ..
+            } => matches!(self.output, Output::Finished { exit_code, .. } if exit_code == expected),
+            _ => self.output.success(),
+        }
     }
 }
 
@@ -71,11 +215,54 @@ impl ActionReport {
 #[derive(Debug, Serialize)]
 pub struct ExecutorReport {
     image: PathBuf,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    extra_drives: Vec<Drive>,
+    #[serde(rename(serialize = "kvm_enabled"))]
+    kvm: bool,
+    #[serde(skip)]
+    spawned_at: Option<Instant>,
     #[serde(rename(serialize = "ssh_connection_ok"))]
     ssh_ok: bool,
+    #[serde(rename(serialize = "guest_ready"))]
+    ready_ok: bool,
     action_reports: Vec<ActionReport>,
     #[serde(rename(serialize = "qemu_exit_clean"))]
     exit_ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    qemu_exit: Option<QemuExit>,
+    #[serde(skip_serializing_if = "Not::not")]
+    no_actions: bool,
+    #[serde(rename(serialize = "kept_running"), skip_serializing_if = "Not::not")]
+    kept: bool,
+    #[serde(
+        rename(serialize = "reused_after_snapshot"),
+        skip_serializing_if = "Not::not"
+    )]
+    reused: bool,
+    #[serde(
+        rename(serialize = "killed_without_poweroff"),
+        skip_serializing_if = "Not::not"
+    )]
+    hard_killed: bool,
+    #[serde(
+        rename(serialize = "qemu_died_before_ssh"),
+        skip_serializing_if = "Not::not"
+    )]
+    died_before_ssh: bool,
+    #[serde(rename(serialize = "ssh_connection_attempts"))]
+    ssh_attempts: usize,
+    #[serde(
+        rename(serialize = "ssh_connection_error"),
+        skip_serializing_if = "Option::is_none"
+    )]
+    ssh_error: Option<String>,
+    #[serde(
+        skip_serializing_if = "Vec::is_empty",
+        serialize_with = "crate::serialize_bytes_lossy"
+    )]
+    console_log: Vec<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    qemu_stderr: Option<String>,
 }
 
 impl ExecutorReport {
@@ -85,12 +272,36 @@ impl ExecutorReport {
         &self.image
     }
 
+    /// # Returns
+    /// Additional drives attached to the QEMU process.
+    pub fn extra_drives(&self) -> &[Drive] {
+        &self.extra_drives[..]
+    }
+
+    /// # Returns
+    /// Whether the QEMU process was running with KVM acceleration.
+    pub fn kvm(&self) -> bool {
+        self.kvm
+    }
+
+    /// # Returns
+    /// The moment the QEMU process was spawned, if known.
+    pub fn spawned_at(&self) -> Option<Instant> {
+        self.spawned_at
+    }
+
     /// # Returns
     /// Whether the SSH connection was established successfuly.
     pub fn ssh_ok(&self) -> bool {
         self.ssh_ok
     }
 
+    /// # Returns
+    /// Whether the guest machine became ready (see [ExecutorConfig::ready_command]).
+    pub fn ready_ok(&self) -> bool {
+        self.ready_ok
+    }
+
     /// # Returns
     /// The reports from the executed actions.
     pub fn action_reports(&self) -> &[ActionReport] {
@@ -103,10 +314,83 @@ impl ExecutorReport {
         self.exit_ok
     }
 
+    /// # Returns
+    /// The way the QEMU process terminated, if known.
+    pub fn qemu_exit(&self) -> Option<QemuExit> {
+        self.qemu_exit
+    }
+
+    /// # Returns
+    /// Whether no actions were executed even though the [ExecutorConfig] required them.
+    pub fn no_actions(&self) -> bool {
+        self.no_actions
+    }
+
+    /// # Returns
+    /// Whether the QEMU process was left running for inspection (see [KeepInstance]).
+    pub fn kept(&self) -> bool {
+        self.kept
+    }
+
+    /// # Returns
+    /// Whether the QEMU process was left running to be restored from a snapshot
+    /// (see [ExecutorConfig::snapshot_stacks]).
+    pub fn reused(&self) -> bool {
+        self.reused
+    }
+
+    /// # Returns
+    /// Whether the QEMU process was killed without a poweroff,
+    /// because a graceful shutdown was disabled (see [ExecutorConfig::graceful_shutdown]).
+    pub fn hard_killed(&self) -> bool {
+        self.hard_killed
+    }
+
+    /// # Returns
+    /// Whether the QEMU process exited before an SSH connection was established.
+    pub fn died_before_ssh(&self) -> bool {
+        self.died_before_ssh
+    }
+
+    /// # Returns
+    /// The number of attempts made to open an SSH connection.
+    pub fn ssh_attempts(&self) -> usize {
+        self.ssh_attempts
+    }
+
+    /// # Returns
+    /// The error from the last failed attempt to open an SSH connection,
+    /// if the connection was not established.
+    pub fn ssh_error(&self) -> Option<&str> {
+        self.ssh_error.as_deref()
+    }
+
+    /// # Returns
+    /// The most recent output of the QEMU process (the serial console of the guest machine).
+    /// At most [crate::qemu::ConsoleLog::LIMIT] bytes are kept.
+    pub fn console_log(&self) -> &[u8] {
+        &self.console_log[..]
+    }
+
+    /// # Returns
+    /// The tail of the stderr of the QEMU process, if it did not exit cleanly and wrote anything there.
+    /// It usually explains why the hypervisor failed (e.g. an invalid device or no access to KVM).
+    pub fn qemu_stderr(&self) -> Option<&str> {
+        self.qemu_stderr.as_deref()
+    }
+
     /// # Returns
     /// Whether the execution of all actions was successful.
     pub fn success(&self) -> bool {
-        self.ssh_ok && self.action_reports.iter().all(ActionReport::success) && self.exit_ok
+        self.ssh_ok
+            && self.ready_ok
+            && !self.no_actions
+            && self
+                .action_reports
+                .iter()
+                .filter(|report| !report.diagnostic && !report.retried)
+                .all(ActionReport::success)
+            && (self.exit_ok || self.kept || self.reused || self.hard_killed)
     }
 }
 
@@ -119,11 +403,174 @@ impl ExecutorConfig {
     pub fn test() -> Self {
         Self {
             user: "root".into(),
-            password: "root".into(),
+            auth: SshAuth::Password("root".into()),
             connection_timeout: Duration::from_secs(20),
+            known_hosts: None,
+            poll_interval: Duration::from_millis(100),
+            max_connection_attempts: None,
             poweroff_timeout: Duration::from_secs(20),
             poweroff_command: "/sbin/poweroff".into(),
-            output_limit: None,
+            use_monitor_powerdown: false,
+            pre_poweroff_delay: Duration::ZERO,
+            graceful_shutdown: true,
+            ready_command: None,
+            ready_timeout: Duration::from_secs(20),
+            shell: None,
+            stdout_limit: None,
+            stderr_limit: None,
+            stream_output: false,
+            transfer_buffer_size: None,
+            require_actions: false,
+            command_policy: Default::default(),
+            keep_instance: KeepInstance::Never,
+            snapshot_stacks: false,
+            ssh_worker_permits: None,
         }
     }
 }
+
+#[cfg(test)]
+impl ActionReport {
+    /// # Returns
+    /// A report from a non-diagnostic action with the given output, for tests.
+    pub fn test(action: SshAction, output: Output) -> Self {
+        Self {
+            action,
+            timeout_ms: 0,
+            elapsed_time_ms: 0,
+            output,
+            diagnostic: false,
+            retried: false,
+        }
+    }
+}
+
+#[cfg(test)]
+impl ExecutorReport {
+    /// # Returns
+    /// A report from a QEMU process that exited cleanly after the given actions, for tests.
+    /// If `ssh_ok` is false, the actions should be empty.
+    pub fn test(ssh_ok: bool, action_reports: Vec<ActionReport>) -> Self {
+        Self {
+            image: PathBuf::new(),
+            extra_drives: vec![],
+            kvm: false,
+            spawned_at: None,
+            ssh_ok,
+            ready_ok: ssh_ok,
+            action_reports,
+            exit_ok: ssh_ok,
+            qemu_exit: None,
+            no_actions: false,
+            kept: false,
+            reused: false,
+            hard_killed: false,
+            died_before_ssh: false,
+            ssh_attempts: 1,
+            ssh_error: None,
+            console_log: vec![],
+            qemu_stderr: None,
+        }
+    }
+
+    /// # Returns
+    /// This report with the image path replaced, for tests.
+    pub fn with_image(self, image: PathBuf) -> Self {
+        Self { image, ..self }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn command_policy() {
+        let policy = CommandPolicy::default();
+        assert!(policy.check("rm -rf /").is_ok());
+
+        let policy = CommandPolicy {
+            allow: vec![],
+            deny: vec![Regex::new(r"rm\s+-rf\s+/").unwrap()],
+        };
+        assert!(policy.check("ls").is_ok());
+        assert!(policy.check("rm -rf /").is_err());
+
+        let policy = CommandPolicy {
+            allow: vec![
+                Regex::new(r"^make\b").unwrap(),
+                Regex::new(r"^\./").unwrap(),
+            ],
+            deny: vec![Regex::new(r"\bwget\b").unwrap()],
+        };
+        assert!(policy.check("make test").is_ok());
+        assert!(policy.check("./run.sh").is_ok());
+        assert!(policy.check("cat /etc/passwd").is_err());
+        assert!(policy.check("./run.sh && wget example.com").is_err());
+    }
+
+    #[test]
+    fn expected_exit_code() {
+        let report = |expected_exit_code, exit_code| ActionReport {
+            action: SshAction::Exec {
+                cmd: "false".into(),
+                expected_exit_code,
+                env: vec![],
+            },
+            timeout_ms: 0,
+            elapsed_time_ms: 0,
+            output: Output::Finished {
+                exit_code,
+                stdout: vec![],
+                stderr: vec![],
+                stdout_truncated: false,
+                stderr_truncated: false,
+                env_prefixed: false,
+            },
+            diagnostic: false,
+            retried: false,
+        };
+
+        assert!(report(None, 0).success());
+        assert!(!report(None, 1).success());
+        assert!(report(Some(1), 1).success());
+        assert!(!report(Some(1), 0).success());
+        assert!(!report(Some(1), 2).success());
+    }
+
+    #[test]
+    fn retried_reports_ignored() {
+        let report = |exit_code| {
+            ActionReport::test(
+                SshAction::Exec {
+                    cmd: "./flaky.sh".into(),
+                    expected_exit_code: None,
+                    env: vec![],
+                },
+                Output::Finished {
+                    exit_code,
+                    stdout: vec![],
+                    stderr: vec![],
+                    stdout_truncated: false,
+                    stderr_truncated: false,
+                    env_prefixed: false,
+                },
+            )
+        };
+        let retried = ActionReport {
+            retried: true,
+            ..report(1)
+        };
+
+        assert!(ExecutorReport::test(true, vec![retried, report(0)]).success());
+        assert!(!ExecutorReport::test(true, vec![report(1)]).success());
+    }
+
+    #[test]
+    fn keep_instance_from_str() {
+        assert_eq!("never".parse(), Ok(KeepInstance::Never));
+        assert_eq!("failure".parse(), Ok(KeepInstance::Failure));
+        assert_eq!("always".parse(), Ok(KeepInstance::Always));
+        assert!("sometimes".parse::<KeepInstance>().is_err());
+    }
+}
@@ -1,46 +1,136 @@
 use super::{base::BaseExecutor, ExecutorConfig, ExecutorReport};
-use crate::{qemu::QemuSpawner, ssh::SshAction};
-use std::{ffi::OsStr, io, time::Duration};
+use crate::{
+    qemu::{Drive, QemuInstance, QemuSpawner},
+    ssh::SshAction,
+};
+use std::{ffi::OsStr, io, path::Path, time::Duration};
+use tokio::sync::oneshot;
 
 /// A struct used to execute multiple stacks of [SshAction]s on a QEMU image
 /// with reboots in-between stacks.
+/// If [ExecutorConfig::snapshot_stacks] is set, a snapshot saved after the first boot
+/// is restored in-between stacks instead.
 pub struct StackExecutor<'a> {
     config: &'a ExecutorConfig,
     reports: Vec<ExecutorReport>,
     spawner: &'a QemuSpawner,
     image: &'a OsStr,
+    extra_drives: &'a [Drive],
+    scratch_dir: &'a Path,
+    previous_exit: Option<oneshot::Receiver<()>>,
+    /// A running QEMU instance with a saved snapshot, left by the previous stack.
+    suspended: Option<QemuInstance>,
+    /// Whether QEMU instances may be left running (see [BaseExecutor::forbid_keeping]).
+    may_keep: bool,
 }
 
 impl<'a> StackExecutor<'a> {
+    /// Name of the snapshot saved after the first boot.
+    const SNAPSHOT_NAME: &'static str = "qemu_test_runner_boot";
+
     /// # Arguments
     /// * config - configuration for SSH and timeouts.
     /// * spawner - used to spawn new [crate::qemu::QemuInstance]s.
     /// * image - path to the image to operate on.
+    /// * extra_drives - additional drives to attach.
+    /// * scratch_dir - host directory for files downloaded from the guest machine.
     /// # Returns
     /// A new instance of this struct.
     pub fn new(
         config: &'a ExecutorConfig,
         spawner: &'a QemuSpawner,
         image: &'a OsStr,
+        extra_drives: &'a [Drive],
+        scratch_dir: &'a Path,
     ) -> StackExecutor<'a> {
         Self {
             config,
             reports: Default::default(),
             spawner,
             image,
+            extra_drives,
+            scratch_dir,
+            previous_exit: None,
+            suspended: None,
+            may_keep: true,
         }
     }
 
+    /// Prevents all stacks opened through this struct from leaving their QEMU instances running.
+    /// See [BaseExecutor::forbid_keeping].
+    pub fn forbid_keeping(&mut self) {
+        self.may_keep = false;
+    }
+
+    /// # Returns
+    /// The host directory for files downloaded from the guest machine.
+    /// It is not shared with any other [StackExecutor].
+    pub fn scratch_dir(&self) -> &Path {
+        self.scratch_dir
+    }
+
     /// Opens a new stack. This includes spawning a new QEMU process.
+    /// Waits until the QEMU process of the previous stack has exited,
+    /// so that the image is never used by two processes at once.
+    /// If the previous stack left its QEMU process running (see [ExecutorConfig::snapshot_stacks]),
+    /// the process is restored from the snapshot and reused instead.
     /// # Returns
     /// The newly opened stack.
     pub async fn open_stack(&mut self) -> io::Result<Stack<'_>> {
-        let qemu = self.spawner.spawn(self.image.to_owned()).await?;
-        let inner = BaseExecutor::new(qemu, self.config).await;
+        self.open_stack_with_timeout(None).await
+    }
+
+    /// Opens a new stack, like [StackExecutor::open_stack].
+    /// # Arguments
+    /// * connection_timeout - timeout for the SSH connection with the QEMU process.
+    ///   If [None], [ExecutorConfig::connection_timeout] is used.
+    /// # Returns
+    /// The newly opened stack.
+    pub async fn open_stack_with_timeout(
+        &mut self,
+        connection_timeout: Option<Duration>,
+    ) -> io::Result<Stack<'_>> {
+        let connection_timeout = connection_timeout.unwrap_or(self.config.connection_timeout);
+        let (mut inner, reusable) = match self.suspended.take() {
+            Some(qemu) => {
+                qemu.loadvm(Self::SNAPSHOT_NAME).await?;
+                (
+                    BaseExecutor::with_connection_timeout(qemu, self.config, connection_timeout)
+                        .await,
+                    true,
+                )
+            }
+            None => {
+                if let Some(previous_exit) = self.previous_exit.take() {
+                    previous_exit.await.ok();
+                }
+
+                let mut qemu = self
+                    .spawner
+                    .spawn(self.image.to_owned(), self.extra_drives.to_vec())
+                    .await?;
+                self.previous_exit = Some(qemu.on_exit());
+                let inner =
+                    BaseExecutor::with_connection_timeout(qemu, self.config, connection_timeout)
+                        .await;
+                let reusable = self.config.snapshot_stacks && inner.ready();
+                if reusable {
+                    inner.savevm(Self::SNAPSHOT_NAME).await?;
+                }
+                (inner, reusable)
+            }
+        };
+        if !self.may_keep {
+            inner.forbid_keeping();
+        }
+        let suspend_to = reusable.then_some(&mut self.suspended);
 
         Ok(Stack {
             inner,
             reports: &mut self.reports,
+            scratch_dir: self.scratch_dir,
+            suspend_to,
+            last: false,
         })
     }
 
@@ -56,23 +146,89 @@ impl<'a> StackExecutor<'a> {
 pub struct Stack<'a> {
     inner: BaseExecutor<'a>,
     reports: &'a mut Vec<ExecutorReport>,
+    scratch_dir: &'a Path,
+    /// Where to leave the QEMU process for the next stack, if it should be reused.
+    suspend_to: Option<&'a mut Option<QemuInstance>>,
+    last: bool,
 }
 
 impl<'a> Stack<'a> {
+    /// Resolves relative local destinations of [SshAction::Receive] against the scratch directory.
+    fn resolve(&self, action: SshAction) -> SshAction {
+        match action {
+            SshAction::Receive { from, to } if to.is_relative() => SshAction::Receive {
+                from,
+                to: self.scratch_dir.join(to),
+            },
+            other => other,
+        }
+    }
+
+    /// Relative local destinations of [SshAction::Receive] are resolved against the scratch directory
+    /// (see [StackExecutor::scratch_dir]).
     /// # Arguments
     /// * action - an [SshAction] to run through the wrapped [BaseExecutor].
     /// * timeout - a timeout for this action.
     /// # Returns
     /// Whether the execution was successful.
     pub async fn run(&mut self, action: SshAction, timeout: Duration) -> io::Result<bool> {
+        let action = self.resolve(action);
         self.inner.run(action, timeout).await
     }
 
+    /// Like [Stack::run], but the action is executed again if it fails.
+    /// See [BaseExecutor::run_with_retries].
+    /// # Arguments
+    /// * action - an [SshAction] to run through the wrapped [BaseExecutor].
+    /// * timeout - a timeout for a single attempt.
+    /// * retries - maximal number of additional attempts.
+    /// # Returns
+    /// Whether the last attempt was successful.
+    pub async fn run_with_retries(
+        &mut self,
+        action: SshAction,
+        timeout: Duration,
+        retries: usize,
+    ) -> io::Result<bool> {
+        let action = self.resolve(action);
+        self.inner.run_with_retries(action, timeout, retries).await
+    }
+
+    /// # Arguments
+    /// * action - a diagnostic [SshAction] to run through the wrapped [BaseExecutor].
+    /// * timeout - a timeout for this action.
+    /// # Returns
+    /// Whether the execution was successful.
+    pub async fn run_diagnostic(
+        &mut self,
+        action: SshAction,
+        timeout: Duration,
+    ) -> io::Result<bool> {
+        let action = self.resolve(action);
+        self.inner.run_diagnostic(action, timeout).await
+    }
+
+    /// Marks this stack as the last one opened on its image.
+    /// See [BaseExecutor::mark_last].
+    pub fn mark_last(&mut self) {
+        self.last = true;
+        self.inner.mark_last();
+    }
+
     /// Finishes the wrapped [BaseExecutor].
+    /// If snapshots are used (see [ExecutorConfig::snapshot_stacks]) and this stack
+    /// is successful and not the last one, the QEMU process is left running for the next stack.
     /// # Returns
     /// Whether all [SshAction]s performed with the wrapped [BaseExecutor] were successful.
     pub async fn finish(self) -> io::Result<bool> {
-        let report = self.inner.finish().await?;
+        let report = match self.suspend_to {
+            Some(suspended) if !self.last && self.inner.success() => {
+                let (report, qemu) = self.inner.suspend();
+                *suspended = Some(qemu);
+                report
+            }
+            _ => self.inner.finish().await?,
+        };
         let success = report.success();
         self.reports.push(report);
 
@@ -81,21 +237,52 @@ impl<'a> Stack<'a> {
 
     /// Runs the given [SshAction]s until one of them is not successful
     /// and finishes the wrapped [BaseExecutor].
+    /// If one of the [SshAction]s fails, the diagnostic [SshAction]s are run before finishing.
     /// # Arguments
-    /// iter - an iterator of [SshAction]s to run and their timeouts.
+    /// iter - an iterator of [SshAction]s to run, their timeouts and numbers of allowed retries
+    /// (see [Stack::run_with_retries]).
+    /// on_failure - an iterator of diagnostic [SshAction]s and their timeouts.
+    /// continue_on_failure - whether the remaining [SshAction]s should be run after one of them fails.
+    /// If so, the diagnostic [SshAction]s are run after all of them.
     /// # Returns
-    /// Whether all [SshAction]s performed with the wrapped [BaseExecutor] were successful.
-    pub async fn run_until_failure<I>(mut self, iter: I) -> io::Result<bool>
+    /// [Ok] if all [SshAction]s performed with the wrapped [BaseExecutor] were successful.
+    /// Otherwise, the index of the first [SshAction] from `iter` that failed.
+    /// If all of them succeeded but the wrapped [BaseExecutor] did not finish successfully
+    /// (e.g. the guest system did not shut down cleanly), the number of [SshAction]s in `iter`.
+    pub async fn run_until_failure<I, J>(
+        mut self,
+        iter: I,
+        on_failure: J,
+        continue_on_failure: bool,
+    ) -> io::Result<Result<(), usize>>
     where
-        I: Iterator<Item = (SshAction, Duration)>,
+        I: Iterator<Item = (SshAction, Duration, usize)>,
+        J: Iterator<Item = (SshAction, Duration)>,
     {
-        for (action, timeout) in iter {
-            if !self.run(action, timeout).await? {
-                break;
+        let mut failed = None;
+        let mut count = 0;
+        for (action, timeout, retries) in iter {
+            if !self.run_with_retries(action, timeout, retries).await? {
+                failed.get_or_insert(count);
+                if !continue_on_failure {
+                    break;
+                }
+            }
+            count += 1;
+        }
+
+        if failed.is_some() {
+            for (action, timeout) in on_failure {
+                self.run_diagnostic(action, timeout).await?;
             }
         }
 
-        self.finish().await
+        let success = self.finish().await?;
+        match failed {
+            Some(idx) => Ok(Err(idx)),
+            None if success => Ok(Ok(())),
+            None => Ok(Err(count)),
+        }
     }
 }
 
@@ -106,6 +293,61 @@ mod test {
     use super::*;
     use crate::{qemu::Image, test_util::Env};
 
+    #[ignore]
+    #[tokio::test]
+    async fn changes_after_unclean_reboot() {
+        let env = Env::read();
+
+        let image = env.base_path().join("image.qcow2");
+
+        env.builder()
+            .create(env.base_image(), Image::Qcow2(image.as_path()))
+            .await
+            .expect("failed to build the image");
+        let spawner = env.spawner(2);
+
+        let config = ExecutorConfig::test();
+
+        time::timeout(Duration::from_secs(120), async {
+            let mut executor =
+                StackExecutor::new(&config, &spawner, image.as_os_str(), &[], env.base_path());
+
+            let mut stack = executor.open_stack().await.expect("failed to open_stack");
+            let success = stack
+                .run(
+                    SshAction::Exec {
+                        cmd: "echo content > file1 && sync".into(),
+                        expected_exit_code: None,
+                        env: vec![],
+                    },
+                    Duration::from_secs(5),
+                )
+                .await
+                .unwrap();
+            assert!(success);
+            // The QEMU process is killed without a poweroff.
+            drop(stack);
+
+            let mut stack = executor.open_stack().await.expect("failed to open_stack");
+            let success = stack
+                .run(
+                    SshAction::Exec {
+                        cmd: "grep content file1".into(),
+                        expected_exit_code: None,
+                        env: vec![],
+                    },
+                    Duration::from_secs(1),
+                )
+                .await
+                .unwrap();
+            assert!(success);
+            let success = stack.finish().await.unwrap();
+            assert!(success);
+        })
+        .await
+        .expect("timeout");
+    }
+
     #[ignore]
     #[tokio::test]
     async fn persistent_changes() {
@@ -119,23 +361,19 @@ mod test {
             .expect("failed to build the image");
         let spawner = env.spawner(1);
 
-        let config = ExecutorConfig {
-            user: "root".into(),
-            password: "root".into(),
-            connection_timeout: Duration::from_secs(20),
-            poweroff_timeout: Duration::from_secs(20),
-            poweroff_command: "/sbin/poweroff".into(),
-            output_limit: None,
-        };
+        let config = ExecutorConfig::test();
 
         let reports = time::timeout(Duration::from_secs(180), async {
-            let mut executor = StackExecutor::new(&config, &spawner, image.as_os_str());
+            let mut executor =
+                StackExecutor::new(&config, &spawner, image.as_os_str(), &[], env.base_path());
 
             let mut stack = executor.open_stack().await.expect("failed to open_stack");
             let success = stack
                 .run(
                     SshAction::Exec {
                         cmd: "touch file1".into(),
+                        expected_exit_code: None,
+                        env: vec![],
                     },
                     Duration::from_secs(1),
                 )
@@ -150,6 +388,8 @@ mod test {
                 .run(
                     SshAction::Exec {
                         cmd: "cat file1".into(),
+                        expected_exit_code: None,
+                        env: vec![],
                     },
                     Duration::from_secs(1),
                 )
@@ -160,6 +400,8 @@ mod test {
                 .run(
                     SshAction::Exec {
                         cmd: "rm file1".into(),
+                        expected_exit_code: None,
+                        env: vec![],
                     },
                     Duration::from_secs(1),
                 )
@@ -170,6 +412,8 @@ mod test {
                 .run(
                     SshAction::Exec {
                         cmd: "touch file2".into(),
+                        expected_exit_code: None,
+                        env: vec![],
                     },
                     Duration::from_secs(1),
                 )
@@ -184,6 +428,8 @@ mod test {
                 .run(
                     SshAction::Exec {
                         cmd: "cat file2".into(),
+                        expected_exit_code: None,
+                        env: vec![],
                     },
                     Duration::from_secs(1),
                 )
@@ -198,6 +444,8 @@ mod test {
                 .run(
                     SshAction::Exec {
                         cmd: "cat file3".into(),
+                        expected_exit_code: None,
+                        env: vec![],
                     },
                     Duration::from_secs(1),
                 )
@@ -218,4 +466,272 @@ mod test {
         assert!(reports[2].success());
         assert!(!reports[3].success());
     }
+
+    #[ignore]
+    #[tokio::test]
+    async fn snapshot_stacks() {
+        let env = Env::read();
+
+        let image = env.base_path().join("image.qcow2");
+
+        env.builder()
+            .create(env.base_image(), Image::Qcow2(image.as_path()))
+            .await
+            .expect("failed to build the image");
+        let spawner = env.spawner(1);
+
+        let config = ExecutorConfig {
+            snapshot_stacks: true,
+            ..ExecutorConfig::test()
+        };
+
+        let reports = time::timeout(Duration::from_secs(120), async {
+            let mut executor =
+                StackExecutor::new(&config, &spawner, image.as_os_str(), &[], env.base_path());
+
+            let mut stack = executor.open_stack().await.expect("failed to open_stack");
+            let success = stack
+                .run(
+                    SshAction::Exec {
+                        cmd: "touch file1".into(),
+                        expected_exit_code: None,
+                        env: vec![],
+                    },
+                    Duration::from_secs(1),
+                )
+                .await
+                .unwrap();
+            assert!(success);
+            let success = stack.finish().await.unwrap();
+            assert!(success);
+
+            let mut stack = executor.open_stack().await.expect("failed to open_stack");
+            stack.mark_last();
+            let success = stack
+                .run(
+                    SshAction::Exec {
+                        cmd: "test ! -e file1".into(),
+                        expected_exit_code: None,
+                        env: vec![],
+                    },
+                    Duration::from_secs(1),
+                )
+                .await
+                .unwrap();
+            assert!(success);
+            let success = stack.finish().await.unwrap();
+            assert!(success);
+
+            executor.finish()
+        })
+        .await
+        .expect("timeout");
+
+        assert_eq!(reports.len(), 2);
+        assert!(reports[0].reused());
+        assert!(reports[0].success());
+        assert!(!reports[1].reused());
+        assert!(reports[1].exit_ok());
+    }
+
+    #[ignore]
+    #[tokio::test]
+    async fn diagnostics_on_failure() {
+        let env = Env::read();
+
+        let image = env.base_path().join("image.qcow2");
+
+        env.builder()
+            .create(env.base_image(), Image::Qcow2(image.as_path()))
+            .await
+            .expect("failed to build the image");
+        let spawner = env.spawner(1);
+        let config = ExecutorConfig::test();
+
+        let reports = time::timeout(Duration::from_secs(60), async {
+            let mut executor =
+                StackExecutor::new(&config, &spawner, image.as_os_str(), &[], env.base_path());
+
+            let actions = vec![
+                (
+                    SshAction::Exec {
+                        cmd: "false".into(),
+                        expected_exit_code: None,
+                        env: vec![],
+                    },
+                    Duration::from_secs(1),
+                    0,
+                ),
+                (
+                    SshAction::Exec {
+                        cmd: "ls".into(),
+                        expected_exit_code: None,
+                        env: vec![],
+                    },
+                    Duration::from_secs(1),
+                    0,
+                ),
+            ];
+            let diagnostics = vec![(
+                SshAction::Exec {
+                    cmd: "pwd".into(),
+                    expected_exit_code: None,
+                    env: vec![],
+                },
+                Duration::from_secs(1),
+            )];
+
+            let res = executor
+                .open_stack()
+                .await
+                .expect("failed to open_stack")
+                .run_until_failure(actions.into_iter(), diagnostics.into_iter(), false)
+                .await
+                .unwrap();
+            assert_eq!(res, Err(0));
+
+            executor.finish()
+        })
+        .await
+        .expect("timeout");
+
+        assert_eq!(reports.len(), 1);
+        let action_reports = reports[0].action_reports();
+        assert_eq!(action_reports.len(), 2);
+        assert!(!action_reports[0].success());
+        assert!(!action_reports[0].diagnostic());
+        assert!(action_reports[1].success());
+        assert!(action_reports[1].diagnostic());
+        assert!(!reports[0].success());
+    }
+
+    #[ignore]
+    #[tokio::test]
+    async fn step_retries() {
+        let env = Env::read();
+
+        let image = env.base_path().join("image.qcow2");
+
+        env.builder()
+            .create(env.base_image(), Image::Qcow2(image.as_path()))
+            .await
+            .expect("failed to build the image");
+        let spawner = env.spawner(1);
+        let config = ExecutorConfig::test();
+
+        let reports = time::timeout(Duration::from_secs(60), async {
+            let mut executor =
+                StackExecutor::new(&config, &spawner, image.as_os_str(), &[], env.base_path());
+
+            // Fails only on the first attempt.
+            let actions = vec![(
+                SshAction::Exec {
+                    cmd: "test -f marker || { touch marker; false; }".into(),
+                    expected_exit_code: None,
+                    env: vec![],
+                },
+                Duration::from_secs(1),
+                2,
+            )];
+
+            let res = executor
+                .open_stack()
+                .await
+                .expect("failed to open_stack")
+                .run_until_failure(actions.into_iter(), std::iter::empty(), false)
+                .await
+                .unwrap();
+            assert_eq!(res, Ok(()));
+
+            executor.finish()
+        })
+        .await
+        .expect("timeout");
+
+        assert_eq!(reports.len(), 1);
+        let action_reports = reports[0].action_reports();
+        assert_eq!(action_reports.len(), 2);
+        assert!(!action_reports[0].success());
+        assert!(action_reports[0].retried());
+        assert!(action_reports[1].success());
+        assert!(!action_reports[1].retried());
+        assert!(reports[0].success());
+    }
+
+    #[ignore]
+    #[tokio::test]
+    async fn continue_on_failure() {
+        let env = Env::read();
+
+        let image = env.base_path().join("image.qcow2");
+
+        env.builder()
+            .create(env.base_image(), Image::Qcow2(image.as_path()))
+            .await
+            .expect("failed to build the image");
+        let spawner = env.spawner(1);
+        let config = ExecutorConfig::test();
+
+        let reports = time::timeout(Duration::from_secs(60), async {
+            let mut executor =
+                StackExecutor::new(&config, &spawner, image.as_os_str(), &[], env.base_path());
+
+            let action = |cmd: &str| {
+                (
+                    SshAction::Exec {
+                        cmd: cmd.into(),
+                        expected_exit_code: None,
+                        env: vec![],
+                    },
+                    Duration::from_secs(1),
+                    0,
+                )
+            };
+            let actions = vec![
+                action("true"),
+                action("false"),
+                action("ls"),
+                action("false"),
+            ];
+            let diagnostics = vec![(
+                SshAction::Exec {
+                    cmd: "pwd".into(),
+                    expected_exit_code: None,
+                    env: vec![],
+                },
+                Duration::from_secs(1),
+            )];
+
+            let res = executor
+                .open_stack()
+                .await
+                .expect("failed to open_stack")
+                .run_until_failure(actions.into_iter(), diagnostics.into_iter(), true)
+                .await
+                .unwrap();
+            assert_eq!(res, Err(1));
+
+            executor.finish()
+        })
+        .await
+        .expect("timeout");
+
+        assert_eq!(reports.len(), 1);
+        let results = reports[0]
+            .action_reports()
+            .iter()
+            .map(|report| (report.success(), report.diagnostic()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            results,
+            [
+                (true, false),
+                (false, false),
+                (true, false),
+                (false, false),
+                (true, true)
+            ]
+        );
+        assert!(!reports[0].success());
+    }
 }
@@ -2,13 +2,18 @@ use serde::{Serialize, Serializer};
 use std::{
     fmt::{self, Debug, Formatter},
     io::{self, ErrorKind},
+    ops::Not,
     path::Path,
 };
 use tokio::fs;
 
+pub mod batch;
 pub mod config;
+pub mod environment;
 pub mod executor;
 pub mod maybe_tmp;
+#[cfg(feature = "metrics")]
+pub mod metrics;
 pub mod patch_validator;
 pub mod qemu;
 pub mod ssh;
@@ -49,15 +54,55 @@ pub enum Output {
         )]
         /// Stderr of the process.
         stderr: Vec<u8>,
+        /// Whether the stdout was truncated to the configured limit.
+        #[serde(skip_serializing_if = "Not::not")]
+        stdout_truncated: bool,
+        /// Whether the stderr was truncated to the configured limit.
+        #[serde(skip_serializing_if = "Not::not")]
+        stderr_truncated: bool,
+        /// Whether the environment variables were exported at the beginning of the command,
+        /// because the SSH server did not accept them.
+        #[serde(skip_serializing_if = "Not::not")]
+        env_prefixed: bool,
     },
     /// An SSH error occurred when executing the action.
     Error {
         #[serde(serialize_with = "serialize_io_error")]
         error: io::Error,
     },
+    /// The action did not finish before its timeout elapsed.
+    Timeout {
+        /// Stdout collected before the timeout, if the output was streamed
+        /// (see [ssh::SshOptions::stream_output]).
+        #[serde(
+            skip_serializing_if = "Vec::is_empty",
+            serialize_with = "serialize_bytes_lossy"
+        )]
+        stdout: Vec<u8>,
+        /// Stderr collected before the timeout, if the output was streamed.
+        #[serde(
+            skip_serializing_if = "Vec::is_empty",
+            serialize_with = "serialize_bytes_lossy"
+        )]
+        stderr: Vec<u8>,
+    },
 }
 
 impl Output {
+    /// # Returns
+    /// A successful output with no stdout and stderr,
+    /// used for actions that do not execute a command.
+    pub(crate) fn empty_success() -> Self {
+        Self::Finished {
+            exit_code: 0,
+            stdout: Default::default(),
+            stderr: Default::default(),
+            stdout_truncated: false,
+            stderr_truncated: false,
+            env_prefixed: false,
+        }
+    }
+
     /// # Returns
     /// Whether the execution was successful.
     pub fn success(&self) -> bool {
@@ -65,20 +110,20 @@ impl Output {
     }
 
     /// # Returns
-    /// The stdout collected from the action, if exists.
+    /// The stdout collected from the finished action, if exists.
     pub fn stdout(&self) -> Option<&[u8]> {
         match self {
             Self::Finished { stdout, .. } => Some(&stdout[..]),
-            Self::Error { .. } => None,
+            Self::Error { .. } | Self::Timeout { .. } => None,
         }
     }
 
     /// # Returns
-    /// The stderr collected from the action, if exists.
+    /// The stderr collected from the finished action, if exists.
     pub fn stderr(&self) -> Option<&[u8]> {
         match self {
             Self::Finished { stderr, .. } => Some(&stderr[..]),
-            Self::Error { .. } => None,
+            Self::Error { .. } | Self::Timeout { .. } => None,
         }
     }
 }
@@ -91,11 +136,21 @@ impl Debug for Output {
                 exit_code,
                 stdout,
                 stderr,
+                stdout_truncated,
+                stderr_truncated,
+                env_prefixed,
             } => s
                 .field("exit_code", exit_code)
                 .field("stdout", &String::from_utf8_lossy(stdout))
-                .field("stderr", &String::from_utf8_lossy(stderr)),
+                .field("stderr", &String::from_utf8_lossy(stderr))
+                .field("stdout_truncated", stdout_truncated)
+                .field("stderr_truncated", stderr_truncated)
+                .field("env_prefixed", env_prefixed),
             Self::Error { error } => s.field("error", error),
+            Self::Timeout { stdout, stderr } => s
+                .field("timeout", &true)
+                .field("stdout", &String::from_utf8_lossy(stdout))
+                .field("stderr", &String::from_utf8_lossy(stderr)),
         };
 
         s.finish()
@@ -109,7 +164,7 @@ where
     serializer.collect_str(error)
 }
 
-fn serialize_bytes_lossy<S>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error>
+pub(crate) fn serialize_bytes_lossy<S>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
 {
@@ -123,9 +178,30 @@ mod test_util {
     use std::{
         env,
         ffi::OsString,
+        fs::Permissions,
+        os::unix::fs::PermissionsExt,
         path::{Path, PathBuf},
     };
     use tempfile::TempDir;
+    use tokio::fs;
+
+    /// Writes an executable shell script, used in place of a real command (e.g. QEMU) in tests.
+    /// # Arguments
+    /// * dir - directory to place the script in.
+    /// * name - name of the script.
+    /// * script - body of the script, executed with `/bin/sh`.
+    /// # Returns
+    /// Path to the script.
+    pub async fn fake_command(dir: &Path, name: &str, script: &str) -> PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, format!("#!/bin/sh\n{}", script))
+            .await
+            .expect("failed to write the script");
+        fs::set_permissions(&path, Permissions::from_mode(0o755))
+            .await
+            .expect("failed to make the script executable");
+        path
+    }
 
     pub struct Env {
         base_image: PathBuf,
@@ -185,9 +261,8 @@ mod test_util {
                 concurrency,
                 QemuConfig {
                     cmd: self.run_cmd.clone(),
-                    memory: 1024,
                     enable_kvm: self.enable_kvm,
-                    irqchip_off: true,
+                    ..QemuConfig::test()
                 },
             )
         }
@@ -197,3 +272,32 @@ mod test_util {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn output_timeout() {
+        let output = Output::Timeout {
+            stdout: vec![],
+            stderr: vec![],
+        };
+        assert!(!output.success());
+        assert!(output.stdout().is_none());
+        assert!(output.stderr().is_none());
+        assert_eq!(
+            serde_json::to_string(&output).unwrap(),
+            r#"{"result":"timeout"}"#
+        );
+
+        let output = Output::Timeout {
+            stdout: b"partial".to_vec(),
+            stderr: vec![],
+        };
+        assert_eq!(
+            serde_json::to_string(&output).unwrap(),
+            r#"{"result":"timeout","stdout":"partial"}"#
+        );
+    }
+}
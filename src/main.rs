@@ -1,92 +1,328 @@
 use clap::Parser;
-use futures::stream::StreamExt;
+use futures::{future, stream::StreamExt};
 use qemu_test_runner::{
+    batch::{self, Batch, BatchOptions},
+    environment::Environment,
+    executor::{base::BaseExecutor, KeepInstance},
     maybe_tmp::MaybeTmp,
-    patch_validator::{Patch, PatchValidator},
+    patch_validator::{Patch, PatchValidator, ValidationError},
     prepare_dir,
-    qemu::{ImageBuilder, QemuConfig, QemuSpawner},
+    qemu::{Image, ImageBuilder, ImageFormat, QemuConfig, QemuSpawner},
+    ssh::SshAction,
     stats::Stats,
-    tester::{PatchProcessor, RunConfig, RunReport},
+    tester::{ArtifactsLayout, KeepArtifacts, PatchProcessor, ResultsFormat, RunConfig, RunReport},
 };
+use regex::Regex;
 use std::{
+    cell::Cell,
     ffi::OsString,
-    io::{Error, ErrorKind, Result},
-    path::PathBuf,
-    process::ExitCode,
+    future::Future,
+    io::IsTerminal,
+    path::{Path, PathBuf},
+    process::{ExitCode, Stdio},
+    sync::Arc,
+    time::Duration,
 };
 use tokio::{
     fs,
-    io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader, Stdout},
-    sync::Mutex,
+    io::{self, AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader, Stdout},
+    process, signal,
+    sync::{Mutex, Semaphore},
 };
-use tokio_stream::wrappers::LinesStream;
+use tokio_stream::wrappers::SplitStream;
+
+#[cfg(feature = "metrics")]
+use qemu_test_runner::metrics;
+#[cfg(feature = "metrics")]
+use std::net::SocketAddr;
 
 #[derive(Parser, Debug)]
 struct Args {
-    #[clap(long)]
+    #[clap(long, required_unless_present = "print-tools")]
     /// Test suite configuration file.
-    suite: PathBuf,
+    suite: Option<PathBuf>,
     #[clap(long, default_value_t = 1)]
     /// Maximal count of concurrent QEMU processes running.
     concurrency: usize,
+    #[clap(long)]
+    /// Maximal count of solutions processed at once.
+    /// Solutions in flight compete for the QEMU processes limited by --concurrency,
+    /// and a single solution may run many tests at once. Artifacts of all solutions in flight
+    /// are kept on disk at the same time. If omitted, all read solutions are processed at once.
+    max_patches_in_flight: Option<usize>,
+    #[clap(long)]
+    /// Maximal count of SSH workers running at once. Every worker occupies a blocking thread.
+    /// A QEMU process uses one worker at a time, but a worker executing a command that timed out
+    /// is released only when the command finishes or the process exits. Values below --concurrency
+    /// make QEMU processes wait for a worker, which counts towards the SSH timeout. If omitted, not limited.
+    max_ssh_workers: Option<usize>,
     #[clap(long, default_value = "qemu-system-x86_64")]
     /// Command used to spawn new QEMU processes.
     qemu_system: OsString,
     #[clap(long, default_value_t = 1024)]
     /// Memory limit for a QEMU process (megabytes).
-    qemu_memory: u16,
-    #[clap(long, default_value_t = true)]
+    qemu_memory: u32,
+    #[clap(long)]
+    /// Number of virtual CPUs for a QEMU process.
+    /// If omitted, QEMU's default is used.
+    qemu_smp: Option<u8>,
+    #[clap(long)]
+    /// Limit for the total memory of concurrent QEMU processes (megabytes).
+    /// New processes wait until enough memory is released. If omitted, only the concurrency is limited.
+    max_total_memory: Option<u32>,
+    #[clap(long, default_value_t = true, action = clap::ArgAction::Set, min_values = 0, default_missing_value = "true")]
     /// Whether to enable KVM for QEMU processes.
     qemu_enable_kvm: bool,
-    #[clap(long, default_value_t = true)]
+    #[clap(long, default_value_t = true, action = clap::ArgAction::Set, min_values = 0, default_missing_value = "true")]
     /// Whether to turn off the irqchip for QEMU processes.
+    /// Only applies to x86 machines, set to false for other targets.
     qemu_irqchip_off: bool,
+    #[clap(long)]
+    /// Machine type for QEMU processes (e.g. q35, virt).
+    /// If omitted, QEMU's default is used.
+    qemu_machine: Option<String>,
+    #[clap(long)]
+    /// CPU model for QEMU processes (e.g. host, cortex-a57).
+    /// If omitted, QEMU's default is used.
+    qemu_cpu: Option<String>,
+    #[clap(long)]
+    /// Interface of the drive for QEMU processes (e.g. virtio, ide).
+    /// If omitted, QEMU's default is used.
+    qemu_drive_interface: Option<String>,
+    #[clap(long)]
+    /// Cache mode of the drive for QEMU processes (e.g. writeback, unsafe).
+    /// If omitted, QEMU's default is used.
+    qemu_drive_cache: Option<String>,
+    #[clap(long = "qemu-arg", allow_hyphen_values = true)]
+    /// Additional argument for QEMU processes, appended after the generated ones.
+    /// Can be given multiple times (e.g. --qemu-arg=-cpu --qemu-arg=host).
+    /// The monitors (-monitor, -qmp) and the network (with the SSH forward) are managed by this program and cannot be changed.
+    qemu_args: Vec<OsString>,
+    #[clap(long, default_value_t = 10000)]
+    /// Timeout for QEMU processes to create their monitor sockets (milliseconds).
+    /// A QEMU process that fails to do so is treated as failed to start.
+    qemu_monitor_timeout_ms: u64,
     #[clap(long, default_value = "qemu-img")]
     /// Command used to create new qcow2 images.
     qemu_img: OsString,
-    #[clap(long)]
-    /// Base QEMU image (raw).
-    base_image: PathBuf,
+    #[clap(long, required_unless_present = "print-tools")]
+    /// Base QEMU image.
+    base_image: Option<PathBuf>,
+    #[clap(long, default_value = "raw")]
+    /// Format of the base QEMU image (raw, qcow2).
+    base_image_format: ImageFormat,
     #[clap(long)]
     /// Output directory for artifacts (qcow2 images).
     /// If omitted, artifacts will be saved in a temporary directory.
     artifacts: Option<PathBuf>,
     #[clap(long)]
+    /// Compress qcow2 artifacts once they are no longer used.
+    /// Saves disk space at the cost of CPU time. Ignored if the artifacts directory is omitted.
+    compress_artifacts: bool,
+    #[clap(long)]
+    /// Run every test on its own standalone copy of the build image (created with `qemu-img convert`),
+    /// so that tests cannot interfere through the shared backing image. Uses more disk space and time.
+    isolate_tests: bool,
+    #[clap(long, default_value = "flat")]
+    /// Naming scheme of the artifacts directories of solutions (flat, sharded, filename).
+    /// Sharded places every directory under a parent named after the first two characters of the id.
+    artifacts_layout: ArtifactsLayout,
+    #[clap(long, default_value = "all")]
+    /// Which artifacts directories of solutions to keep after processing (all, failed, none).
+    /// Failed keeps only the solutions that failed to build or failed any test.
+    /// Artifacts used by QEMU processes left running are always kept.
+    keep_artifacts: KeepArtifacts,
+    #[clap(long, default_value = "legacy")]
+    /// Format of the result lines printed for processed solutions (legacy, csv, jsonl).
+    /// Legacy is `<patch>;<OK or failed tests>`, jsonl is a JSON object per solution.
+    results_format: ResultsFormat,
+    #[clap(long = "test")]
+    /// Name of a test to run. Can be given multiple times.
+    /// If omitted, all tests from the suite are run.
+    tests: Vec<String>,
+    #[clap(long)]
+    /// Regular expression for filenames of solutions, matched against the whole filename.
+    /// The file stem is used as the student's ID.
+    /// If omitted, filenames must be of format ab123456.patch.
+    patch_pattern: Option<Regex>,
+    #[clap(long)]
+    /// Maximal size of a solution file (bytes).
+    /// Larger solutions are rejected before they reach the VM.
+    patch_max_bytes: Option<u64>,
+    #[clap(long)]
+    /// Reject solution files that are not valid UTF-8.
+    patch_require_utf8: bool,
+    #[clap(long)]
+    /// Reject solution files with contents identical to an earlier solution (compared by SHA-256),
+    /// even if their IDs differ.
+    reject_duplicate_content: bool,
+    #[clap(long)]
+    /// File to read solutions from, one per line.
+    /// If omitted, solutions are read from the standard input.
+    input: Option<PathBuf>,
+    #[clap(long)]
     /// Output directory for detailed run reports.
     /// If omitted, reports will not be generated.
     reports: Option<PathBuf>,
+    #[clap(long)]
+    /// Directory with reports from a previous run.
+    /// For every solution, only the tests that failed in its previous report are run.
+    /// Solutions without a previous report or with a failed build are tested with the whole suite.
+    rerun_failed: Option<PathBuf>,
+    #[clap(long)]
+    /// Skip solutions that already have a valid report in the reports directory,
+    /// e.g. when repeating a run that crashed. Requires the reports directory.
+    resume: bool,
+    #[clap(long, default_value = "never")]
+    /// When to leave QEMU processes running for inspection (never, failure, always).
+    /// Kept processes are not powered off and run until this program exits.
+    /// Processes of the build and bootstrap scenarios are never kept, as their images back other scenarios.
+    keep_instance_on: KeepInstance,
+    #[cfg(feature = "metrics")]
+    #[clap(long)]
+    /// Address to serve metrics on, in the Prometheus text format (under /metrics).
+    /// If omitted, metrics are not served.
+    metrics_addr: Option<SocketAddr>,
+    #[clap(long)]
+    /// Check the environment instead of processing solutions.
+    /// Boots the base image, connects over SSH, runs a command, transfers a file and powers off,
+    /// printing the result of every stage.
+    self_test: bool,
+    #[clap(long)]
+    /// Print the versions of the QEMU commands (--qemu-system and --qemu-img) and other information
+    /// about the environment as JSON, instead of processing solutions.
+    /// Exits with a failure if any of the commands cannot be executed.
+    print_tools: bool,
+    #[clap(long)]
+    /// Validate the suite file and the solutions from the input without booting QEMU.
+    /// Prints the validation result of every solution and exits with a failure
+    /// if the suite is invalid (e.g. a transferred file does not exist) or any solution is rejected.
+    dry_run: bool,
+    #[clap(long)]
+    /// File to save the statistics of the run to (JSON).
+    /// Unlike the summary in the reports directory, it can be placed anywhere.
+    stats_out: Option<PathBuf>,
+    #[clap(long)]
+    /// Host command executed (with `sh -c`) after all solutions are processed.
+    /// It receives the path to the summary (if the reports directory was specified)
+    /// in QEMU_TEST_RUNNER_SUMMARY and the overall result (1 or 0) in QEMU_TEST_RUNNER_SUCCESS.
+    post_run: Option<String>,
+    #[clap(long)]
+    /// Treat a failure of the post-run command as a failure of the whole run.
+    post_run_required: bool,
+}
+
+fn make_patch_validator(args: &Args) -> PatchValidator {
+    match args.patch_pattern.clone() {
+        Some(pattern) => PatchValidator::with_pattern(pattern),
+        None => PatchValidator::default(),
+    }
+    .with_max_bytes(args.patch_max_bytes)
+    .with_require_utf8(args.patch_require_utf8)
+    .with_content_dedup(args.reject_duplicate_content)
 }
 
 async fn make_patch_processor(args: Args, artifacts_root: PathBuf) -> PatchProcessor {
     if args.concurrency == 0 {
         panic!("concurrency level cannot be set below 1");
     }
+    if args.max_patches_in_flight == Some(0) {
+        panic!("number of solutions in flight cannot be set below 1");
+    }
+    if args.max_ssh_workers == Some(0) {
+        panic!("number of SSH workers cannot be set below 1");
+    }
+    if args.qemu_smp == Some(0) {
+        panic!("number of virtual CPUs cannot be set below 1");
+    }
+    if let Some(limit) = args.max_total_memory {
+        if args.qemu_memory > limit {
+            panic!("total memory limit cannot be lower than the memory of a single QEMU process");
+        }
+    }
 
-    let run_config = RunConfig::from_file(&args.suite)
+    let suite = args.suite.as_ref().expect("the suite file is required");
+    let mut run_config = RunConfig::from_file(suite)
         .await
         .expect("failed to process the suite file");
+    run_config.execution.keep_instance = args.keep_instance_on;
+    run_config.execution.ssh_worker_permits = args
+        .max_ssh_workers
+        .map(|limit| Arc::new(Semaphore::new(limit)));
+
+    let test_filter = if args.tests.is_empty() {
+        None
+    } else {
+        if let Some(test) = args
+            .tests
+            .iter()
+            .find(|test| !run_config.tests.contains_key(*test))
+        {
+            panic!("test {} does not exist in the suite", test);
+        }
+        log::info!("Only the following tests will be run: {:?}.", args.tests);
+
+        Some(args.tests.into_iter().collect())
+    };
 
     let qemu_config = QemuConfig {
         cmd: args.qemu_system,
         memory: args.qemu_memory,
+        smp: args.qemu_smp,
         enable_kvm: args.qemu_enable_kvm,
         irqchip_off: args.qemu_irqchip_off,
+        machine: args.qemu_machine,
+        cpu: args.qemu_cpu,
+        drive_interface: args.qemu_drive_interface,
+        drive_cache: args.qemu_drive_cache,
+        extra_args: args.qemu_args,
+        monitor_timeout: Duration::from_millis(args.qemu_monitor_timeout_ms),
+        poll_interval: run_config.execution.poll_interval,
     };
+    if let Some(arg) = qemu_config.managed_extra_arg() {
+        panic!(
+            "QEMU argument {} cannot be overridden, it is managed by this program",
+            arg.to_string_lossy()
+        );
+    }
+    let mut spawner = QemuSpawner::new(args.concurrency, qemu_config);
+    if let Some(limit) = args.max_total_memory {
+        spawner = spawner.with_memory_limit(limit);
+    }
 
     PatchProcessor {
-        spawner: QemuSpawner::new(args.concurrency, qemu_config),
+        spawner,
         builder: ImageBuilder { cmd: args.qemu_img },
-        base_image: fs::canonicalize(args.base_image)
+        base_image: fs::canonicalize(args.base_image.expect("the base image is required"))
             .await
             .expect("failed to canonicalize the base image path"),
+        base_image_format: args.base_image_format,
         run_config,
         artifacts_root,
+        test_filter,
+        bootstrapped_image: None,
+        compress_artifacts: args.compress_artifacts && args.artifacts.is_some(),
+        artifacts_layout: args.artifacts_layout,
+        keep_artifacts: args.keep_artifacts,
+        isolate_tests: args.isolate_tests,
     }
 }
 
 fn print_stats(stats: &Stats) {
+    if stats.interrupted {
+        log::warn!("The run was interrupted, not all solutions were processed.");
+    }
     log::info!("{} solution(s) accepted.", stats.valid_solutions);
     log::info!("{} solution(s) rejected.", stats.invalid_solutions);
+    if stats.resumed_solutions > 0 {
+        log::info!(
+            "{} solution(s) skipped, already processed in a previous run.",
+            stats.resumed_solutions
+        );
+    }
+    if !stats.rejections.is_empty() {
+        log::info!("Rejected solutions by reason: {:?}.", stats.rejections);
+    }
 
     if !stats.internal_errors.is_empty() {
         log::error!(
@@ -97,6 +333,12 @@ fn print_stats(stats: &Stats) {
     }
 
     log::info!("{} solution(s) failed to build.", stats.builds_failed);
+    log::info!(
+        "Ran {} test(s), skipped {}, blocked by failed dependencies {}.",
+        stats.tests_run,
+        stats.tests_skipped,
+        stats.tests_blocked
+    );
 
     let mut tests_with_failures = stats
         .test_failures
@@ -105,6 +347,28 @@ fn print_stats(stats: &Stats) {
         .collect::<Vec<_>>();
     tests_with_failures.sort_unstable_by_key(|(_, failures)| *failures);
     log::info!("Tests by failures count: {:?}.", tests_with_failures);
+    for (kind, failures) in [
+        ("timeouts", &stats.test_timeouts),
+        ("SSH failures", &stats.ssh_failures),
+    ] {
+        if failures.is_empty() {
+            continue;
+        }
+        let mut failures = failures
+            .iter()
+            .map(|(test, failures)| (test, *failures))
+            .collect::<Vec<_>>();
+        failures.sort_unstable_by_key(|(_, failures)| *failures);
+        log::info!("Tests by {} count: {:?}.", kind, failures);
+    }
+
+    if let Some(average) = stats.average_processing_time_ms() {
+        log::info!(
+            "Processing time: {}ms in total, {}ms per solution on average.",
+            stats.processing_time_ms,
+            average
+        );
+    }
 
     if !stats.missing_reports.is_empty() {
         log::error!(
@@ -115,120 +379,376 @@ fn print_stats(stats: &Stats) {
     }
 }
 
-struct LineProcessor {
-    patch_processor: PatchProcessor,
-    patch_validator: Mutex<PatchValidator>,
-    reports_dir: Option<PathBuf>,
-    stats: Mutex<Stats>,
-    stdout: Mutex<Stdout>,
+/// Executes the post-run command.
+/// # Arguments
+/// * cmd - the command to execute.
+/// * summary - path to the saved summary, if any.
+/// * success - whether the whole run was successful.
+/// # Returns
+/// Whether the command succeeded.
+async fn post_run(cmd: &str, summary: Option<&Path>, success: bool) -> bool {
+    log::info!("Executing the post-run command '{}'.", cmd);
+
+    let mut command = process::Command::new("sh");
+    command
+        .arg("-c")
+        .arg(cmd)
+        .env("QEMU_TEST_RUNNER_SUCCESS", if success { "1" } else { "0" })
+        .stdin(Stdio::null());
+    if let Some(summary) = summary {
+        command.env("QEMU_TEST_RUNNER_SUMMARY", summary);
+    }
+
+    match command.status().await {
+        Ok(status) if status.success() => true,
+        Ok(status) => {
+            log::error!("Post-run command failed with {}.", status);
+            false
+        }
+        Err(error) => {
+            log::error!("Failed to execute the post-run command. Error: {}.", error);
+            false
+        }
+    }
 }
 
-impl LineProcessor {
-    async fn print_results(&self, patch: &Patch, report: &RunReport) {
-        let report_col = if report.build().success() {
-            let failed_tests = report
-                .tests()
-                .iter()
-                .filter(|(_, report)| !report.success())
-                .map(|(name, _)| &name[..])
-                .collect::<Vec<_>>();
-
-            if failed_tests.is_empty() {
-                "OK".into()
-            } else {
-                failed_tests.join(",")
+/// Prints the result of a single self-test stage.
+/// The result is colored only if STDOUT is a terminal.
+/// # Returns
+/// Whether the stage passed.
+fn print_check(stage: &str, ok: bool) -> bool {
+    let mark = match (ok, std::io::stdout().is_terminal()) {
+        (true, true) => "\x1b[32m[ OK ]\x1b[0m",
+        (true, false) => "[ OK ]",
+        (false, true) => "\x1b[31m[FAIL]\x1b[0m",
+        (false, false) => "[FAIL]",
+    };
+    println!("{} {}", mark, stage);
+    ok
+}
+
+/// Validates the suite file and all solutions from the input, without running any QEMU process.
+/// Prints the validation result of every solution.
+/// # Arguments
+/// * args - arguments of the program.
+/// * patch_validator - validator for the solutions.
+/// * input - the input with solutions.
+/// # Returns
+/// Whether the suite and all solutions are valid.
+async fn dry_run<R: AsyncRead + Unpin>(
+    args: &Args,
+    mut patch_validator: PatchValidator,
+    input: R,
+) -> bool {
+    let suite = match args.suite.as_ref() {
+        Some(suite) => suite,
+        None => {
+            log::error!("The suite file is required.");
+            return false;
+        }
+    };
+    let run_config = match RunConfig::from_file(suite).await {
+        Ok(run_config) => run_config,
+        Err(error) => {
+            log::error!("Invalid suite file: {:?}.", error);
+            return false;
+        }
+    };
+
+    let mut ok = true;
+    if let Some(test) = args
+        .tests
+        .iter()
+        .find(|test| !run_config.tests.contains_key(*test))
+    {
+        log::error!("Test {} does not exist in the suite.", test);
+        ok = false;
+    }
+
+    let mut tests = run_config
+        .tests
+        .keys()
+        .filter(|test| args.tests.is_empty() || args.tests.contains(test))
+        .collect::<Vec<_>>();
+    tests.sort_unstable();
+    log::info!(
+        "Suite is valid. Every solution would be built with {} step(s) and tested with {} test(s): {:?}.",
+        run_config.build.steps.iter().map(Vec::len).sum::<usize>(),
+        tests.len(),
+        tests
+    );
+    if run_config.bootstrap.is_some() {
+        log::info!("The bootstrap scenario would be run first.");
+    }
+
+    let mut lines = BufReader::new(input).split(b'\n');
+    let (mut valid, mut invalid) = (0, 0);
+    let mut stdout = io::stdout();
+    loop {
+        let line = match lines.next_segment().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(error) => {
+                log::error!("Failed to read from the input. Error: {}.", error);
+                ok = false;
+                break;
             }
-        } else {
-            "build failed".into()
         };
 
-        let line = format!("{};{}\n", patch, report_col);
-        self.stdout
-            .lock()
-            .await
-            .write_all(line.as_bytes())
+        let res = match String::from_utf8(line) {
+            Ok(line) if line.trim().is_empty() => continue,
+            Ok(line) => patch_validator
+                .validate_line(&line)
+                .await
+                .map_err(|error| (line.trim_end().to_string(), error)),
+            Err(error) => Err((
+                String::from_utf8_lossy(error.as_bytes()).into_owned(),
+                ValidationError::NotUtf8,
+            )),
+        };
+        let result_line = match res {
+            Ok(patch) => {
+                valid += 1;
+                format!("{};valid\n", patch)
+            }
+            Err((line, error)) => {
+                invalid += 1;
+                format!("{};invalid {}\n", line, error)
+            }
+        };
+        stdout
+            .write_all(result_line.as_bytes())
             .await
             .expect("failed to write to stdout");
     }
 
-    async fn save_report(&self, patch: &Patch, report: &RunReport) -> Result<()> {
-        if let Some(dir) = self.reports_dir.as_ref() {
-            let buf = serde_json::to_vec_pretty(report).map_err(|error| {
-                Error::new(
-                    ErrorKind::Other,
-                    format!("failed to serialize report: {}", error),
-                )
-            })?;
+    log::info!(
+        "{} solution(s) would be processed, {} rejected.",
+        valid,
+        invalid
+    );
 
-            let mut path = dir.join(patch.id());
-            path.set_extension("json");
+    ok && invalid == 0
+}
 
-            fs::write(&path, &buf[..]).await?;
-            log::info!(
-                "Successfuly saved report for solution {} at {}.",
-                patch,
-                path.display()
-            );
+/// Checks whether the environment is set up correctly.
+/// Boots the base image, establishes an SSH connection, runs a trivial command,
+/// transfers a small file and powers the guest off.
+/// # Returns
+/// Whether all stages passed.
+async fn self_test(patch_processor: &PatchProcessor) -> bool {
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    let root = patch_processor.artifacts_root.join("self_test");
+    let image = root.join("image.qcow2");
+    let file = root.join("file.txt");
+    let prepared = async {
+        prepare_dir(root.as_path()).await?;
+        fs::write(&file, b"self-test").await?;
+        patch_processor
+            .builder
+            .create(patch_processor.base(), Image::Qcow2(image.as_path()))
+            .await
+    }
+    .await;
+    if let Err(error) = prepared {
+        log::error!("Failed to prepare the image: {}.", error);
+        return print_check("Image creation", false);
+    }
+    print_check("Image creation", true);
+
+    let qemu = patch_processor
+        .spawner
+        .spawn(image.into_os_string(), vec![])
+        .await;
+    let qemu = match qemu {
+        Ok(qemu) => qemu,
+        Err(error) => {
+            log::error!("Failed to spawn the QEMU process: {}.", error);
+            return print_check("QEMU startup", false);
+        }
+    };
+    print_check("QEMU startup", true);
+
+    let config = &patch_processor.run_config.execution;
+    let mut executor = BaseExecutor::new(qemu, config).await;
+    let command = executor
+        .run(
+            SshAction::Exec {
+                cmd: "true".into(),
+                expected_exit_code: None,
+                env: vec![],
+            },
+            TIMEOUT,
+        )
+        .await
+        .unwrap_or(false);
+    let transfer = executor
+        .run(
+            SshAction::Send {
+                from: file,
+                to: "self_test.txt".into(),
+                mode: None,
+            },
+            TIMEOUT,
+        )
+        .await
+        .unwrap_or(false);
+    let report = match executor.finish().await {
+        Ok(report) => report,
+        Err(error) => {
+            log::error!("Failed to power off the QEMU process: {}.", error);
+            return print_check("Poweroff", false);
         }
+    };
 
-        Ok(())
+    let mut ok = print_check("SSH connection", report.ssh_ok());
+    if config.ready_command.is_some() {
+        ok &= print_check("Guest readiness", report.ready_ok());
     }
+    ok &= print_check("Command execution", command);
+    ok &= print_check("File transfer", transfer);
+    ok &= print_check("Poweroff", report.exit_ok());
 
-    async fn process(&self, line: String) {
-        let patch = match self
-            .patch_validator
-            .lock()
-            .await
-            .validate(line.as_ref())
-            .await
-        {
-            Ok(patch) => {
-                log::info!("Starting to process solution {}.", patch);
-                patch
-            }
+    ok
+}
+
+struct LineProcessor {
+    batch: Batch,
+    patch_validator: Mutex<PatchValidator>,
+    stdout: Mutex<Stdout>,
+    results_format: ResultsFormat,
+    #[cfg(feature = "metrics")]
+    metrics_addr: Option<SocketAddr>,
+}
+
+impl LineProcessor {
+    /// # Returns
+    /// The solution from the given line, or [None] if the line is blank or invalid.
+    async fn validate(&self, line: Vec<u8>) -> Option<Patch> {
+        let line = match String::from_utf8(line) {
+            Ok(line) => line,
             Err(error) => {
                 log::warn!("Invalid input line. Error: {}", error);
-                self.stats.lock().await.solution_rejected();
-                return;
+                self.batch
+                    .stats()
+                    .await
+                    .solution_rejected(&ValidationError::NotUtf8);
+                return None;
             }
         };
+        if line.trim().is_empty() {
+            log::debug!("Skipping a blank input line.");
+            return None;
+        }
 
-        let run_result = self.patch_processor.process(&patch).await;
-        self.stats.lock().await.patch_processed(&patch, &run_result);
-        let report = match run_result {
-            Ok(report) => {
-                log::info!("Successfuly tested solution {}.", patch);
-                report
-            }
+        match self.patch_validator.lock().await.validate_line(&line).await {
+            Ok(patch) => Some(patch),
             Err(error) => {
-                log::error!(
-                    "An error occurred when testing solution {}: {}.",
-                    patch,
-                    error
-                );
-                return;
+                log::warn!("Invalid input line. Error: {}", error);
+                self.batch.stats().await.solution_rejected(&error);
+                None
             }
-        };
+        }
+    }
 
-        self.print_results(&patch, &report).await;
+    /// # Returns
+    /// A future printing the results line of the given solution.
+    fn print_results(&self, patch: &Patch, report: &RunReport) -> impl Future<Output = ()> + '_ {
+        let line = self.results_format.line(patch, report);
+        async move {
+            self.stdout
+                .lock()
+                .await
+                .write_all(line.as_bytes())
+                .await
+                .expect("failed to write to stdout");
+        }
+    }
 
-        if let Err(error) = self.save_report(&patch, &report).await {
-            log::error!(
-                "An error occurred when saving the report for solution {}: {}.",
-                patch,
-                error
-            );
-            self.stats.lock().await.saving_report_failed(&patch);
+    /// Awaits the given future, serving metrics in the meantime if configured.
+    #[cfg(feature = "metrics")]
+    async fn with_metrics<F: std::future::Future<Output = ()>>(&self, fut: F) {
+        let addr = match self.metrics_addr {
+            Some(addr) => addr,
+            None => return fut.await,
+        };
+
+        tokio::pin!(fut);
+        tokio::select! {
+            _ = &mut fut => {}
+            result = metrics::serve(addr, || self.batch.render_metrics()) => {
+                if let Err(error) = result {
+                    log::error!("Failed to serve metrics at {}. Error: {}.", addr, error);
+                }
+                fut.await;
+            }
         }
     }
 
-    async fn run(self) -> Stats {
-        LinesStream::new(BufReader::new(io::stdin()).lines())
-            .map(|line| line.expect("failed to read to stdin"))
-            .for_each_concurrent(None, |line| self.process(line))
-            .await;
+    /// Awaits the given future.
+    #[cfg(not(feature = "metrics"))]
+    async fn with_metrics<F: std::future::Future<Output = ()>>(&self, fut: F) {
+        fut.await
+    }
+
+    /// Processes solutions read line by line from the given input.
+    /// The first interrupt (Ctrl-C) stops reading the input, but solutions in flight are finished.
+    /// The second interrupt cancels them.
+    /// # Returns
+    /// Statistics of the run.
+    async fn run<R: AsyncRead + Unpin>(self, input: R) -> Stats {
+        let interrupted = Cell::new(false);
+
+        let patches = SplitStream::new(BufReader::new(input).split(b'\n'))
+            .take_while(|line| {
+                if let Err(error) = line {
+                    log::error!(
+                        "Failed to read from the input, no more solutions will be accepted. Error: {}.",
+                        error
+                    );
+                }
+                future::ready(line.is_ok())
+            })
+            .filter_map(|line| future::ready(line.ok()))
+            .take_until(async {
+                interrupt().await;
+                interrupted.set(true);
+            })
+            .filter_map(|line| self.validate(line));
+        let processing = self
+            .batch
+            .run(patches, |patch, report| self.print_results(patch, report));
+        let cancellation = async {
+            interrupt().await;
+            log::warn!(
+                "Interrupted, no more solutions will be accepted. Waiting for solutions in flight, interrupt again to cancel them."
+            );
+            interrupt().await;
+            log::warn!("Interrupted again, cancelling solutions in flight.");
+            interrupted.set(true);
+        };
+        self.with_metrics(async {
+            tokio::select! {
+                _ = processing => {}
+                _ = cancellation => {}
+            }
+        })
+        .await;
+
+        let mut stats = self.batch.finish().await;
+        stats.interrupted = interrupted.get();
+
+        stats
+    }
+}
 
-        self.stats.into_inner()
+/// Waits for an interrupt (Ctrl-C).
+/// Never completes if listening for interrupts is not possible.
+async fn interrupt() {
+    if let Err(error) = signal::ctrl_c().await {
+        log::error!("Failed to listen for interrupts. Error: {}.", error);
+        future::pending::<()>().await;
     }
 }
 
@@ -239,6 +759,50 @@ async fn main() -> ExitCode {
     let args = Args::parse();
     log::debug!("Program is running with args: {:?}.", args);
 
+    if args.dry_run {
+        let patch_validator = make_patch_validator(&args);
+        let success = match args.input.as_ref() {
+            Some(path) => {
+                let file = fs::File::open(path)
+                    .await
+                    .expect("failed to open the input file");
+                dry_run(&args, patch_validator, file).await
+            }
+            None => dry_run(&args, patch_validator, io::stdin()).await,
+        };
+
+        return if success {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
+        };
+    }
+
+    // Probed before any work is done, so that unusable QEMU commands are reported immediately.
+    let environment = Environment::probe(&args.qemu_system, &args.qemu_img).await;
+    log::info!("Running in environment {:?}.", environment);
+    if args.print_tools {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&environment).expect("failed to serialize environment")
+        );
+        for error in environment.tool_errors() {
+            eprintln!("{}", error);
+        }
+
+        return if environment.tool_errors().is_empty() {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
+        };
+    }
+    if !environment.tool_errors().is_empty() {
+        for error in environment.tool_errors() {
+            log::error!("QEMU commands cannot be used: {}.", error);
+        }
+        return ExitCode::FAILURE;
+    }
+
     let (artifacts, reports_dir) = {
         let artifacts = match args.artifacts.as_ref() {
             Some(path) => MaybeTmp::at_path(path.to_path_buf())
@@ -261,18 +825,116 @@ async fn main() -> ExitCode {
         (artifacts, reports_dir)
     };
 
+    match batch::write_report(reports_dir.as_deref(), "environment", &environment).await {
+        Ok(Some(path)) => log::info!("Saved environment fingerprint at {}.", path.display()),
+        Ok(None) => {}
+        Err(error) => log::error!(
+            "An error occurred when saving the environment fingerprint: {}.",
+            error
+        ),
+    }
+
+    let run_self_test = args.self_test;
+    let post_run_cmd = args.post_run.clone();
+    let post_run_required = args.post_run_required;
+    let stats_out = args.stats_out.clone();
+    let max_in_flight = args.max_patches_in_flight;
+    let results_format = args.results_format;
+    let patch_validator = make_patch_validator(&args);
+    // Opened before any work is done, so that a wrong path is reported immediately.
+    let input = match args.input.as_ref() {
+        Some(path) => Some(
+            fs::File::open(path)
+                .await
+                .expect("failed to open the input file"),
+        ),
+        None => None,
+    };
+    let rerun_failed = args.rerun_failed.clone();
+    let resume = args.resume;
+    if resume && reports_dir.is_none() {
+        panic!("resuming a run requires the reports directory");
+    }
+    #[cfg(feature = "metrics")]
+    let metrics_addr = args.metrics_addr;
+    let mut patch_processor = make_patch_processor(args, artifacts.path().to_path_buf()).await;
+
+    if run_self_test {
+        return if self_test(&patch_processor).await {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
+        };
+    }
+
+    if let Some(report) = patch_processor
+        .bootstrap()
+        .await
+        .expect("failed to run the bootstrap scenario")
+    {
+        match batch::write_report(reports_dir.as_deref(), "bootstrap", &report).await {
+            Ok(Some(path)) => log::info!("Saved bootstrap report at {}.", path.display()),
+            Ok(None) => {}
+            Err(error) => log::error!(
+                "An error occurred when saving the bootstrap report: {}.",
+                error
+            ),
+        }
+
+        if !report.success() {
+            log::error!("Bootstrap scenario failed, no solutions will be processed.");
+            return ExitCode::FAILURE;
+        }
+    }
+
     let lines_processor = LineProcessor {
-        patch_processor: make_patch_processor(args, artifacts.path().to_path_buf()).await,
-        patch_validator: Default::default(),
-        reports_dir,
-        stats: Default::default(),
+        batch: Batch::new(
+            patch_processor,
+            BatchOptions {
+                max_in_flight,
+                rerun_failed,
+                resume_from: resume.then(|| reports_dir.clone()).flatten(),
+                reports_dir: reports_dir.clone(),
+            },
+        ),
+        patch_validator: Mutex::new(patch_validator),
         stdout: Mutex::new(io::stdout()),
+        results_format,
+        #[cfg(feature = "metrics")]
+        metrics_addr,
     };
 
-    let stats = lines_processor.run().await;
+    let mut stats = match input {
+        Some(file) => lines_processor.run(file).await,
+        None => lines_processor.run(io::stdin()).await,
+    };
+    stats.qemu_system_version = environment.qemu_system_release.clone();
+    stats.qemu_img_version = environment.qemu_img_release.clone();
     print_stats(&stats);
 
-    if stats.success() {
+    let summary = match batch::write_report(reports_dir.as_deref(), "summary", &stats).await {
+        Ok(path) => path,
+        Err(error) => {
+            log::error!("An error occurred when saving the summary: {}.", error);
+            None
+        }
+    };
+
+    if let Some(path) = stats_out {
+        match batch::write_json(&path, &stats).await {
+            Ok(()) => log::info!("Saved statistics at {}.", path.display()),
+            Err(error) => log::error!("An error occurred when saving the statistics: {}.", error),
+        }
+    }
+
+    let mut success = stats.success();
+    if let Some(cmd) = post_run_cmd {
+        if !post_run(&cmd, summary.as_deref(), success).await && post_run_required {
+            success = false;
+        }
+    }
+
+    if success {
         ExitCode::SUCCESS
     } else {
         ExitCode::FAILURE
@@ -0,0 +1,311 @@
+use crate::stats::Stats;
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::{fmt::Write as _, future::Future, io, net::SocketAddr, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    time,
+};
+
+/// Prefix of all exported metric names.
+const PREFIX: &str = "qemu_test_runner";
+
+/// A histogram of durations with fixed buckets.
+pub struct Histogram {
+    /// Upper bounds of the buckets (seconds).
+    bounds: &'static [f64],
+    /// Cumulative counts of observations in the buckets.
+    counts: Vec<u64>,
+    /// Sum of all observations (seconds).
+    sum: f64,
+    /// Number of all observations.
+    count: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new(&[
+            10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1200.0, 1800.0, 3600.0,
+        ])
+    }
+}
+
+impl Histogram {
+    /// # Arguments
+    /// * bounds - upper bounds of the buckets (seconds), in increasing order.
+    /// # Returns
+    /// A new instance of this struct.
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    /// Records a single observation.
+    pub fn observe(&mut self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        for (bound, count) in self.bounds.iter().zip(&mut self.counts) {
+            if secs <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += secs;
+        self.count += 1;
+    }
+
+    fn render(&self, name: &str, help: &str, out: &mut String) {
+        writeln!(out, "# HELP {}_{} {}", PREFIX, name, help).ok();
+        writeln!(out, "# TYPE {}_{} histogram", PREFIX, name).ok();
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            writeln!(
+                out,
+                "{}_{}_bucket{{le=\"{}\"}} {}",
+                PREFIX, name, bound, count
+            )
+            .ok();
+        }
+        writeln!(
+            out,
+            "{}_{}_bucket{{le=\"+Inf\"}} {}",
+            PREFIX, name, self.count
+        )
+        .ok();
+        writeln!(out, "{}_{}_sum {}", PREFIX, name, self.sum).ok();
+        writeln!(out, "{}_{}_count {}", PREFIX, name, self.count).ok();
+    }
+}
+
+fn render_single(name: &str, kind: &str, help: &str, value: usize, out: &mut String) {
+    writeln!(out, "# HELP {}_{} {}", PREFIX, name, help).ok();
+    writeln!(out, "# TYPE {}_{} {}", PREFIX, name, kind).ok();
+    writeln!(out, "{}_{} {}", PREFIX, name, value).ok();
+}
+
+/// Renders metrics in the Prometheus text format.
+/// # Arguments
+/// * stats - statistics gathered so far.
+/// * running_instances - number of currently running QEMU processes.
+/// * processing_times - durations of processing single solutions.
+/// # Returns
+/// The rendered metrics.
+pub fn render(stats: &Stats, running_instances: usize, processing_times: &Histogram) -> String {
+    let mut out = String::new();
+
+    render_single(
+        "solutions_tested_total",
+        "counter",
+        "Number of solutions accepted by the validator and tested.",
+        stats.valid_solutions,
+        &mut out,
+    );
+    render_single(
+        "solutions_rejected_total",
+        "counter",
+        "Number of solutions rejected by the validator.",
+        stats.invalid_solutions,
+        &mut out,
+    );
+    render_single(
+        "builds_failed_total",
+        "counter",
+        "Number of solutions that failed to build.",
+        stats.builds_failed,
+        &mut out,
+    );
+    render_single(
+        "internal_errors_total",
+        "counter",
+        "Number of solutions not processed due to internal errors.",
+        stats.internal_errors.len(),
+        &mut out,
+    );
+    render_single(
+        "tests_run_total",
+        "counter",
+        "Number of tests run.",
+        stats.tests_run,
+        &mut out,
+    );
+
+    writeln!(
+        out,
+        "# HELP {}_test_failures_total Number of failures by test.",
+        PREFIX
+    )
+    .ok();
+    writeln!(out, "# TYPE {}_test_failures_total counter", PREFIX).ok();
+    let mut failures = stats.test_failures.iter().collect::<Vec<_>>();
+    failures.sort_unstable();
+    for (test, count) in failures {
+        writeln!(
+            out,
+            "{}_test_failures_total{{test=\"{}\"}} {}",
+            PREFIX,
+            test.replace('\\', "\\\\").replace('"', "\\\""),
+            count
+        )
+        .ok();
+    }
+
+    render_single(
+        "qemu_instances_running",
+        "gauge",
+        "Number of currently running QEMU processes.",
+        running_instances,
+        &mut out,
+    );
+    processing_times.render(
+        "solution_processing_seconds",
+        "Time of processing a single solution.",
+        &mut out,
+    );
+
+    out
+}
+
+/// Timeout for reading the request line from a metrics connection.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serves metrics over HTTP on the given address.
+/// Responds to `GET /metrics` with the result of `render`, and with 404 to all other requests.
+/// Connections are handled concurrently, and a connection that does not send its request
+/// within [REQUEST_TIMEOUT] is dropped, so that a single slow client does not block others.
+/// This function returns only if the listener could not be created.
+/// # Arguments
+/// * addr - address to listen on.
+/// * render - produces the current metrics.
+pub async fn serve<F, Fut>(addr: SocketAddr, render: F) -> io::Result<()>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = String>,
+{
+    let listener = TcpListener::bind(addr).await?;
+    log::info!("Serving metrics at http://{}/metrics.", addr);
+
+    let mut connections = FuturesUnordered::new();
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(conn) => conn,
+                Err(error) => {
+                    log::warn!("Failed to accept a metrics connection. Error: {}.", error);
+                    continue;
+                }
+            },
+            Some(()) = connections.next() => continue,
+        };
+
+        connections.push(respond(stream, peer, &render));
+    }
+}
+
+/// Reads a single request from the connection and responds to it.
+async fn respond<F, Fut>(stream: TcpStream, peer: SocketAddr, render: &F)
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = String>,
+{
+    let mut stream = BufReader::new(stream);
+    let mut request_line = String::new();
+    match time::timeout(REQUEST_TIMEOUT, stream.read_line(&mut request_line)).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(error)) => {
+            log::debug!("Failed to read a request from {}. Error: {}.", peer, error);
+            return;
+        }
+        Err(_) => {
+            log::debug!("Timed out waiting for a request from {}.", peer);
+            return;
+        }
+    }
+
+    let response = if request_line.starts_with("GET /metrics ") {
+        let body = render().await;
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".into()
+    };
+
+    if let Err(error) = stream.get_mut().write_all(response.as_bytes()).await {
+        log::debug!("Failed to send metrics to {}. Error: {}.", peer, error);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn histogram() {
+        let mut histogram = Histogram::new(&[1.0, 10.0]);
+        histogram.observe(Duration::from_millis(500));
+        histogram.observe(Duration::from_secs(5));
+        histogram.observe(Duration::from_secs(50));
+
+        let mut out = String::new();
+        histogram.render("x", "help", &mut out);
+        assert!(out.contains("qemu_test_runner_x_bucket{le=\"1\"} 1\n"));
+        assert!(out.contains("qemu_test_runner_x_bucket{le=\"10\"} 2\n"));
+        assert!(out.contains("qemu_test_runner_x_bucket{le=\"+Inf\"} 3\n"));
+        assert!(out.contains("qemu_test_runner_x_sum 55.5\n"));
+        assert!(out.contains("qemu_test_runner_x_count 3\n"));
+    }
+
+    #[test]
+    fn render_stats() {
+        let mut stats = Stats {
+            valid_solutions: 3,
+            invalid_solutions: 1,
+            ..Default::default()
+        };
+        stats.test_failures.insert("test_\"1\"".into(), 2);
+
+        let out = render(&stats, 2, &Histogram::default());
+        assert!(out.contains("qemu_test_runner_solutions_tested_total 3\n"));
+        assert!(out.contains("qemu_test_runner_solutions_rejected_total 1\n"));
+        assert!(out.contains("qemu_test_runner_test_failures_total{test=\"test_\\\"1\\\"\"} 2\n"));
+        assert!(out.contains("qemu_test_runner_qemu_instances_running 2\n"));
+    }
+
+    #[tokio::test]
+    async fn serve_with_idle_client() {
+        let addr = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap()
+        };
+        let server = tokio::spawn(serve(addr, || async { "metrics".to_string() }));
+
+        let idle = loop {
+            match TcpStream::connect(addr).await {
+                Ok(stream) => break stream,
+                Err(_) => time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /metrics HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        time::timeout(
+            Duration::from_secs(2),
+            tokio::io::AsyncReadExt::read_to_string(&mut client, &mut response),
+        )
+        .await
+        .expect("the idle client should not block other clients")
+        .unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("metrics"));
+
+        drop(idle);
+        server.abort();
+    }
+}
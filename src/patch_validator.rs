@@ -1,9 +1,11 @@
+use regex::Regex;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
 use std::{
     collections::{hash_map::Entry, HashMap},
     ffi::OsStr,
     fmt::{self, Display, Formatter},
     io,
-    ops::Not,
     path::{Path, PathBuf},
 };
 use tokio::fs;
@@ -16,11 +18,44 @@ pub enum ValidationError {
     /// The path has no filename.
     NoFilename,
     /// The path has invalid filename.
-    InvalidFilename,
+    /// Contains a description of the expected format.
+    InvalidFilename(String),
     /// The path does not represent a file.
     NotAFile,
     /// The ID extracted from the path was already seen before.
     AlreadySeen(PathBuf),
+    /// The line looks like a JSON object, but could not be parsed.
+    Json(serde_json::Error),
+    /// The line or the content of the file is not valid UTF-8.
+    NotUtf8,
+    /// The file is larger than allowed.
+    /// Contains the size of the file (bytes).
+    TooLarge(u64),
+    /// The content of the file is identical to the content of an earlier file.
+    /// Contains the path to the earlier file.
+    DuplicateContent(PathBuf),
+    /// The ID extracted from the path is reserved for other files in the output directories
+    /// (see [PatchValidator::RESERVED_IDS]).
+    ReservedId(String),
+}
+
+impl ValidationError {
+    /// # Returns
+    /// A short name of this error's variant, used to classify rejections.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Io(_) => "io",
+            Self::NoFilename => "no filename",
+            Self::InvalidFilename(_) => "invalid filename",
+            Self::NotAFile => "not a file",
+            Self::AlreadySeen(_) => "already seen",
+            Self::Json(_) => "invalid JSON",
+            Self::NotUtf8 => "not UTF-8",
+            Self::TooLarge(_) => "too large",
+            Self::DuplicateContent(_) => "duplicate content",
+            Self::ReservedId(_) => "reserved id",
+        }
+    }
 }
 
 impl Display for ValidationError {
@@ -28,11 +63,22 @@ impl Display for ValidationError {
         match self {
             Self::Io(error) => error.fmt(f),
             Self::NoFilename => f.write_str("no filename"),
-            Self::InvalidFilename => {
-                f.write_str("invalid filename, expected format ab123456.patch")
+            Self::InvalidFilename(expected) => {
+                write!(f, "invalid filename, expected format {}", expected)
             }
             Self::NotAFile => f.write_str("not a file"),
             Self::AlreadySeen(path) => write!(f, "id already seen before: {}", path.display()),
+            Self::Json(error) => write!(f, "invalid JSON: {}", error),
+            Self::NotUtf8 => f.write_str("not valid UTF-8"),
+            Self::TooLarge(size) => write!(f, "file too large ({} bytes)", size),
+            Self::DuplicateContent(path) => {
+                write!(
+                    f,
+                    "content identical to an earlier file: {}",
+                    path.display()
+                )
+            }
+            Self::ReservedId(id) => write!(f, "id {} is reserved", id),
         }
     }
 }
@@ -47,9 +93,17 @@ impl From<io::Error> for ValidationError {
 #[derive(Debug)]
 pub struct Patch {
     path: PathBuf,
+    meta: Option<serde_json::Value>,
 }
 
 impl Patch {
+    /// # Returns
+    /// Metadata attached to the solution in the input.
+    /// It is not used for processing, only included in reports.
+    pub fn meta(&self) -> Option<&serde_json::Value> {
+        self.meta.as_ref()
+    }
+
     /// # Returns
     /// The path to the oatch file.
     pub fn path(&self) -> &Path {
@@ -72,13 +126,74 @@ impl Display for Patch {
     }
 }
 
+/// A single line of input in the JSON format.
+#[derive(Deserialize)]
+struct InputLine {
+    path: PathBuf,
+    #[serde(default)]
+    meta: Option<serde_json::Value>,
+}
+
 /// A struct for validating all of the patch paths during one session.
 #[derive(Default)]
 pub struct PatchValidator {
     seen_patches: HashMap<String, PathBuf>,
+    pattern: Option<Regex>,
+    max_bytes: Option<u64>,
+    require_utf8: bool,
+    /// SHA-256 hashes of contents of all accepted files, if duplicate contents are rejected.
+    seen_contents: Option<HashMap<[u8; 32], PathBuf>>,
 }
 
 impl PatchValidator {
+    /// IDs that cannot be used by solutions,
+    /// as they name the summary, the environment fingerprint and the bootstrap report and artifacts.
+    pub const RESERVED_IDS: [&'static str; 3] = ["summary", "environment", "bootstrap"];
+
+    /// # Arguments
+    /// * pattern - a regular expression that must match the whole filename of a patch.
+    ///   It replaces the default format (`ab123456.patch`).
+    ///   The file stem is used as the student's ID.
+    /// # Returns
+    /// A new instance of this struct.
+    pub fn with_pattern(pattern: Regex) -> Self {
+        let anchored = Regex::new(&format!("^(?:{})$", pattern.as_str()))
+            .expect("anchoring a valid regex should produce a valid regex");
+
+        Self {
+            pattern: Some(anchored),
+            ..Default::default()
+        }
+    }
+
+    /// # Arguments
+    /// * max_bytes - maximal size of a patch file (bytes). [None] means no limit.
+    /// # Returns
+    /// This validator, rejecting larger files with [ValidationError::TooLarge].
+    pub fn with_max_bytes(mut self, max_bytes: Option<u64>) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// # Arguments
+    /// * require_utf8 - whether content of a patch file must be valid UTF-8.
+    /// # Returns
+    /// This validator, rejecting other files with [ValidationError::NotUtf8] if required.
+    pub fn with_require_utf8(mut self, require_utf8: bool) -> Self {
+        self.require_utf8 = require_utf8;
+        self
+    }
+
+    /// # Arguments
+    /// * reject - whether files with contents identical to an earlier file should be rejected.
+    ///   This requires reading and hashing every file.
+    /// # Returns
+    /// This validator, rejecting such files with [ValidationError::DuplicateContent] if required.
+    pub fn with_content_dedup(mut self, reject: bool) -> Self {
+        self.seen_contents = reject.then(HashMap::new);
+        self
+    }
+
     fn check_filename(filename: &str) -> bool {
         filename.is_ascii()
             && filename.len() == 14
@@ -87,9 +202,22 @@ impl PatchValidator {
             && filename[2..8].chars().all(|c| c.is_ascii_digit())
     }
 
+    fn filename_error(&self) -> ValidationError {
+        let expected = match self.pattern.as_ref() {
+            Some(pattern) => format!("matching {}", pattern.as_str()),
+            None => "ab123456.patch".into(),
+        };
+
+        ValidationError::InvalidFilename(expected)
+    }
+
     /// Validates a single path.
     /// The path must represent a file with filename accepted by the regular expression of form `([a-z]{2}[0-9]{6})\.patch`
-    /// The first group in this regex is the student's ID.
+    /// (or by the pattern given in [PatchValidator::with_pattern]).
+    /// The file stem is the student's ID.
+    /// If configured, the size, the encoding and the uniqueness of the content of the file are checked as well
+    /// (see [PatchValidator::with_max_bytes], [PatchValidator::with_require_utf8]
+    /// and [PatchValidator::with_content_dedup]).
     /// # Arguments
     /// * path - path to validate.
     /// # Returns
@@ -99,29 +227,87 @@ impl PatchValidator {
             .file_name()
             .ok_or(ValidationError::NoFilename)?
             .to_str()
-            .ok_or(ValidationError::InvalidFilename)?;
+            .ok_or_else(|| self.filename_error())?;
 
-        Self::check_filename(filename)
-            .not()
-            .then_some(Err(ValidationError::InvalidFilename))
-            .unwrap_or(Ok(()))?;
+        let valid = match self.pattern.as_ref() {
+            Some(pattern) => pattern.is_match(filename),
+            None => Self::check_filename(filename),
+        };
+        if !valid {
+            return Err(self.filename_error());
+        }
+        if let Some(id) = Path::new(filename)
+            .file_stem()
+            .and_then(OsStr::to_str)
+            .filter(|id| Self::RESERVED_IDS.contains(id))
+        {
+            return Err(ValidationError::ReservedId(id.into()));
+        }
 
         let metadata = fs::metadata(&path).await?;
         if !metadata.is_file() {
             return Err(ValidationError::NotAFile);
         }
+        if self
+            .max_bytes
+            .is_some_and(|max_bytes| metadata.len() > max_bytes)
+        {
+            return Err(ValidationError::TooLarge(metadata.len()));
+        }
+        let content = if self.require_utf8 || self.seen_contents.is_some() {
+            Some(fs::read(&path).await?)
+        } else {
+            None
+        };
+        if self.require_utf8
+            && content
+                .as_deref()
+                .is_some_and(|content| std::str::from_utf8(content).is_err())
+        {
+            return Err(ValidationError::NotUtf8);
+        }
 
-        match self.seen_patches.entry(filename.to_string()) {
-            Entry::Vacant(e) => {
-                e.insert(path.to_path_buf());
+        if let Some(seen) = self.seen_patches.get(filename) {
+            return Err(ValidationError::AlreadySeen(seen.clone()));
+        }
+        if let (Some(seen_contents), Some(content)) = (self.seen_contents.as_mut(), content) {
+            match seen_contents.entry(Sha256::digest(&content).into()) {
+                Entry::Vacant(e) => {
+                    e.insert(path.to_path_buf());
+                }
+                Entry::Occupied(e) => {
+                    return Err(ValidationError::DuplicateContent(e.get().clone()))
+                }
             }
-            Entry::Occupied(e) => return Err(ValidationError::AlreadySeen(e.get().clone())),
         }
+        self.seen_patches
+            .insert(filename.to_string(), path.to_path_buf());
 
         Ok(Patch {
             path: path.to_path_buf(),
+            meta: None,
         })
     }
+
+    /// Validates a single line of input.
+    /// The line is either a bare path or a JSON object of form `{"path": "...", "meta": ...}`.
+    /// The metadata is attached to the returned [Patch].
+    /// # Arguments
+    /// * line - line to validate. A trailing line terminator (`\n` or `\r\n`) is ignored.
+    /// # Returns
+    /// A valid [Patch].
+    pub async fn validate_line(&mut self, line: &str) -> Result<Patch, ValidationError> {
+        let line = line.trim_end_matches(['\r', '\n']);
+        if !line.trim_start().starts_with('{') {
+            return self.validate(line.as_ref()).await;
+        }
+
+        let input: InputLine = serde_json::from_str(line).map_err(ValidationError::Json)?;
+        let mut patch = self.validate(&input.path).await?;
+        patch.meta = input.meta;
+
+        Ok(patch)
+    }
 }
 
 #[cfg(test)]
@@ -187,4 +373,168 @@ mod tests {
             .expect_err("duplicate id should not pass");
         assert!(matches!(error, ValidationError::AlreadySeen(p) if p == file_1_path));
     }
+
+    #[tokio::test]
+    async fn content_dedup() {
+        let tmp = tempfile::tempdir().unwrap();
+
+        let path_1 = tmp.path().join("aa111111.patch");
+        fs::write(&path_1, "diff").await.unwrap();
+        let path_2 = tmp.path().join("aa222222.patch");
+        fs::write(&path_2, "diff").await.unwrap();
+
+        let mut validator = PatchValidator::default();
+        validator.validate(&path_1).await.unwrap();
+        validator
+            .validate(&path_2)
+            .await
+            .expect("identical content should pass by default");
+
+        let mut validator = PatchValidator::default().with_content_dedup(true);
+        validator.validate(&path_1).await.unwrap();
+        let error = validator
+            .validate(&path_2)
+            .await
+            .expect_err("identical content should not pass");
+        assert!(matches!(error, ValidationError::DuplicateContent(p) if p == path_1));
+
+        let path_3 = tmp.path().join("aa333333.patch");
+        fs::write(&path_3, "other diff").await.unwrap();
+        validator
+            .validate(&path_3)
+            .await
+            .expect("different content should pass");
+    }
+
+    #[tokio::test]
+    async fn content_checks() {
+        let tmp = tempfile::tempdir().unwrap();
+
+        let mut validator = PatchValidator::default()
+            .with_max_bytes(Some(12))
+            .with_require_utf8(true);
+
+        let path = tmp.path().join("aa111111.patch");
+        fs::write(&path, "too large file").await.unwrap();
+        let error = validator
+            .validate(&path)
+            .await
+            .expect_err("too large file should not pass");
+        assert!(matches!(error, ValidationError::TooLarge(14)));
+
+        let path = tmp.path().join("aa222222.patch");
+        fs::write(&path, b"\xff\xfe").await.unwrap();
+        let error = validator
+            .validate(&path)
+            .await
+            .expect_err("binary file should not pass");
+        assert!(matches!(error, ValidationError::NotUtf8));
+
+        let path = tmp.path().join("aa333333.patch");
+        fs::write(&path, "zażółć").await.unwrap();
+        validator
+            .validate(&path)
+            .await
+            .expect("small UTF-8 file should pass");
+
+        // Rejected files are not remembered.
+        fs::write(tmp.path().join("aa111111.patch"), "ok")
+            .await
+            .unwrap();
+        validator
+            .validate(&tmp.path().join("aa111111.patch"))
+            .await
+            .expect("fixed file should pass");
+    }
+
+    #[tokio::test]
+    async fn with_pattern() {
+        let tmp = tempfile::tempdir().unwrap();
+
+        let mut validator = PatchValidator::with_pattern(Regex::new(r"[0-9]{8}\.diff").unwrap());
+
+        let file_1_path = tmp.path().join("12345678.diff");
+        fs::write(&file_1_path, &[]).await.unwrap();
+        let patch = validator
+            .validate(&file_1_path)
+            .await
+            .expect("filename matching the pattern should pass");
+        assert_eq!(patch.id(), "12345678");
+
+        for filename in ["ab123456.patch", "12345678.diff.orig", "x12345678.diff"] {
+            let path = tmp.path().join(filename);
+            fs::write(&path, &[]).await.unwrap();
+            let error = validator
+                .validate(&path)
+                .await
+                .expect_err("filename not matching the pattern should not pass");
+            assert!(matches!(error, ValidationError::InvalidFilename(_)));
+        }
+    }
+
+    #[tokio::test]
+    async fn reserved_ids() {
+        let tmp = tempfile::tempdir().unwrap();
+
+        let mut validator = PatchValidator::with_pattern(Regex::new(r".+\.patch").unwrap());
+        for id in PatchValidator::RESERVED_IDS {
+            let path = tmp.path().join(format!("{}.patch", id));
+            fs::write(&path, &[]).await.unwrap();
+            let error = validator
+                .validate(&path)
+                .await
+                .expect_err("reserved id should not pass");
+            assert!(matches!(error, ValidationError::ReservedId(found) if found == id));
+        }
+
+        let path = tmp.path().join("summary.1.patch");
+        fs::write(&path, &[]).await.unwrap();
+        validator
+            .validate(&path)
+            .await
+            .expect("id containing a reserved word should pass");
+    }
+
+    #[tokio::test]
+    async fn validate_line() {
+        let tmp = tempfile::tempdir().unwrap();
+
+        let mut validator = PatchValidator::default();
+
+        let file_1_path = tmp.path().join("aa111111.patch");
+        fs::write(&file_1_path, &[]).await.unwrap();
+        let patch = validator
+            .validate_line(file_1_path.to_str().unwrap())
+            .await
+            .expect("bare path should pass");
+        assert!(patch.meta().is_none());
+
+        let file_3_path = tmp.path().join("aa333333.patch");
+        fs::write(&file_3_path, &[]).await.unwrap();
+        let patch = validator
+            .validate_line(&format!("{}\r\n", file_3_path.display()))
+            .await
+            .expect("line terminator should be ignored");
+        assert_eq!(patch.path(), file_3_path.as_path());
+
+        let file_2_path = tmp.path().join("aa222222.patch");
+        fs::write(&file_2_path, &[]).await.unwrap();
+        let line = serde_json::json!({
+            "path": file_2_path,
+            "meta": {"student": "Jan Kowalski", "attempt": 2},
+        })
+        .to_string();
+        let patch = validator
+            .validate_line(&line)
+            .await
+            .expect("JSON object should pass");
+        assert_eq!(patch.path(), file_2_path.as_path());
+        assert_eq!(patch.meta().unwrap()["attempt"], 2);
+
+        let error = validator
+            .validate_line(r#"{"path": "#)
+            .await
+            .expect_err("invalid JSON should not pass");
+        assert!(matches!(error, ValidationError::Json(_)));
+    }
 }
@@ -1,26 +1,68 @@
 use futures::{future, TryStreamExt};
+use serde::Serialize;
+use serde_json::{json, Value};
 use std::{
+    collections::VecDeque,
     ffi::{OsStr, OsString},
-    io,
-    net::{Ipv4Addr, SocketAddr},
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    os::unix::{fs::PermissionsExt, process::ExitStatusExt},
     path::Path,
     path::PathBuf,
-    process::{ExitStatus, Stdio},
+    process::{ExitStatus, Output, Stdio},
     str::FromStr,
-    sync::Arc,
-    time::Duration,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 use tempfile::TempDir;
 use tokio::{
     fs,
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+        BufReader,
+    },
     net::UnixStream,
     process::{Child, Command},
-    sync::{OwnedSemaphorePermit, Semaphore},
-    task, time,
+    sync::{oneshot, OwnedSemaphorePermit, Semaphore},
+    task::{self, JoinHandle},
+    time,
 };
 use tokio_stream::wrappers::LinesStream;
 
+/// A format of a QEMU image.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageFormat {
+    /// A copy-on-write image.
+    Qcow2,
+    /// A raw image.
+    Raw,
+}
+
+impl ImageFormat {
+    fn as_os_str(self) -> &'static OsStr {
+        match self {
+            Self::Qcow2 => "qcow2".as_ref(),
+            Self::Raw => "raw".as_ref(),
+        }
+    }
+}
+
+impl FromStr for ImageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "qcow2" => Ok(Self::Qcow2),
+            "raw" => Ok(Self::Raw),
+            other => Err(format!(
+                "invalid image format '{}', expected one of: raw, qcow2",
+                other
+            )),
+        }
+    }
+}
+
 /// An image for QEMU process.
 #[derive(Clone, Copy)]
 pub enum Image<'a> {
@@ -31,6 +73,18 @@ pub enum Image<'a> {
 }
 
 impl<'a> Image<'a> {
+    /// # Arguments
+    /// * path - path to the image file.
+    /// * format - format of the image.
+    /// # Returns
+    /// A new instance of this enum.
+    pub fn new(path: &'a Path, format: ImageFormat) -> Self {
+        match format {
+            ImageFormat::Qcow2 => Self::Qcow2(path),
+            ImageFormat::Raw => Self::Raw(path),
+        }
+    }
+
     /// # Returns
     /// The path to the image file.
     pub fn path(self) -> &'a Path {
@@ -40,11 +94,42 @@ impl<'a> Image<'a> {
         }
     }
 
-    fn format(self) -> &'static OsStr {
+    /// # Returns
+    /// The format of the image.
+    pub fn format(self) -> ImageFormat {
         match self {
-            Self::Qcow2(_) => "qcow2".as_ref(),
-            Self::Raw(_) => "raw".as_ref(),
+            Self::Qcow2(_) => ImageFormat::Qcow2,
+            Self::Raw(_) => ImageFormat::Raw,
+        }
+    }
+}
+
+/// An additional drive attached to a QEMU process.
+#[derive(Clone, Debug, Serialize)]
+pub struct Drive {
+    /// Path to the image file.
+    pub path: PathBuf,
+    /// Format of the image.
+    pub format: ImageFormat,
+    /// Interface of the drive (e.g. `virtio`, `ide`).
+    /// If [None], QEMU's default is used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+}
+
+impl Drive {
+    fn arg(&self) -> OsString {
+        let mut arg = OsString::new();
+        arg.push("file=");
+        arg.push(&self.path);
+        arg.push(",format=");
+        arg.push(self.format.as_os_str());
+        if let Some(interface) = self.interface.as_ref() {
+            arg.push(",if=");
+            arg.push(interface);
         }
+
+        arg
     }
 }
 
@@ -55,111 +140,559 @@ pub struct ImageBuilder {
 }
 
 impl ImageBuilder {
+    /// # Arguments
+    /// output - output of a `qemu-img` invocation.
+    /// what - description of the invocation, used in the error message.
+    /// # Returns
+    /// An error containing the captured stderr if the invocation failed.
+    fn check_output(output: &Output, what: fmt::Arguments<'_>) -> io::Result<()> {
+        if output.status.success() {
+            return Ok(());
+        }
+
+        Err(io::Error::other(format!(
+            "failed to {} ({}): {}",
+            what,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+
     /// Creates a new copy-on-write image.
+    /// An existing file at the destination (e.g. a sealed image from a previous run) is replaced.
     /// # Arguments
     /// src - source (backing) image.
     /// dst - destination (backed) image.
     pub async fn create(&self, src: Image<'_>, dst: Image<'_>) -> io::Result<()> {
-        Command::new(&self.cmd)
+        match fs::remove_file(dst.path()).await {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+            _ => {}
+        }
+
+        let output = Command::new(&self.cmd)
             .arg("create")
             .arg("-f")
-            .arg(dst.format())
+            .arg(dst.format().as_os_str())
             .arg("-b")
             .arg(src.path())
             .arg("-F")
-            .arg(src.format())
+            .arg(src.format().as_os_str())
             .arg(dst.path())
             .output()
             .await?;
 
-        Ok(())
+        Self::check_output(
+            &output,
+            format_args!("create image {}", dst.path().display()),
+        )
+    }
+
+    /// Creates a new blank qcow2 image.
+    /// # Arguments
+    /// dst - path to the new image.
+    /// size_mb - virtual size of the new image (megabytes).
+    pub async fn create_blank(&self, dst: &Path, size_mb: u64) -> io::Result<()> {
+        let output = Command::new(&self.cmd)
+            .arg("create")
+            .arg("-f")
+            .arg(ImageFormat::Qcow2.as_os_str())
+            .arg(dst)
+            .arg(format!("{}M", size_mb))
+            .output()
+            .await?;
+
+        Self::check_output(&output, format_args!("create image {}", dst.display()))
+    }
+
+    /// Creates a new standalone qcow2 image with the contents of the source image.
+    /// Unlike [ImageBuilder::create], the new image does not depend on any backing image.
+    /// An existing file at the destination is replaced.
+    /// # Arguments
+    /// src - source image.
+    /// dst - path to the new image.
+    pub async fn flatten(&self, src: Image<'_>, dst: &Path) -> io::Result<()> {
+        match fs::remove_file(dst).await {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+            _ => {}
+        }
+
+        let output = Command::new(&self.cmd)
+            .arg("convert")
+            .arg("-f")
+            .arg(src.format().as_os_str())
+            .arg("-O")
+            .arg(ImageFormat::Qcow2.as_os_str())
+            .arg(src.path())
+            .arg(dst)
+            .output()
+            .await?;
+
+        Self::check_output(
+            &output,
+            format_args!("flatten image {}", src.path().display()),
+        )
+    }
+
+    /// Makes an existing image read-only on the host,
+    /// so that it can be safely used as a backing image by concurrent QEMU processes.
+    /// QEMU opens backing images read-only, so they work as before.
+    /// This is advisory only: processes running as root ignore the file mode,
+    /// and processes that already have the image open keep their write access.
+    /// Callers must ensure that no QEMU process still uses the image as its writable drive.
+    /// # Arguments
+    /// image - path to the image to seal.
+    pub async fn seal(&self, image: &Path) -> io::Result<()> {
+        let mut permissions = fs::metadata(image).await?.permissions();
+        permissions.set_mode(permissions.mode() & !0o222);
+        fs::set_permissions(image, permissions).await
+    }
+
+    /// Compresses an existing qcow2 image in place.
+    /// Only clusters not present in the backing image are kept.
+    /// # Arguments
+    /// image - path to the image to compress.
+    /// backing - backing image of the compressed image.
+    pub async fn compress(&self, image: &Path, backing: Image<'_>) -> io::Result<()> {
+        let mut tmp = image.as_os_str().to_os_string();
+        tmp.push(".compressed");
+
+        let output = Command::new(&self.cmd)
+            .arg("convert")
+            .arg("-c")
+            .arg("-O")
+            .arg(ImageFormat::Qcow2.as_os_str())
+            .arg("-B")
+            .arg(backing.path())
+            .arg("-F")
+            .arg(backing.format().as_os_str())
+            .arg(image)
+            .arg(&tmp)
+            .output()
+            .await?;
+
+        if let Err(error) =
+            Self::check_output(&output, format_args!("compress image {}", image.display()))
+        {
+            fs::remove_file(&tmp).await.ok();
+            return Err(error);
+        }
+
+        fs::rename(&tmp, image).await
     }
 }
 
 /// A struct for interacting with QEMU Monitor.
+/// The QEMU exposes the Monitor twice: in the human-readable mode (HMP) and in the JSON mode (QMP).
 struct MonitorHandle {
-    /// A temporary directory containing the UNIX socket used by the Monitor.
+    /// A temporary directory containing the UNIX sockets used by the Monitor.
     socket_dir: TempDir,
+    /// Timeout for the sockets to be created by the QEMU.
+    socket_timeout: Duration,
+    /// Interval between checks for the sockets.
+    poll_interval: Duration,
 }
 
 impl MonitorHandle {
-    /// Name of the UNIX socket file.
+    /// Name of the UNIX socket file for the HMP.
     const SOCKET_NAME: &'static str = "monitor.sock";
+    /// Name of the UNIX socket file for the QMP.
+    const QMP_SOCKET_NAME: &'static str = "qmp.sock";
 
     /// Creates a new instance of this struct.
-    /// Creates a temporary directory for the socket file, but does not create the socket itself.
-    /// It must be created by the QEMU.
-    fn new() -> io::Result<Self> {
+    /// Creates a temporary directory for the socket files, but does not create the sockets themselves.
+    /// They must be created by the QEMU.
+    /// # Arguments
+    /// * socket_timeout - timeout for the sockets to appear.
+    /// * poll_interval - interval between checks for the sockets.
+    fn new(socket_timeout: Duration, poll_interval: Duration) -> io::Result<Self> {
         let socket_dir = tempfile::tempdir()?;
 
-        Ok(Self { socket_dir })
+        Ok(Self {
+            socket_dir,
+            socket_timeout,
+            poll_interval,
+        })
     }
 
-    /// Returns the path to the UNIX socket.
+    /// Returns the path to the HMP UNIX socket.
     /// This path may not exist yet, the socket should be created by the QEMU.
     fn socket(&self) -> PathBuf {
         self.socket_dir.path().join(Self::SOCKET_NAME)
     }
 
+    /// Returns the path to the QMP UNIX socket.
+    /// This path may not exist yet, the socket should be created by the QEMU.
+    fn qmp_socket(&self) -> PathBuf {
+        self.socket_dir.path().join(Self::QMP_SOCKET_NAME)
+    }
+
+    /// Parses a single line of the `info usernet` command output.
+    /// The column layout differs between QEMU versions (e.g. the FD column is not always present),
+    /// so the ports are recognized as the numbers following the source and the destination addresses.
+    /// # Returns
+    /// The number of the local port, if the line describes a forward to the port 22.
     fn parse_network_info_line(line: &str) -> Option<u16> {
         let mut chunks = line.split_ascii_whitespace();
+        if !chunks.next()?.contains("HOST_FORWARD") {
+            return None;
+        }
 
-        let hostfwd = chunks
-            .next()
-            .map(|p| p.contains("HOST_FORWARD"))
-            .unwrap_or(false);
-        if hostfwd {
-            let src_port = chunks.nth(2).map(u16::from_str).transpose().ok().flatten();
-            let dst_port = chunks.nth(1).map(u16::from_str).transpose().ok().flatten();
+        let chunks = chunks.collect::<Vec<_>>();
+        let mut ports = chunks
+            .windows(2)
+            .filter(|pair| pair[0] == "*" || IpAddr::from_str(pair[0]).is_ok())
+            .map(|pair| u16::from_str(pair[1]).ok());
 
-            if let (Some(src), Some(22)) = (src_port, dst_port) {
-                return Some(src);
-            }
+        match (ports.next().flatten(), ports.next().flatten()) {
+            (Some(src), Some(22)) => Some(src),
+            _ => None,
         }
-
-        None
     }
 
-    /// Returns the number of the local port forwarded to the port 22 (standard SSH port).
-    async fn ssh_port(&self) -> io::Result<u16> {
-        let mut stream = {
-            let socket = self.socket();
-            while fs::metadata(&socket).await.is_err() {
-                time::sleep(Duration::from_millis(100)).await;
+    /// Waits for the socket to be created by the QEMU and connects to it.
+    /// Fails with [io::ErrorKind::TimedOut] if the socket does not appear within the `socket_timeout`,
+    /// which means that the QEMU failed to start.
+    async fn connect(&self, socket: &Path) -> io::Result<UnixStream> {
+        let wait = async {
+            while fs::metadata(socket).await.is_err() {
+                time::sleep(self.poll_interval).await;
             }
-            UnixStream::connect(socket).await?
         };
+        time::timeout(self.socket_timeout, wait)
+            .await
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "QEMU monitor socket never appeared (waited {}ms)",
+                        self.socket_timeout.as_millis()
+                    ),
+                )
+            })?;
 
-        stream.write_all(b"info usernet\n").await?;
+        UnixStream::connect(socket).await
+    }
+
+    /// Waits for the HMP socket to be created by the QEMU, connects to it and sends the given command.
+    /// # Returns
+    /// The stream for reading the response.
+    async fn send_command(&self, command: &str) -> io::Result<UnixStream> {
+        let mut stream = self.connect(&self.socket()).await?;
+
+        stream.write_all(command.as_bytes()).await?;
+        stream.write_all(b"\n").await?;
         stream.flush().await?;
         stream.shutdown().await?;
 
+        Ok(stream)
+    }
+
+    /// Reads QMP messages from the given stream until a command response arrives.
+    /// Asynchronous events are skipped.
+    /// # Returns
+    /// The value returned by the command.
+    async fn qmp_response<S: AsyncBufRead + Unpin>(stream: &mut S) -> io::Result<Value> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if stream.read_line(&mut line).await? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "QMP connection closed before the response was received",
+                ));
+            }
+
+            let mut message = serde_json::from_str::<Value>(&line)?;
+            if let Some(value) = message.get_mut("return") {
+                return Ok(value.take());
+            }
+            if let Some(error) = message.get("error") {
+                return Err(io::Error::other(format!("QMP command failed: {}", error)));
+            }
+        }
+    }
+
+    /// Negotiates the QMP capabilities and runs the given HMP command through the QMP.
+    /// # Arguments
+    /// * stream - a stream connected to the QMP socket.
+    /// * command - an HMP command to run.
+    /// # Returns
+    /// The output of the command.
+    async fn qmp_human_command<S: AsyncRead + AsyncWrite + Unpin>(
+        stream: S,
+        command: &str,
+    ) -> io::Result<String> {
+        let mut stream = BufReader::new(stream);
+
+        // The greeting has no "return" field, but it must be consumed before sending commands.
+        let mut greeting = String::new();
+        stream.read_line(&mut greeting).await?;
+        serde_json::from_str::<Value>(&greeting)?
+            .get("QMP")
+            .ok_or_else(|| io::Error::other("invalid QMP greeting"))?;
+
+        let requests = [
+            json!({ "execute": "qmp_capabilities" }),
+            json!({
+                "execute": "human-monitor-command",
+                "arguments": { "command-line": command },
+            }),
+        ];
+        let mut output = Value::Null;
+        for request in requests {
+            stream
+                .get_mut()
+                .write_all(format!("{}\n", request).as_bytes())
+                .await?;
+            stream.get_mut().flush().await?;
+            output = Self::qmp_response(&mut stream).await?;
+        }
+
+        match output {
+            Value::String(output) => Ok(output),
+            other => Err(io::Error::other(format!(
+                "unexpected QMP command output: {}",
+                other
+            ))),
+        }
+    }
+
+    /// Requests a graceful shutdown of the guest machine (ACPI power button press).
+    /// Does not wait for the QEMU process to exit.
+    async fn system_powerdown(&self) -> io::Result<()> {
+        let mut stream = self.send_command("system_powerdown").await?;
+        // Reading the response until the QEMU closes the connection ensures the command was processed.
+        stream.read_to_end(&mut Vec::new()).await?;
+
+        Ok(())
+    }
+
+    /// Runs a snapshot command (`savevm` or `loadvm`) through the QMP.
+    /// These commands produce no output on success.
+    async fn snapshot_command(&self, command: &str, name: &str) -> io::Result<()> {
+        let stream = self.connect(&self.qmp_socket()).await?;
+        let output = Self::qmp_human_command(stream, &format!("{} {}", command, name)).await?;
+
+        match output.trim() {
+            "" => Ok(()),
+            error => Err(io::Error::other(format!(
+                "{} of snapshot {} failed: {}",
+                command, name, error
+            ))),
+        }
+    }
+
+    /// Saves the state of the guest machine (memory and all disks) as a snapshot with the given name.
+    /// All writable disks must be in the qcow2 format.
+    async fn savevm(&self, name: &str) -> io::Result<()> {
+        self.snapshot_command("savevm", name).await
+    }
+
+    /// Restores the state of the guest machine from the snapshot with the given name.
+    async fn loadvm(&self, name: &str) -> io::Result<()> {
+        self.snapshot_command("loadvm", name).await
+    }
+
+    /// Returns the number of the local port forwarded to the port 22 (standard SSH port),
+    /// using the given QMP connection.
+    async fn ssh_port_qmp(stream: UnixStream) -> io::Result<u16> {
+        let info = Self::qmp_human_command(stream, "info usernet").await?;
+
+        info.lines()
+            .find_map(Self::parse_network_info_line)
+            .ok_or_else(|| {
+                io::Error::other(
+                    "no SSH port forward found in network info received from the QEMU monitor",
+                )
+            })
+    }
+
+    /// Returns the number of the local port forwarded to the port 22 (standard SSH port),
+    /// using the HMP connection.
+    async fn ssh_port_hmp(&self) -> io::Result<u16> {
+        let stream = self.send_command("info usernet").await?;
+
         let stream = LinesStream::new(BufReader::new(stream).lines())
             .try_filter_map(|line| future::ready(Ok(Self::parse_network_info_line(&line))));
         tokio::pin!(stream);
         stream.try_next().await?.ok_or_else(|| {
-            io::Error::new(
-                io::ErrorKind::Other,
+            io::Error::other(
                 "no SSH port forward found in network info received from the QEMU monitor",
             )
         })
     }
+
+    /// Returns the number of the local port forwarded to the port 22 (standard SSH port).
+    /// The QMP is tried first, as its responses are clearly delimited.
+    /// The HMP is used as a fallback.
+    /// Fails with [io::ErrorKind::TimedOut] if the QMP socket does not appear on time.
+    async fn ssh_port(&self) -> io::Result<u16> {
+        let stream = self.connect(&self.qmp_socket()).await?;
+        match Self::ssh_port_qmp(stream).await {
+            Ok(port) => Ok(port),
+            Err(error) => {
+                log::debug!(
+                    "Failed to get the SSH port through the QMP, falling back to the HMP. Error: {}.",
+                    error
+                );
+                self.ssh_port_hmp().await
+            }
+        }
+    }
+}
+
+/// The way a QEMU process terminated.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum QemuExit {
+    /// The process exited with a zero code.
+    Clean,
+    /// The process exited with a non-zero code.
+    Code(i32),
+    /// The process was killed by a signal.
+    Signal(i32),
+}
+
+impl QemuExit {
+    /// # Returns
+    /// Whether the process exited cleanly.
+    pub fn clean(self) -> bool {
+        self == Self::Clean
+    }
+}
+
+impl fmt::Display for QemuExit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Clean => f.write_str("exit code 0"),
+            Self::Code(code) => write!(f, "exit code {}", code),
+            Self::Signal(signal) => write!(f, "signal {}", signal),
+        }
+    }
+}
+
+impl TryFrom<ExitStatus> for QemuExit {
+    type Error = io::Error;
+
+    fn try_from(status: ExitStatus) -> io::Result<Self> {
+        match (status.code(), status.signal()) {
+            (Some(0), _) => Ok(Self::Clean),
+            (Some(code), _) => Ok(Self::Code(code)),
+            (None, Some(signal)) => Ok(Self::Signal(signal)),
+            (None, None) => Err(io::Error::other(
+                "QEMU process terminated in an unknown way",
+            )),
+        }
+    }
+}
+
+/// A bounded buffer for the output of a QEMU process, which is the serial console of the guest machine.
+/// Only the most recent output is kept.
+#[derive(Clone, Default)]
+pub struct ConsoleLog(Arc<Mutex<VecDeque<u8>>>);
+
+impl ConsoleLog {
+    /// Maximal number of bytes kept in the buffer.
+    pub const LIMIT: usize = 64 * 1024;
+    /// Maximal number of bytes returned by [ConsoleLog::tail].
+    pub const TAIL: usize = 4 * 1024;
+
+    fn push(&self, bytes: &[u8]) {
+        let mut buf = self.0.lock().unwrap();
+        buf.extend(bytes);
+        let excess = buf.len().saturating_sub(Self::LIMIT);
+        buf.drain(..excess);
+    }
+
+    /// # Returns
+    /// The output collected so far.
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.lock().unwrap().iter().copied().collect()
+    }
+
+    /// # Returns
+    /// At most [ConsoleLog::TAIL] most recent bytes of the output, lossily converted to a string and trimmed.
+    /// [None] if there is no output.
+    pub fn tail(&self) -> Option<String> {
+        let contents = self.contents();
+        let start = contents.len().saturating_sub(Self::TAIL);
+        let tail = String::from_utf8_lossy(&contents[start..])
+            .trim()
+            .to_string();
+
+        (!tail.is_empty()).then_some(tail)
+    }
+
+    /// Spawns a task that streams the given reader into this buffer until EOF.
+    fn capture<R>(&self, reader: R) -> JoinHandle<()>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        Self::capture_into(vec![self.clone()], reader)
+    }
+
+    /// Spawns a task that streams the given reader into all given buffers until EOF.
+    fn capture_into<R>(logs: Vec<Self>, mut reader: R) -> JoinHandle<()>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        task::spawn(async move {
+            let mut buf = [0; 4096];
+            loop {
+                match reader.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => logs.iter().for_each(|log| log.push(&buf[..n])),
+                }
+            }
+        })
+    }
+}
+
+/// Permits held by a running [QemuInstance].
+struct Permits {
+    _count: OwnedSemaphorePermit,
+    _memory: Option<OwnedSemaphorePermit>,
 }
 
 /// A wrapper over a Qemu instance running as a [Child] process.
 /// The instance is killed on drop.
 pub struct QemuInstance {
     child: Option<Child>,
-    permit: Option<OwnedSemaphorePermit>,
+    permit: Option<Permits>,
     image_path: OsString,
+    extra_drives: Vec<Drive>,
+    kvm: bool,
+    spawned_at: Instant,
+    exit_sender: Option<oneshot::Sender<()>>,
     monitor: MonitorHandle,
+    console_log: ConsoleLog,
+    stderr: ConsoleLog,
+    console_readers: Vec<JoinHandle<()>>,
+    poll_interval: Duration,
 }
 
 impl QemuInstance {
     /// # Returns
     /// A [SocketAddr] for the SSH connection with the wrapped QEMU instance.
-    pub async fn ssh(&self) -> io::Result<SocketAddr> {
-        let port = self.monitor.ssh_port().await?;
+    /// Fails if the process exits before the address becomes available.
+    /// Fails with [io::ErrorKind::TimedOut] if the process does not create its monitor sockets
+    /// within [QemuConfig::monitor_timeout].
+    pub async fn ssh(&mut self) -> io::Result<SocketAddr> {
+        let port = tokio::select! {
+            port = self.monitor.ssh_port() => port?,
+            status = Self::poll_exit(self.child.as_mut().unwrap(), self.poll_interval) => {
+                let status = status?;
+                self.join_console_readers().await;
+                let message = match self.stderr.tail() {
+                    Some(stderr) => format!(
+                        "QEMU process exited before SSH was available ({}), stderr: {}",
+                        status, stderr
+                    ),
+                    None => format!("QEMU process exited before SSH was available ({})", status),
+                };
+                return Err(io::Error::other(message));
+            }
+        };
 
         Ok(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port))
     }
@@ -170,29 +703,124 @@ impl QemuInstance {
         &self.image_path
     }
 
+    /// # Returns
+    /// Whether the wrapped instance was spawned with KVM acceleration.
+    pub fn kvm(&self) -> bool {
+        self.kvm
+    }
+
+    /// # Returns
+    /// The moment the wrapped instance was spawned.
+    pub fn spawned_at(&self) -> Instant {
+        self.spawned_at
+    }
+
+    /// # Returns
+    /// The additional drives attached to the wrapped instance.
+    pub fn extra_drives(&self) -> &[Drive] {
+        &self.extra_drives[..]
+    }
+
+    /// Requests a graceful shutdown of the guest machine through the QEMU monitor.
+    /// Does not wait for the wrapped [Child] to exit.
+    pub async fn powerdown(&self) -> io::Result<()> {
+        self.monitor.system_powerdown().await
+    }
+
+    /// Saves the state of the guest machine (memory and all disks) as a snapshot with the given name.
+    /// All writable drives must be in the [ImageFormat::Qcow2] format.
+    pub async fn savevm(&self, name: &str) -> io::Result<()> {
+        self.monitor.savevm(name).await
+    }
+
+    /// Restores the state of the guest machine from the snapshot with the given name.
+    /// Open connections to the guest machine are not valid afterwards.
+    pub async fn loadvm(&self, name: &str) -> io::Result<()> {
+        self.monitor.loadvm(name).await
+    }
+
+    /// # Returns
+    /// A handle to the output of the wrapped instance (the serial console of the guest machine).
+    /// It remains valid after this struct is consumed.
+    pub fn console_log(&self) -> ConsoleLog {
+        self.console_log.clone()
+    }
+
+    /// # Returns
+    /// A handle to the stderr of the wrapped instance, which contains errors reported by QEMU itself.
+    /// Its contents are also included in the [QemuInstance::console_log].
+    /// It remains valid after this struct is consumed.
+    pub fn stderr(&self) -> ConsoleLog {
+        self.stderr.clone()
+    }
+
     /// Kills the wrapped [Child].
     pub async fn kill(&mut self) -> io::Result<()> {
         self.child.as_mut().unwrap().kill().await
     }
 
     /// Waits for the wrapped [Child] to exit.
-    pub async fn wait(mut self) -> io::Result<()> {
-        let output = self.child.take().unwrap().wait_with_output().await?;
-        if output.status.success() {
-            Ok(())
-        } else if let Some(code) = output.status.code() {
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("QEMU process exited with a non-zero code {}", code),
-            ))
-        } else {
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                "QEMU process killed by a signal",
-            ))
+    /// # Returns
+    /// The way the wrapped [Child] terminated.
+    pub async fn wait(mut self) -> io::Result<QemuExit> {
+        let status = self.child.take().unwrap().wait().await?;
+        self.join_console_readers().await;
+        QemuExit::try_from(status)
+    }
+
+    /// Waits until the output of the wrapped [Child] is fully captured.
+    /// Should be called only after the [Child] has exited.
+    async fn join_console_readers(&mut self) {
+        for reader in self.console_readers.drain(..) {
+            reader.await.ok();
         }
     }
 
+    /// # Returns
+    /// A receiver that completes once the wrapped [Child] has exited and was reaped,
+    /// no matter if this struct was consumed with [QemuInstance::wait] or dropped.
+    /// Detached instances (see [QemuInstance::detach]) complete it immediately.
+    pub fn on_exit(&mut self) -> oneshot::Receiver<()> {
+        let (tx, rx) = oneshot::channel();
+        self.exit_sender = Some(tx);
+        rx
+    }
+
+    /// Releases the wrapped [Child] without killing it.
+    /// The process runs until it exits on its own or this program terminates.
+    /// The concurrency and memory permits are released, so the process does not block new instances.
+    pub fn detach(mut self) {
+        self.permit.take();
+        self.exit_sender.take();
+        if let Some(mut child) = self.child.take() {
+            task::spawn(async move {
+                child.wait().await.ok();
+            });
+        }
+    }
+
+    /// Waits until the wrapped [Child] exits, without consuming this struct.
+    /// # Returns
+    /// Exit status of the wrapped [Child].
+    pub async fn exited(&mut self) -> io::Result<ExitStatus> {
+        Self::poll_exit(self.child.as_mut().unwrap(), self.poll_interval).await
+    }
+
+    async fn poll_exit(child: &mut Child, poll_interval: Duration) -> io::Result<ExitStatus> {
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(status);
+            }
+            time::sleep(poll_interval).await;
+        }
+    }
+
+    /// # Returns
+    /// The interval between checks in loops waiting for the wrapped instance.
+    pub fn poll_interval(&self) -> Duration {
+        self.poll_interval
+    }
+
     /// Checks whether the wrapped [Child] has exited.
     /// # Returns
     /// Exit status of the wrapped [Child], if available.
@@ -204,10 +832,12 @@ impl QemuInstance {
 impl Drop for QemuInstance {
     fn drop(&mut self) {
         let permit = self.permit.take();
+        let exit_sender = self.exit_sender.take();
         if let Some(mut child) = self.child.take() {
             child.start_kill().ok();
             task::spawn(async move {
                 let _permit = permit;
+                let _exit_sender = exit_sender;
                 child.wait().await.ok();
             });
         }
@@ -219,16 +849,84 @@ pub struct QemuConfig {
     /// The command used to spawn a QEMU process.
     pub cmd: OsString,
     /// The memory limit for new instances (megabytes).
-    pub memory: u16,
+    pub memory: u32,
+    /// Number of virtual CPUs for new instances.
+    /// If [None], QEMU's default is used.
+    pub smp: Option<u8>,
     /// Whether to enable KVM for new instances.
     pub enable_kvm: bool,
     /// Whether to turn of the kernel irqchip.
+    /// Appended as `kernel_irqchip=off` to the machine options.
     pub irqchip_off: bool,
+    /// Machine type (e.g. `q35`, `virt`).
+    /// If [None], QEMU's default is used.
+    pub machine: Option<String>,
+    /// CPU model (e.g. `host`, `cortex-a57`).
+    /// If [None], QEMU's default is used.
+    pub cpu: Option<String>,
+    /// Interface of the drive (e.g. `virtio`, `ide`).
+    /// If [None], QEMU's default is used.
+    pub drive_interface: Option<String>,
+    /// Cache mode of the drive (e.g. `writeback`, `unsafe`).
+    /// If [None], QEMU's default is used.
+    pub drive_cache: Option<String>,
+    /// Additional arguments appended after the ones generated from this config.
+    /// See [QemuConfig::MANAGED_ARGS].
+    pub extra_args: Vec<OsString>,
+    /// Timeout for new instances to create their monitor sockets.
+    pub monitor_timeout: Duration,
+    /// Interval between checks in loops waiting for new instances (e.g. for their exit).
+    pub poll_interval: Duration,
+}
+
+impl QemuConfig {
+    /// Options managed by the [QemuSpawner], which cannot be given in `extra_args`.
+    /// The monitor is used to discover the SSH port and the network setup provides the SSH forward.
+    pub const MANAGED_ARGS: &'static [&'static str] =
+        &["monitor", "qmp", "net", "netdev", "nic", "daemonize"];
+
+    /// # Returns
+    /// The first argument from `extra_args` that would clash with an option managed by the [QemuSpawner].
+    pub fn managed_extra_arg(&self) -> Option<&OsStr> {
+        self.extra_args
+            .iter()
+            .find(|arg| {
+                arg.to_str()
+                    .and_then(|arg| arg.strip_prefix('-'))
+                    .map(|option| option.strip_prefix('-').unwrap_or(option))
+                    .is_some_and(|option| Self::MANAGED_ARGS.contains(&option))
+            })
+            .map(OsString::as_os_str)
+    }
+}
+
+#[cfg(test)]
+impl QemuConfig {
+    /// # Returns
+    /// A simple config for tests.
+    pub fn test() -> Self {
+        Self {
+            cmd: "qemu-system-x86_64".into(),
+            memory: 1024,
+            smp: None,
+            enable_kvm: false,
+            irqchip_off: true,
+            machine: None,
+            cpu: None,
+            drive_interface: None,
+            drive_cache: None,
+            extra_args: vec![],
+            monitor_timeout: Duration::from_secs(10),
+            poll_interval: Duration::from_millis(100),
+        }
+    }
 }
 
 /// A struct used to spawn new [QemuInstance]s.
 pub struct QemuSpawner {
     permits: Arc<Semaphore>,
+    children_limit: usize,
+    memory_permits: Option<(Arc<Semaphore>, u32)>,
     config: QemuConfig,
 }
 
@@ -243,19 +941,53 @@ impl QemuSpawner {
     pub fn new(children_limit: usize, config: QemuConfig) -> Self {
         Self {
             permits: Arc::new(Semaphore::new(children_limit)),
+            children_limit,
+            memory_permits: None,
             config,
         }
     }
 
-    fn setup_cmd(&self, image_path: &OsStr, monitor_socket: &OsStr) -> Command {
+    /// Limits the total memory of running QEMU processes spawned with this instance.
+    /// Every process reserves the memory it is configured with.
+    /// # Arguments
+    /// * limit_mb - limit for the total memory (megabytes).
+    /// # Returns
+    /// This instance with the limit set.
+    pub fn with_memory_limit(mut self, limit_mb: u32) -> Self {
+        self.memory_permits = Some((Arc::new(Semaphore::new(limit_mb as usize)), limit_mb));
+        self
+    }
+
+    /// # Returns
+    /// Number of currently running QEMU processes spawned with this instance.
+    pub fn running(&self) -> usize {
+        self.children_limit - self.permits.available_permits()
+    }
+
+    fn setup_cmd(
+        &self,
+        image_path: &OsStr,
+        extra_drives: &[Drive],
+        monitor: &MonitorHandle,
+    ) -> Command {
         let mut drive = OsString::new();
         drive.push("file=");
         drive.push(image_path);
+        if let Some(interface) = self.config.drive_interface.as_ref() {
+            drive.push(",if=");
+            drive.push(interface);
+        }
+        if let Some(cache) = self.config.drive_cache.as_ref() {
+            drive.push(",cache=");
+            drive.push(cache);
+        }
 
-        let mut monitor = OsString::new();
-        monitor.push("unix:");
-        monitor.push(monitor_socket);
-        monitor.push(",server,nowait");
+        let socket_arg = |socket: PathBuf| {
+            let mut arg = OsString::from("unix:");
+            arg.push(socket);
+            arg.push(",server,nowait");
+            arg
+        };
 
         let mut cmd = Command::new(&self.config.cmd);
         cmd.arg("-nographic")
@@ -270,16 +1002,38 @@ impl QemuSpawner {
             .arg("-m")
             .arg(format!("{}M", self.config.memory))
             .arg("-monitor")
-            .arg(monitor);
+            .arg(socket_arg(monitor.socket()))
+            .arg("-qmp")
+            .arg(socket_arg(monitor.qmp_socket()));
+
+        for drive in extra_drives {
+            cmd.arg("-drive").arg(drive.arg());
+        }
+
+        if let Some(smp) = self.config.smp {
+            cmd.arg("-smp").arg(smp.to_string());
+        }
 
         if self.config.enable_kvm {
             cmd.arg("-enable-kvm");
         }
 
-        if self.config.irqchip_off {
-            cmd.arg("-machine").arg("kernel_irqchip=off");
+        let machine = match (self.config.machine.as_ref(), self.config.irqchip_off) {
+            (Some(machine), true) => Some(format!("{},kernel_irqchip=off", machine)),
+            (Some(machine), false) => Some(machine.clone()),
+            (None, true) => Some("kernel_irqchip=off".into()),
+            (None, false) => None,
+        };
+        if let Some(machine) = machine {
+            cmd.arg("-machine").arg(machine);
+        }
+
+        if let Some(cpu) = self.config.cpu.as_ref() {
+            cmd.arg("-cpu").arg(cpu);
         }
 
+        cmd.args(&self.config.extra_args);
+
         cmd.stderr(Stdio::piped())
             .stdout(Stdio::piped())
             .stdin(Stdio::null())
@@ -289,35 +1043,88 @@ impl QemuSpawner {
     }
 
     /// Spawns a new QEMU instance.
-    /// This method will wait if there are too many running QEMU processes spawned with this instance.
+    /// This method will wait if there are too many running QEMU processes spawned with this instance,
+    /// or if they use too much memory (see [QemuSpawner::with_memory_limit]).
     /// # Arguments
     /// * image_path - path to the QEMU image to use.
+    /// * extra_drives - additional drives to attach.
     /// # Returns
     /// A newly spawned QEMU processed wrapped in a [QemuInstance].
-    pub async fn spawn(&self, image_path: OsString) -> io::Result<QemuInstance> {
+    pub async fn spawn(
+        &self,
+        image_path: OsString,
+        extra_drives: Vec<Drive>,
+    ) -> io::Result<QemuInstance> {
         log::debug!(
             "Awaiting for a permission to spawn a QEMU process on image {}.",
             image_path.to_string_lossy()
         );
-        let permit = self
+        let memory = self.config.memory;
+        if let Some((_, limit)) = self.memory_permits.as_ref() {
+            if memory > *limit {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "QEMU process memory ({}M) exceeds the total memory limit ({}M)",
+                        memory, limit
+                    ),
+                ));
+            }
+        }
+
+        let count = self
             .permits
             .clone()
             .acquire_owned()
             .await
             .expect("semaphore should not be closed");
+        let memory = match self.memory_permits.as_ref() {
+            Some((permits, _)) => Some(
+                permits
+                    .clone()
+                    .acquire_many_owned(memory)
+                    .await
+                    .expect("semaphore should not be closed"),
+            ),
+            None => None,
+        };
+        let permit = Permits {
+            _count: count,
+            _memory: memory,
+        };
 
-        let monitor = MonitorHandle::new()?;
-        let socket = monitor.socket();
+        let monitor = MonitorHandle::new(self.config.monitor_timeout, self.config.poll_interval)?;
 
-        let mut command = self.setup_cmd(&image_path, socket.as_os_str());
+        let mut command = self.setup_cmd(&image_path, &extra_drives, &monitor);
         log::debug!("Spawning a QEMU process. {:?}", command);
-        let child = command.spawn()?;
+        let mut child = command.spawn()?;
+
+        let console_log = ConsoleLog::default();
+        let stderr_log = ConsoleLog::default();
+        let mut console_readers = Vec::with_capacity(2);
+        if let Some(stdout) = child.stdout.take() {
+            console_readers.push(console_log.capture(stdout));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            console_readers.push(ConsoleLog::capture_into(
+                vec![console_log.clone(), stderr_log.clone()],
+                stderr,
+            ));
+        }
 
         Ok(QemuInstance {
             child: Some(child),
             permit: Some(permit),
             image_path,
+            extra_drives,
+            kvm: self.config.enable_kvm,
+            spawned_at: Instant::now(),
+            exit_sender: None,
             monitor,
+            console_log,
+            stderr: stderr_log,
+            console_readers,
+            poll_interval: self.config.poll_interval,
         })
     }
 }
@@ -325,9 +1132,451 @@ impl QemuSpawner {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::test_util::Env;
+    use crate::test_util::{fake_command, Env};
+    use std::os::unix::fs::PermissionsExt;
     use tokio::{task, time};
 
+    fn monitor() -> MonitorHandle {
+        MonitorHandle::new(Duration::from_secs(1), Duration::from_millis(100)).unwrap()
+    }
+
+    fn drive_arg(config: QemuConfig) -> OsString {
+        let cmd = QemuSpawner::new(1, config).setup_cmd("image.qcow2".as_ref(), &[], &monitor());
+        let mut args = cmd.as_std().get_args();
+        args.find(|arg| *arg == "-drive")
+            .and_then(|_| args.next())
+            .expect("no -drive argument")
+            .to_os_string()
+    }
+
+    #[test]
+    fn drive_options() {
+        assert_eq!(drive_arg(QemuConfig::test()), "file=image.qcow2");
+
+        let config = QemuConfig {
+            drive_interface: Some("virtio".into()),
+            drive_cache: Some("unsafe".into()),
+            ..QemuConfig::test()
+        };
+        assert_eq!(drive_arg(config), "file=image.qcow2,if=virtio,cache=unsafe");
+
+        let config = QemuConfig {
+            drive_cache: Some("none".into()),
+            ..QemuConfig::test()
+        };
+        assert_eq!(drive_arg(config), "file=image.qcow2,cache=none");
+    }
+
+    /// Output of `info usernet` in QEMU 2.11.
+    const USERNET_2_11: &str = "VLAN 0 (net0):
+  Protocol[State]    FD  Source Address  Port   Dest. Address  Port RecvQ SendQ
+  TCP[HOST_FORWARD]  12               * 42795       10.0.2.15    22     0     0
+";
+
+    /// Output of `info usernet` in QEMU 6.1, with an established SSH connection.
+    const USERNET_6_1: &str = "Hub -1 (#net010):
+  Protocol[State]    FD  Source Address  Port   Dest. Address  Port RecvQ SendQ
+  TCP[ESTABLISHED]   23       10.0.2.15    22       10.0.2.2 52344     0     0
+  TCP[HOST_FORWARD]  16               * 39551       10.0.2.15    22     0     0
+";
+
+    #[test]
+    fn network_info() {
+        let port = |info: &str| {
+            info.lines()
+                .find_map(MonitorHandle::parse_network_info_line)
+        };
+
+        assert_eq!(port(USERNET_2_11), Some(42795));
+        assert_eq!(port(USERNET_6_1), Some(39551));
+        assert_eq!(
+            port("  TCP[HOST_FORWARD]  127.0.0.1  2222  10.0.2.15  22  0  0"),
+            Some(2222)
+        );
+        assert_eq!(
+            port("  TCP[HOST_FORWARD]  16  *  8080  10.0.2.15  80  0  0"),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn monitor_socket_timeout() {
+        let monitor =
+            MonitorHandle::new(Duration::from_millis(200), Duration::from_millis(100)).unwrap();
+
+        let error = monitor.ssh_port().await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn qmp_human_command() {
+        let (client, server) = UnixStream::pair().unwrap();
+
+        let server = task::spawn(async move {
+            let mut server = BufReader::new(server);
+            server
+                .get_mut()
+                .write_all(b"{\"QMP\": {\"version\": {}, \"capabilities\": []}}\r\n")
+                .await
+                .unwrap();
+
+            let mut requests = vec![];
+            for response in [
+                "{\"return\": {}}\r\n".to_string(),
+                format!(
+                    "{{\"event\": \"NIC_RX_FILTER_CHANGED\"}}\r\n{{\"return\": {}}}\r\n",
+                    Value::from(USERNET_6_1)
+                ),
+            ] {
+                let mut request = String::new();
+                server.read_line(&mut request).await.unwrap();
+                requests.push(serde_json::from_str::<Value>(&request).unwrap());
+                server
+                    .get_mut()
+                    .write_all(response.as_bytes())
+                    .await
+                    .unwrap();
+            }
+
+            requests
+        });
+
+        let output = MonitorHandle::qmp_human_command(client, "info usernet")
+            .await
+            .unwrap();
+        assert_eq!(output, USERNET_6_1);
+
+        let requests = server.await.unwrap();
+        assert_eq!(requests[0], json!({ "execute": "qmp_capabilities" }));
+        assert_eq!(
+            requests[1],
+            json!({
+                "execute": "human-monitor-command",
+                "arguments": { "command-line": "info usernet" },
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn qmp_error() {
+        let (client, mut server) = UnixStream::pair().unwrap();
+        server
+            .write_all(
+                b"{\"QMP\": {}}\n{\"error\": {\"class\": \"CommandNotFound\", \"desc\": \"x\"}}\n",
+            )
+            .await
+            .unwrap();
+
+        let error = MonitorHandle::qmp_human_command(client, "info usernet")
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("CommandNotFound"));
+    }
+
+    #[test]
+    fn console_log_limit() {
+        let log = ConsoleLog::default();
+        log.push(&vec![b'a'; ConsoleLog::LIMIT]);
+        log.push(b"panic");
+
+        let contents = log.contents();
+        assert_eq!(contents.len(), ConsoleLog::LIMIT);
+        assert!(contents.ends_with(b"apanic"));
+
+        let tail = log.tail().unwrap();
+        assert_eq!(tail.len(), ConsoleLog::TAIL);
+        assert!(tail.ends_with("apanic"));
+        assert_eq!(ConsoleLog::default().tail(), None);
+    }
+
+    #[tokio::test]
+    async fn console_log_capture() {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg("echo boot; echo panic >&2")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        let log = ConsoleLog::default();
+        let stdout = log.capture(child.stdout.take().unwrap());
+        let stderr = log.capture(child.stderr.take().unwrap());
+        child.wait().await.unwrap();
+        stdout.await.unwrap();
+        stderr.await.unwrap();
+
+        let contents = String::from_utf8(log.contents()).unwrap();
+        assert!(contents.contains("boot\n"));
+        assert!(contents.contains("panic\n"));
+    }
+
+    #[tokio::test]
+    async fn create_failure() {
+        let tmp = tempfile::tempdir().unwrap();
+        let script = fake_command(
+            tmp.path(),
+            "qemu-img",
+            "echo 'unsupported format' >&2\nexit 1\n",
+        )
+        .await;
+
+        let builder = ImageBuilder {
+            cmd: script.into_os_string(),
+        };
+        let error = builder
+            .create(
+                Image::Raw("base.img".as_ref()),
+                Image::Qcow2(tmp.path().join("image.qcow2").as_path()),
+            )
+            .await
+            .expect_err("failed qemu-img should be reported");
+        assert!(error.to_string().contains("unsupported format"));
+
+        builder
+            .create_blank(tmp.path().join("disk.qcow2").as_path(), 16)
+            .await
+            .expect_err("failed qemu-img should be reported");
+    }
+
+    #[tokio::test]
+    async fn flatten() {
+        let tmp = tempfile::tempdir().unwrap();
+        let script = fake_command(
+            tmp.path(),
+            "qemu-img",
+            &format!("echo \"$@\" > {}\n", tmp.path().join("args").display()),
+        )
+        .await;
+
+        let dst = tmp.path().join("flat.qcow2");
+        fs::write(&dst, "").await.unwrap();
+        let builder = ImageBuilder {
+            cmd: script.into_os_string(),
+        };
+        builder.seal(&dst).await.unwrap();
+
+        builder
+            .flatten(Image::Qcow2("build.qcow2".as_ref()), &dst)
+            .await
+            .unwrap();
+        assert!(!dst.exists());
+        let args = fs::read_to_string(tmp.path().join("args")).await.unwrap();
+        assert_eq!(
+            args.trim(),
+            format!("convert -f qcow2 -O qcow2 build.qcow2 {}", dst.display())
+        );
+    }
+
+    #[tokio::test]
+    async fn exit_before_ssh() {
+        let tmp = tempfile::tempdir().unwrap();
+        let script = fake_command(
+            tmp.path(),
+            "qemu-system",
+            "echo 'kvm: permission denied' >&2\nexit 1\n",
+        )
+        .await;
+
+        let spawner = QemuSpawner::new(
+            1,
+            QemuConfig {
+                cmd: script.into_os_string(),
+                ..QemuConfig::test()
+            },
+        );
+        let mut qemu = spawner.spawn("image.qcow2".into(), vec![]).await.unwrap();
+
+        let error = qemu.ssh().await.unwrap_err();
+        assert!(error.to_string().contains("kvm: permission denied"));
+    }
+
+    #[tokio::test]
+    async fn seal() {
+        let tmp = tempfile::tempdir().unwrap();
+        let image = tmp.path().join("image.qcow2");
+        fs::write(&image, "").await.unwrap();
+        fs::set_permissions(&image, std::fs::Permissions::from_mode(0o664))
+            .await
+            .unwrap();
+
+        let builder = ImageBuilder { cmd: "true".into() };
+        builder.seal(&image).await.unwrap();
+        let mode = fs::metadata(&image).await.unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o444);
+
+        builder
+            .create(Image::Raw("base.img".as_ref()), Image::Qcow2(&image))
+            .await
+            .unwrap();
+        assert!(!image.exists());
+    }
+
+    #[test]
+    fn image_format_from_str() {
+        assert_eq!("raw".parse(), Ok(ImageFormat::Raw));
+        assert_eq!("qcow2".parse(), Ok(ImageFormat::Qcow2));
+        assert!("vmdk".parse::<ImageFormat>().is_err());
+
+        let image = Image::new("image.qcow2".as_ref(), ImageFormat::Qcow2);
+        assert!(matches!(image, Image::Qcow2(_)));
+    }
+
+    #[test]
+    fn smp() {
+        let args = |config| {
+            QemuSpawner::new(1, config)
+                .setup_cmd("image.qcow2".as_ref(), &[], &monitor())
+                .as_std()
+                .get_args()
+                .map(OsStr::to_os_string)
+                .collect::<Vec<_>>()
+        };
+
+        assert!(!args(QemuConfig::test()).contains(&"-smp".into()));
+
+        let config = QemuConfig {
+            smp: Some(4),
+            ..QemuConfig::test()
+        };
+        let args = args(config);
+        let position = args
+            .iter()
+            .position(|arg| arg == "-smp")
+            .expect("no -smp argument");
+        assert_eq!(args[position + 1], "4");
+    }
+
+    #[test]
+    fn machine_and_cpu() {
+        let args = |config| {
+            QemuSpawner::new(1, config)
+                .setup_cmd("image.qcow2".as_ref(), &[], &monitor())
+                .as_std()
+                .get_args()
+                .map(OsStr::to_os_string)
+                .collect::<Vec<_>>()
+        };
+        let value = |args: &[OsString], option: &str| {
+            args.iter()
+                .position(|arg| arg == option)
+                .map(|position| args[position + 1].clone())
+        };
+
+        let default_args = args(QemuConfig::test());
+        assert_eq!(
+            value(&default_args, "-machine"),
+            Some("kernel_irqchip=off".into())
+        );
+        assert_eq!(value(&default_args, "-cpu"), None);
+
+        let config = QemuConfig {
+            machine: Some("virt".into()),
+            cpu: Some("cortex-a57".into()),
+            irqchip_off: false,
+            ..QemuConfig::test()
+        };
+        let custom_args = args(config);
+        assert_eq!(value(&custom_args, "-machine"), Some("virt".into()));
+        assert_eq!(value(&custom_args, "-cpu"), Some("cortex-a57".into()));
+
+        let config = QemuConfig {
+            machine: Some("q35".into()),
+            ..QemuConfig::test()
+        };
+        assert_eq!(
+            value(&args(config), "-machine"),
+            Some("q35,kernel_irqchip=off".into())
+        );
+    }
+
+    #[test]
+    fn extra_args() {
+        let config = QemuConfig {
+            extra_args: vec!["-cpu".into(), "host".into()],
+            ..QemuConfig::test()
+        };
+        assert!(config.managed_extra_arg().is_none());
+        let args = QemuSpawner::new(1, config)
+            .setup_cmd("image.qcow2".as_ref(), &[], &monitor())
+            .as_std()
+            .get_args()
+            .map(OsStr::to_os_string)
+            .collect::<Vec<_>>();
+        assert!(args.ends_with(&["-cpu".into(), "host".into()]));
+
+        for managed in ["-monitor", "--netdev", "-nic"] {
+            let config = QemuConfig {
+                extra_args: vec!["-cpu".into(), "host".into(), managed.into()],
+                ..QemuConfig::test()
+            };
+            assert_eq!(config.managed_extra_arg(), Some(OsStr::new(managed)));
+        }
+    }
+
+    #[test]
+    fn extra_drives() {
+        let drives = [
+            Drive {
+                path: "disk_1.qcow2".into(),
+                format: ImageFormat::Qcow2,
+                interface: None,
+            },
+            Drive {
+                path: "disk_2.img".into(),
+                format: ImageFormat::Raw,
+                interface: Some("ide".into()),
+            },
+        ];
+        let cmd = QemuSpawner::new(1, QemuConfig::test()).setup_cmd(
+            "image.qcow2".as_ref(),
+            &drives,
+            &monitor(),
+        );
+        let args = cmd.as_std().get_args().collect::<Vec<_>>();
+        let drive_args = args
+            .windows(2)
+            .filter(|pair| pair[0] == "-drive")
+            .map(|pair| pair[1])
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            drive_args,
+            [
+                "file=image.qcow2",
+                "file=disk_1.qcow2,format=qcow2",
+                "file=disk_2.img,format=raw,if=ide"
+            ]
+        );
+    }
+
+    #[test]
+    fn qemu_exit_from_status() {
+        let exit = QemuExit::try_from(ExitStatus::from_raw(0)).unwrap();
+        assert_eq!(exit, QemuExit::Clean);
+        assert!(exit.clean());
+
+        let exit = QemuExit::try_from(ExitStatus::from_raw(3 << 8)).unwrap();
+        assert_eq!(exit, QemuExit::Code(3));
+        assert!(!exit.clean());
+
+        let exit = QemuExit::try_from(ExitStatus::from_raw(9)).unwrap();
+        assert_eq!(exit, QemuExit::Signal(9));
+        assert!(!exit.clean());
+    }
+
+    #[tokio::test]
+    async fn memory_limit_exceeded() {
+        let spawner = QemuSpawner::new(1, QemuConfig::test()).with_memory_limit(1);
+        let error = spawner
+            .spawn("image.qcow2".into(), vec![])
+            .await
+            .err()
+            .expect("spawn should fail");
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(spawner.running(), 0);
+    }
+
     #[ignore]
     #[tokio::test]
     async fn build_and_run() {
@@ -341,14 +1590,15 @@ mod test {
             .expect("failed to build the image");
         let mut qemu = env
             .spawner(1)
-            .spawn(image.into())
+            .spawn(image.into(), vec![])
             .await
             .expect("failed to spawn the QEMU process");
 
         time::sleep(Duration::from_secs(1)).await;
         assert!(qemu.try_wait().expect("try_wait failed").is_none());
         qemu.kill().await.expect("kill failed");
-        assert!(qemu.wait().await.is_err());
+        let exit = qemu.wait().await.expect("wait failed");
+        assert!(matches!(exit, QemuExit::Signal(_)));
     }
 
     #[ignore]
@@ -369,11 +1619,11 @@ mod test {
 
         let spawner = env.spawner(1);
         let _qemu = spawner
-            .spawn(image_1.into())
+            .spawn(image_1.into(), vec![])
             .await
             .expect("failed to spawn the QEMU process");
 
-        let handle = task::spawn(async move { spawner.spawn(image_2.into()).await });
+        let handle = task::spawn(async move { spawner.spawn(image_2.into(), vec![]).await });
 
         time::sleep(Duration::from_secs(1)).await;
         assert!(!handle.is_finished());
@@ -1,21 +1,45 @@
 use crate::Output;
 use serde::{Deserialize, Serialize};
-use ssh2::Session;
+use ssh2::{Channel, CheckResult, ErrorCode, KnownHostFileKind, KnownHosts, Session};
 use std::{
-    fmt::Display,
-    fs::File,
-    io::{self, Read},
+    fmt::{self, Display, Formatter},
+    fs::{self, File},
+    io::{self, Read, Write},
     net::{SocketAddr, TcpStream},
+    num::NonZeroUsize,
+    os::raw::c_int,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
     thread,
     time::Duration,
 };
 use tokio::{
-    sync::{mpsc, oneshot},
-    task,
+    sync::{mpsc, oneshot, Semaphore},
+    task, time,
 };
 
+/// Default permissions of files sent with [SshAction::Send].
+pub const DEFAULT_FILE_MODE: i32 = 0o644;
+
+/// Error code returned by libssh2 when the SSH server denies a channel request (e.g. setting a variable).
+const LIBSSH2_ERROR_CHANNEL_REQUEST_DENIED: c_int = -22;
+
+/// Error code returned by libssh2 when the SSH server rejects the credentials.
+const LIBSSH2_ERROR_AUTHENTICATION_FAILED: c_int = -18;
+
+/// Error code returned by libssh2 when the public key could not be verified.
+const LIBSSH2_ERROR_PUBLICKEY_UNVERIFIED: c_int = -19;
+
+/// Checks whether the given error means that the connection cannot succeed without a change in the configuration,
+/// e.g. the credentials were rejected or the host key did not match.
+/// Such errors are reported with [io::ErrorKind::PermissionDenied], so connection attempts should not be retried.
+pub fn is_permanent(error: &io::Error) -> bool {
+    error.kind() == io::ErrorKind::PermissionDenied
+}
+
 /// A command that can be executed by the [SshHandle].
 #[derive(Debug, Deserialize, Clone, Serialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
@@ -24,6 +48,14 @@ pub enum SshAction {
     Exec {
         /// Commang to be executed.
         cmd: String,
+        /// Exit code that means success.
+        /// If [None], any non-zero exit code means failure.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expected_exit_code: Option<i32>,
+        /// Environment variables set for the command.
+        /// If the SSH server does not accept them, they are exported at the beginning of the command.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        env: Vec<(String, String)>,
     },
     /// Sending a file to the remote machine.
     Send {
@@ -31,10 +63,240 @@ pub enum SshAction {
         from: PathBuf,
         /// Path to the destination on the remote machine.
         to: PathBuf,
+        /// Permissions of the destination file.
+        /// If [None], [DEFAULT_FILE_MODE] is used.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mode: Option<i32>,
+    },
+    /// Receiving a file from the remote machine.
+    Receive {
+        /// Path to the source on the remote machine.
+        from: PathBuf,
+        /// Path to the destination on the local machine.
+        to: PathBuf,
+    },
+    /// Waiting without interacting with the remote machine.
+    Sleep {
+        /// Duration of the wait (milliseconds).
+        duration_ms: u64,
+    },
+}
+
+/// Limits for outputs of commands executed by the [SshHandle].
+/// The outputs will be truncated to these lengths.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct OutputLimits {
+    /// Limit for stdout.
+    pub stdout: Option<u64>,
+    /// Limit for stderr.
+    pub stderr: Option<u64>,
+}
+
+impl Display for SshAction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Exec { cmd, .. } => write!(f, "command '{}'", cmd),
+            Self::Send { from, to, .. } => {
+                write!(f, "transfer of {} to {}", from.display(), to.display())
+            }
+            Self::Receive { from, to } => {
+                write!(f, "download of {} to {}", from.display(), to.display())
+            }
+            Self::Sleep { duration_ms } => write!(f, "sleep for {}ms", duration_ms),
+        }
+    }
+}
+
+/// A method of authentication over SSH.
+#[derive(Clone, Debug)]
+pub enum SshAuth {
+    /// Authentication with a password.
+    Password(String),
+    /// Authentication with a key pair.
+    PublicKey {
+        /// Path to the private key on the local machine.
+        private_key: PathBuf,
+        /// Passphrase for the private key, if it is encrypted.
+        passphrase: Option<String>,
+        /// Path to the public key on the local machine.
+        /// If [None], it is derived from the private key.
+        public_key: Option<PathBuf>,
     },
 }
 
-struct Work(SshAction, oneshot::Sender<Output>);
+/// Options for executing [SshAction]s with the [SshHandle].
+#[derive(Clone, Debug)]
+pub struct SshOptions {
+    /// Limits for outputs of executed commands.
+    pub output_limits: OutputLimits,
+    /// Shell used to execute commands, invoked as `<shell> -c '<command>'`.
+    /// If [None], commands are executed directly.
+    pub shell: Option<String>,
+    /// Size of the buffer used for file transfers (bytes).
+    /// If [None], the default buffer of [io::copy] is used.
+    pub transfer_buffer_size: Option<NonZeroUsize>,
+    /// Path to an OpenSSH known hosts file used to verify the host key of the SSH server.
+    /// If [None], the host key is not verified.
+    pub known_hosts: Option<PathBuf>,
+    /// Whether to collect outputs of executed commands while they are running,
+    /// so that the partial output is available if the command times out
+    /// (see [SshHandle::exec_with_timeout]).
+    pub stream_output: bool,
+    /// Interval between connection attempts in [SshHandle::new].
+    pub poll_interval: Duration,
+    /// Permits limiting the number of SSH workers running at once, shared by all connections.
+    /// Every worker occupies a blocking thread from opening the connection until the [SshHandle] is dropped
+    /// and its last [SshAction] finishes. Connections wait for a permit before they are opened.
+    /// If [None], the number of workers is not limited.
+    pub worker_permits: Option<Arc<Semaphore>>,
+}
+
+impl Default for SshOptions {
+    fn default() -> Self {
+        Self {
+            output_limits: Default::default(),
+            shell: None,
+            transfer_buffer_size: None,
+            known_hosts: None,
+            stream_output: false,
+            poll_interval: Duration::from_millis(100),
+            worker_permits: None,
+        }
+    }
+}
+
+/// Quotes the given string for a POSIX shell.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// # Returns
+/// Shell commands exporting the given environment variables, to be prepended to a command.
+/// Fails if any of the names is not a valid variable name.
+fn env_exports(env: &[(String, String)]) -> io::Result<String> {
+    env.iter()
+        .map(|(name, value)| {
+            let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if valid {
+                Ok(format!("export {}={}; ", name, shell_quote(value)))
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid environment variable name '{}'", name),
+                ))
+            }
+        })
+        .collect()
+}
+
+/// Lists the contents of the given local directory recursively.
+/// Symbolic links to files are followed. Symbolic links to directories are skipped,
+/// so that links pointing back up the tree cannot make the walk loop forever.
+/// Entries other than files and directories are skipped as well.
+/// # Returns
+/// Paths of the subdirectories and of the files, relative to the given directory, in sorted order.
+fn walk_dir(root: &Path) -> io::Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let mut dirs = Vec::new();
+    let mut files = Vec::new();
+
+    let mut pending = vec![PathBuf::new()];
+    while let Some(relative) = pending.pop() {
+        for entry in fs::read_dir(root.join(&relative))? {
+            let entry = entry?;
+            let path = relative.join(entry.file_name());
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                dirs.push(path.clone());
+                pending.push(path);
+            } else if file_type.is_file() {
+                files.push(path);
+            } else if file_type.is_symlink() {
+                let metadata = fs::metadata(entry.path())?;
+                if metadata.is_file() {
+                    files.push(path);
+                } else if metadata.is_dir() {
+                    log::debug!(
+                        "Skipping symbolic link to a directory {}.",
+                        entry.path().display()
+                    );
+                }
+            }
+        }
+    }
+
+    dirs.sort_unstable();
+    files.sort_unstable();
+
+    Ok((dirs, files))
+}
+
+/// Reads all data from the reader into the buffer, up to the given limit.
+/// # Arguments
+/// reader - the reader to read from.
+/// limit - limit for the number of bytes to read. If [None], all data is read.
+/// buf - the buffer to read into.
+/// # Returns
+/// Whether the data was truncated, i.e. the limit was reached and more data remained.
+fn read_limited<R: Read>(mut reader: R, limit: Option<u64>, buf: &mut Vec<u8>) -> io::Result<bool> {
+    let limit = match limit {
+        Some(limit) => limit,
+        None => {
+            reader.read_to_end(buf)?;
+            return Ok(false);
+        }
+    };
+
+    let read = (&mut reader).take(limit).read_to_end(buf)?;
+    if (read as u64) < limit {
+        return Ok(false);
+    }
+
+    let mut byte = [0];
+    loop {
+        match reader.read(&mut byte) {
+            Ok(read) => return Ok(read > 0),
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+/// Output of a command collected while it is running.
+#[derive(Default)]
+struct PartialOutput {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    stdout_truncated: bool,
+    stderr_truncated: bool,
+}
+
+/// State of a [Work] shared between the [SshHandle] and the [SshWorker].
+#[derive(Default)]
+struct Progress {
+    /// Output collected so far, if the output is streamed.
+    output: Mutex<PartialOutput>,
+    /// Set by the [SshHandle] when the result is no longer awaited.
+    cancelled: AtomicBool,
+}
+
+/// Appends the data to the buffer, up to the given limit.
+/// # Arguments
+/// buf - the buffer to append to.
+/// truncated - set if some of the data does not fit within the limit.
+/// data - the data to append.
+/// limit - limit for the length of the buffer. If [None], all data is appended.
+fn append_limited(buf: &mut Vec<u8>, truncated: &mut bool, data: &[u8], limit: Option<u64>) {
+    let room = limit
+        .map(|limit| (limit as usize).saturating_sub(buf.len()))
+        .unwrap_or(usize::MAX);
+    if data.len() > room {
+        *truncated = true;
+    }
+    buf.extend_from_slice(&data[..data.len().min(room)]);
+}
+
+struct Work(SshAction, oneshot::Sender<Output>, Arc<Progress>);
 
 /// A worker for executing blocking functions from the [ssh2] crate.
 struct SshWorker {
@@ -42,9 +304,8 @@ struct SshWorker {
     session: Session,
     /// The channel for new [Work] to do.
     receiver: mpsc::Receiver<Work>,
-    /// Limit for stdout and stderr of executed commands.
-    /// The output will be truncated to this length.
-    output_limit: Option<u64>,
+    /// Options for executing actions.
+    options: SshOptions,
 }
 
 impl SshWorker {
@@ -53,31 +314,120 @@ impl SshWorker {
     /// # Arguments
     /// addr - [SocketAddr] to connect to.
     /// username - username of the user to authenticate.
-    /// password - password of the user to authenticate.
+    /// auth - method of authentication.
     /// # Returns
     /// A new SSH [Session].
-    fn open_session(addr: SocketAddr, username: &str, password: &str) -> io::Result<Session> {
-        let conn = TcpStream::connect(&addr)?;
+    fn open_session(
+        addr: SocketAddr,
+        username: &str,
+        auth: &SshAuth,
+        known_hosts: Option<&Path>,
+    ) -> io::Result<Session> {
+        let conn = TcpStream::connect(addr)?;
 
         let mut session = Session::new()?;
         session.set_tcp_stream(conn);
         session.handshake()?;
-        session.userauth_password(username, password)?;
+        if let Some(known_hosts) = known_hosts {
+            Self::verify_host_key(&session, addr, known_hosts)?;
+        }
+        let res = match auth {
+            SshAuth::Password(password) => session.userauth_password(username, password),
+            SshAuth::PublicKey {
+                private_key,
+                passphrase,
+                public_key,
+            } => session.userauth_pubkey_file(
+                username,
+                public_key.as_deref(),
+                private_key,
+                passphrase.as_deref(),
+            ),
+        };
+        res.map_err(|error| Self::auth_error(error, username))?;
 
         Ok(session)
     }
 
+    /// Classifies an error from the authentication.
+    /// # Arguments
+    /// error - the error returned by libssh2.
+    /// username - username of the user that failed to authenticate.
+    /// # Returns
+    /// An [io::ErrorKind::PermissionDenied] error if the credentials were rejected,
+    /// otherwise the original error, as the authentication may succeed when retried.
+    fn auth_error(error: ssh2::Error, username: &str) -> io::Error {
+        match error.code() {
+            ErrorCode::Session(
+                LIBSSH2_ERROR_AUTHENTICATION_FAILED | LIBSSH2_ERROR_PUBLICKEY_UNVERIFIED,
+            ) => io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "SSH authentication failed for user {}: {}",
+                    username,
+                    error.message()
+                ),
+            ),
+            _ => error.into(),
+        }
+    }
+
+    /// Checks the host key of the SSH server against the given known hosts file.
+    /// The key is looked up by the host address only, as the port is forwarded from a random host port.
+    /// Plain `host` entries (as for port 22) are therefore used.
+    /// # Arguments
+    /// session - a session after the handshake.
+    /// addr - [SocketAddr] of the SSH server.
+    /// known_hosts - path to an OpenSSH known hosts file.
+    fn verify_host_key(session: &Session, addr: SocketAddr, known_hosts: &Path) -> io::Result<()> {
+        let (key, _) = session
+            .host_key()
+            .ok_or_else(|| io::Error::other("SSH server did not provide a host key"))?;
+
+        let mut hosts = session.known_hosts()?;
+        hosts.read_file(known_hosts, KnownHostFileKind::OpenSSH)?;
+
+        Self::check_host_key(&hosts, addr, key)
+    }
+
+    /// # Arguments
+    /// hosts - known hosts read from a file.
+    /// addr - [SocketAddr] of the SSH server. Only the address is used for the lookup.
+    /// key - host key of the SSH server.
+    /// # Returns
+    /// An error with [io::ErrorKind::PermissionDenied] if the key is missing or does not match.
+    fn check_host_key(hosts: &KnownHosts, addr: SocketAddr, key: &[u8]) -> io::Result<()> {
+        let rejected = |reason: &str| {
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("host key of {} {}", addr, reason),
+            ))
+        };
+        match hosts.check(&addr.ip().to_string(), key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::Mismatch => rejected("does not match the known hosts file"),
+            CheckResult::NotFound => rejected("is not in the known hosts file"),
+            CheckResult::Failure => rejected("could not be checked"),
+        }
+    }
+
     /// Runs this worker until all of the related [SshAction] [mpsc::Sender]s are dropped.
     /// This is a blocking method.
     fn run(mut self) {
-        while let Some(Work(action, tx)) = self.receiver.blocking_recv() {
+        while let Some(Work(action, tx, progress)) = self.receiver.blocking_recv() {
+            if progress.cancelled.load(Ordering::SeqCst) {
+                continue;
+            }
+
             let res = match action {
-                SshAction::Exec { cmd } => self.exec(&cmd),
-                SshAction::Send { from, to } => self.send(&from, &to).map(|_| Output::Finished {
-                    exit_code: 0,
-                    stdout: Default::default(),
-                    stderr: Default::default(),
-                }),
+                SshAction::Exec { cmd, env, .. } => self.exec(&cmd, &env, &progress),
+                SshAction::Send { from, to, mode } => {
+                    self.send(&from, &to, mode.unwrap_or(DEFAULT_FILE_MODE))
+                }
+                SshAction::Receive { from, to } => {
+                    self.receive(&from, &to).map(|_| Output::empty_success())
+                }
+                SshAction::Sleep { .. } => unreachable!("sleeping is handled by the SshHandle"),
             };
 
             let output = match res {
@@ -93,22 +443,74 @@ impl SshWorker {
     /// This is a blocking method.
     /// # Arguments
     /// cmd - the command to execute.
+    /// env - environment variables for the command.
+    /// progress - receives the output while the command is running, if it is streamed.
     /// # Returns
     /// The [Output] of the command.
-    fn exec(&mut self, cmd: &str) -> io::Result<Output> {
+    fn exec(
+        &mut self,
+        cmd: &str,
+        env: &[(String, String)],
+        progress: &Progress,
+    ) -> io::Result<Output> {
         let mut channel = self.session.channel_session()?;
-        channel.exec(cmd).map_err(io::Error::from)?;
 
-        let mut stdout = Vec::new();
-        match self.output_limit {
-            Some(limit) => (&mut channel).take(limit).read_to_end(&mut stdout)?,
-            None => channel.read_to_end(&mut stdout)?,
+        let mut env_prefixed = false;
+        for (name, value) in env {
+            match channel.setenv(name, value) {
+                Ok(()) => {}
+                // Most SSH servers accept only variables listed in their `AcceptEnv` option.
+                Err(error)
+                    if error.code() == ErrorCode::Session(LIBSSH2_ERROR_CHANNEL_REQUEST_DENIED) =>
+                {
+                    env_prefixed = true;
+                    break;
+                }
+                Err(error) => return Err(error.into()),
+            }
+        }
+
+        let cmd = if env_prefixed {
+            log::debug!(
+                "SSH server rejected environment variables, exporting them in the command."
+            );
+            format!("{}{}", env_exports(env)?, cmd)
+        } else {
+            cmd.to_string()
         };
 
-        let mut stderr = Vec::new();
-        match self.output_limit {
-            Some(limit) => channel.stderr().take(limit).read_to_end(&mut stderr)?,
-            None => channel.stderr().read_to_end(&mut stderr)?,
+        match self.options.shell.as_ref() {
+            Some(shell) => channel.exec(&format!("{} -c {}", shell, shell_quote(&cmd))),
+            None => channel.exec(&cmd),
+        }
+        .map_err(io::Error::from)?;
+
+        let (stdout, stderr, stdout_truncated, stderr_truncated) = if self.options.stream_output {
+            self.session.set_blocking(false);
+            let res = self.stream(&mut channel, progress);
+            self.session.set_blocking(true);
+            res?;
+
+            let output = std::mem::take(&mut *progress.output.lock().unwrap());
+            (
+                output.stdout,
+                output.stderr,
+                output.stdout_truncated,
+                output.stderr_truncated,
+            )
+        } else {
+            let mut stdout = Vec::new();
+            let stdout_truncated =
+                read_limited(&mut channel, self.options.output_limits.stdout, &mut stdout)?;
+
+            let mut stderr = Vec::new();
+            let stderr_truncated = read_limited(
+                channel.stderr(),
+                self.options.output_limits.stderr,
+                &mut stderr,
+            )?;
+
+            (stdout, stderr, stdout_truncated, stderr_truncated)
         };
 
         channel.wait_close()?;
@@ -118,20 +520,180 @@ impl SshWorker {
             exit_code,
             stdout,
             stderr,
+            stdout_truncated,
+            stderr_truncated,
+            env_prefixed,
+        })
+    }
+
+    /// Reads the stdout and the stderr of a running command into the [Progress] as they arrive.
+    /// The session must be in the non-blocking mode.
+    /// This is a blocking method.
+    /// # Returns
+    /// When both streams are closed, or with an error when the [Progress] is cancelled.
+    fn stream(&self, channel: &mut Channel, progress: &Progress) -> io::Result<()> {
+        let limits = &self.options.output_limits;
+        let mut buf = [0; 4096];
+        let (mut stdout_done, mut stderr_done) = (false, false);
+
+        while !(stdout_done && stderr_done) {
+            if progress.cancelled.load(Ordering::SeqCst) {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "command was cancelled",
+                ));
+            }
+
+            let mut idle = true;
+            for stderr in [false, true] {
+                let done = if stderr {
+                    &mut stderr_done
+                } else {
+                    &mut stdout_done
+                };
+                if *done {
+                    continue;
+                }
+
+                let read = if stderr {
+                    channel.stderr().read(&mut buf)
+                } else {
+                    channel.read(&mut buf)
+                };
+                match read {
+                    Ok(0) => *done = true,
+                    Ok(read) => {
+                        idle = false;
+                        let mut output = progress.output.lock().unwrap();
+                        let output = &mut *output;
+                        if stderr {
+                            append_limited(
+                                &mut output.stderr,
+                                &mut output.stderr_truncated,
+                                &buf[..read],
+                                limits.stderr,
+                            );
+                        } else {
+                            append_limited(
+                                &mut output.stdout,
+                                &mut output.stdout_truncated,
+                                &buf[..read],
+                                limits.stdout,
+                            );
+                        }
+                    }
+                    Err(error) if error.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(error) => return Err(error),
+                }
+            }
+
+            if idle {
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Transfers a file or a directory to the remote machine.
+    /// Directories are transferred recursively, see [SshWorker::send_dir].
+    /// This is a blocking method.
+    /// # Arguments
+    /// local - path to the source on the local machine.
+    /// remote - path to the destination on the remote machine.
+    /// mode - permissions of the destination files.
+    fn send(&mut self, local: &Path, remote: &Path, mode: i32) -> io::Result<Output> {
+        if !fs::metadata(local)?.is_dir() {
+            self.send_file(local, remote, mode)?;
+            return Ok(Output::empty_success());
+        }
+
+        let count = self.send_dir(local, remote, mode)?;
+
+        Ok(Output::Finished {
+            exit_code: 0,
+            stdout: format!("{} files transferred\n", count).into_bytes(),
+            stderr: Default::default(),
+            stdout_truncated: false,
+            stderr_truncated: false,
+            env_prefixed: false,
         })
     }
 
+    /// Transfers a directory to the remote machine, preserving its structure.
+    /// Remote directories are created with `mkdir -p`.
+    /// This is a blocking method.
+    /// # Arguments
+    /// local - path to the source directory on the local machine.
+    /// remote - path to the destination directory on the remote machine.
+    /// mode - permissions of the destination files.
+    /// # Returns
+    /// The number of transferred files.
+    fn send_dir(&mut self, local: &Path, remote: &Path, mode: i32) -> io::Result<usize> {
+        let (dirs, files) = walk_dir(local)?;
+
+        let mut mkdir = String::from("mkdir -p --");
+        for dir in
+            std::iter::once(remote.to_path_buf()).chain(dirs.iter().map(|dir| remote.join(dir)))
+        {
+            mkdir.push(' ');
+            mkdir.push_str(&shell_quote(&dir.to_string_lossy()));
+        }
+        let mut channel = self.session.channel_session()?;
+        channel.exec(&mkdir)?;
+        let mut stderr = String::new();
+        channel.stderr().read_to_string(&mut stderr)?;
+        channel.wait_close()?;
+        if channel.exit_status()? != 0 {
+            return Err(io::Error::other(format!(
+                "failed to create remote directories: {}",
+                stderr.trim()
+            )));
+        }
+
+        for file in &files {
+            self.send_file(&local.join(file), &remote.join(file), mode)?;
+        }
+
+        Ok(files.len())
+    }
+
     /// Transfers a file to the remote machine.
     /// This is a blocking method.
     /// # Arguments
     /// local - path to the source file on the local machine.
     /// remote - path to the destination file on the remote machine.
-    fn send(&mut self, local: &Path, remote: &Path) -> io::Result<()> {
+    /// mode - permissions of the destination file.
+    fn send_file(&mut self, local: &Path, remote: &Path, mode: i32) -> io::Result<()> {
         let mut file = File::open(local)?;
         let size = file.metadata()?.len();
 
-        let mut remote_file = self.session.scp_send(remote, 0o777, size, None)?;
-        io::copy(&mut file, &mut remote_file)?;
+        let mut remote_file = self.session.scp_send(remote, mode, size, None)?;
+        self.copy(&mut file, &mut remote_file)?;
+
+        remote_file.send_eof()?;
+        remote_file.wait_eof()?;
+        remote_file.close()?;
+        remote_file.wait_close()?;
+
+        Ok(())
+    }
+
+    /// Transfers a file from the remote machine.
+    /// Missing parent directories of the destination are created.
+    /// This is a blocking method.
+    /// # Arguments
+    /// remote - path to the source file on the remote machine.
+    /// local - path to the destination file on the local machine.
+    fn receive(&mut self, remote: &Path, local: &Path) -> io::Result<()> {
+        let (mut remote_file, _) = self.session.scp_recv(remote)?;
+
+        if let Some(parent) = local.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = File::create(local)?;
+        self.copy(&mut remote_file, &mut file)?;
+        file.flush()?;
 
         remote_file.send_eof()?;
         remote_file.wait_eof()?;
@@ -140,6 +702,41 @@ impl SshWorker {
 
         Ok(())
     }
+
+    /// Copies all data from the reader to the writer,
+    /// using a buffer of the configured size (see [copy_buffered]).
+    fn copy<R: Read, W: Write>(&self, reader: &mut R, writer: &mut W) -> io::Result<()> {
+        copy_buffered(reader, writer, self.options.transfer_buffer_size)
+    }
+}
+
+/// Copies all data from the reader to the writer.
+/// # Arguments
+/// reader - the reader to copy from.
+/// writer - the writer to copy to.
+/// buffer_size - size of the buffer to use. If [None], the default buffer of [io::copy] is used.
+fn copy_buffered<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    buffer_size: Option<NonZeroUsize>,
+) -> io::Result<()> {
+    match buffer_size {
+        Some(buffer_size) => {
+            let mut buf = vec![0; buffer_size.get()];
+            loop {
+                let read = reader.read(&mut buf)?;
+                if read == 0 {
+                    break;
+                }
+                writer.write_all(&buf[..read])?;
+            }
+        }
+        None => {
+            io::copy(reader, writer)?;
+        }
+    }
+
+    Ok(())
 }
 
 /// A handle for executing [SshAction]s on a remote machine.
@@ -149,52 +746,75 @@ pub struct SshHandle {
 }
 
 impl SshHandle {
+    /// Makes repeated attempts to connect to the SSH server, until one of them succeeds
+    /// or fails with a permanent error (see [is_permanent]).
     /// # Arguments
     /// addr - [SocketAddr] of the SSH server.
     /// username - username of the user to authenticate.
-    /// password - password of the user to authenticate.
-    /// output_limit - limit for stdin and stderr of executed commands.
+    /// auth - method of authentication.
+    /// options - options for executing actions.
     /// # Returns
     /// A new instance of this struct.
     pub async fn new(
         addr: SocketAddr,
         username: String,
-        password: String,
-        output_limit: Option<u64>,
+        auth: SshAuth,
+        options: SshOptions,
     ) -> io::Result<Self> {
-        let session = {
-            log::debug!("Establishing an SSH connection to {}.", addr);
-            let guard = Arc::new(());
-            let weak = Arc::downgrade(&guard);
-            task::spawn_blocking(move || {
-                while weak.strong_count() > 0 {
-                    if let Ok(session) = SshWorker::open_session(addr, &username, &password) {
-                        return Some(session);
-                    }
-                    thread::sleep(Duration::from_millis(100));
-                }
+        loop {
+            match Self::connect(addr, username.clone(), auth.clone(), options.clone()).await {
+                Ok(handle) => break Ok(handle),
+                Err(error) if is_permanent(&error) => break Err(error),
+                Err(error) => log::trace!("Failed to connect to {}. Error: {}.", addr, error),
+            }
+            time::sleep(options.poll_interval).await;
+        }
+    }
 
-                None
-            })
-            .await
-            .map_err(|e| {
-                io::Error::new(
-                    io::ErrorKind::Other,
-                    format!("failed to open an SSH connection: {}", e),
-                )
-            })?
-            .expect("task was not cancelled")
+    /// Makes a single attempt to connect to the SSH server.
+    /// # Arguments
+    /// addr - [SocketAddr] of the SSH server.
+    /// username - username of the user to authenticate.
+    /// auth - method of authentication.
+    /// options - options for executing actions.
+    /// # Returns
+    /// A new instance of this struct.
+    pub async fn connect(
+        addr: SocketAddr,
+        username: String,
+        auth: SshAuth,
+        options: SshOptions,
+    ) -> io::Result<Self> {
+        let permit = match options.worker_permits.clone() {
+            Some(permits) => Some(
+                permits
+                    .acquire_owned()
+                    .await
+                    .expect("semaphore should not be closed"),
+            ),
+            None => None,
         };
 
+        log::debug!("Establishing an SSH connection to {}.", addr);
+        let known_hosts = options.known_hosts.clone();
+        let session = task::spawn_blocking(move || {
+            SshWorker::open_session(addr, &username, &auth, known_hosts.as_deref())
+        })
+        .await
+        .map_err(|e| io::Error::other(format!("failed to open an SSH connection: {}", e)))??;
+
         let (tx, rx) = mpsc::channel(1);
 
         let worker = SshWorker {
             session,
             receiver: rx,
-            output_limit,
+            options,
         };
         log::debug!("Spawning a background SSH worker for address {}.", addr);
-        task::spawn_blocking(move || worker.run());
+        task::spawn_blocking(move || {
+            let _permit = permit;
+            worker.run();
+        });
 
         Ok(Self { sender: tx })
     }
@@ -203,10 +823,7 @@ impl SshHandle {
     where
         E: Display,
     {
-        io::Error::new(
-            io::ErrorKind::Other,
-            format!("SSH worker unexpectedly died: {}", error),
-        )
+        io::Error::other(format!("SSH worker unexpectedly died: {}", error))
     }
 
     /// Executes an [SshAction] on the remote machine.
@@ -215,10 +832,49 @@ impl SshHandle {
     /// # Returns
     /// [Output] of the executed action.
     pub async fn exec(&mut self, cmd: SshAction) -> io::Result<Output> {
+        self.send_work(cmd, Default::default()).await
+    }
+
+    /// Executes an [SshAction] on the remote machine, with a timeout.
+    /// When the timeout elapses, a running command is abandoned.
+    /// If [SshOptions::stream_output] is set, the output collected so far is returned in [Output::Timeout].
+    /// # Arguments
+    /// cmd - action to execute.
+    /// timeout - timeout for the action, including the time spent waiting for the worker.
+    /// # Returns
+    /// [Output] of the executed action.
+    pub async fn exec_with_timeout(
+        &mut self,
+        cmd: SshAction,
+        timeout: Duration,
+    ) -> io::Result<Output> {
+        let progress = Arc::new(Progress::default());
+
+        match time::timeout(timeout, self.send_work(cmd, progress.clone())).await {
+            Ok(res) => res,
+            Err(_) => {
+                progress.cancelled.store(true, Ordering::SeqCst);
+                let output = std::mem::take(&mut *progress.output.lock().unwrap());
+
+                Ok(Output::Timeout {
+                    stdout: output.stdout,
+                    stderr: output.stderr,
+                })
+            }
+        }
+    }
+
+    async fn send_work(&mut self, cmd: SshAction, progress: Arc<Progress>) -> io::Result<Output> {
+        // Sleeping does not need the worker, which would be blocked for the whole wait.
+        if let SshAction::Sleep { duration_ms } = cmd {
+            time::sleep(Duration::from_millis(duration_ms)).await;
+            return Ok(Output::empty_success());
+        }
+
         let (tx, rx) = oneshot::channel();
 
         self.sender
-            .send(Work(cmd, tx))
+            .send(Work(cmd, tx, progress))
             .await
             .map_err(Self::worker_died)?;
 
@@ -232,6 +888,258 @@ mod test {
     use crate::{qemu::Image, test_util::Env};
     use tokio::{fs, time};
 
+    #[test]
+    fn known_hosts_lookup() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("known_hosts");
+        let key_blob = |byte: u8| {
+            let mut blob = vec![0, 0, 0, 11];
+            blob.extend_from_slice(b"ssh-ed25519");
+            blob.extend_from_slice(&[0, 0, 0, 32]);
+            blob.extend_from_slice(&[byte; 32]);
+            blob
+        };
+
+        let session = Session::new().unwrap();
+        let mut hosts = session.known_hosts().unwrap();
+        hosts
+            .add(
+                "127.0.0.1",
+                &key_blob(1),
+                "guest",
+                ssh2::KnownHostKeyFormat::Ed255219,
+            )
+            .unwrap();
+        hosts.write_file(&path, KnownHostFileKind::OpenSSH).unwrap();
+
+        let mut hosts = session.known_hosts().unwrap();
+        hosts.read_file(&path, KnownHostFileKind::OpenSSH).unwrap();
+        let forwarded = "127.0.0.1:40022".parse().unwrap();
+        SshWorker::check_host_key(&hosts, forwarded, &key_blob(1))
+            .expect("plain entry should match a forwarded port");
+
+        let error = SshWorker::check_host_key(&hosts, forwarded, &key_blob(2)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        assert!(is_permanent(&error));
+
+        let error =
+            SshWorker::check_host_key(&hosts, "127.0.0.2:40022".parse().unwrap(), &key_blob(1))
+                .unwrap_err();
+        assert!(error.to_string().contains("is not in the known hosts file"));
+    }
+
+    #[test]
+    fn env_exports() {
+        let env = vec![
+            ("PATH".into(), "/opt/bin:/usr/bin".into()),
+            ("_MSG".into(), "it's".into()),
+        ];
+        assert_eq!(
+            super::env_exports(&env).unwrap(),
+            "export PATH='/opt/bin:/usr/bin'; export _MSG='it'\\''s'; "
+        );
+
+        for name in ["", "1A", "A B", "A;B"] {
+            let env = vec![(name.into(), "value".into())];
+            assert_eq!(
+                super::env_exports(&env).unwrap_err().kind(),
+                io::ErrorKind::InvalidInput
+            );
+        }
+    }
+
+    #[test]
+    fn walk_dir() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(tmp.path().join("b/c")).unwrap();
+        std::fs::create_dir(tmp.path().join("d")).unwrap();
+        for file in ["a.txt", "b/x.txt", "b/c/y.txt"] {
+            std::fs::write(tmp.path().join(file), "content").unwrap();
+        }
+
+        let (dirs, files) = super::walk_dir(tmp.path()).unwrap();
+        assert_eq!(dirs, [Path::new("b"), Path::new("b/c"), Path::new("d")]);
+        assert_eq!(
+            files,
+            [
+                Path::new("a.txt"),
+                Path::new("b/c/y.txt"),
+                Path::new("b/x.txt")
+            ]
+        );
+    }
+
+    #[test]
+    fn walk_dir_symlinks() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::create_dir(tmp.path().join("a")).unwrap();
+        std::fs::write(tmp.path().join("a/x.txt"), "content").unwrap();
+        std::os::unix::fs::symlink("..", tmp.path().join("a/loop")).unwrap();
+        std::os::unix::fs::symlink("x.txt", tmp.path().join("a/y.txt")).unwrap();
+
+        let (dirs, files) = super::walk_dir(tmp.path()).unwrap();
+        assert_eq!(dirs, [Path::new("a")]);
+        assert_eq!(files, [Path::new("a/x.txt"), Path::new("a/y.txt")]);
+    }
+
+    #[tokio::test]
+    async fn sleep_without_worker() {
+        let (sender, receiver) = mpsc::channel(1);
+        drop(receiver);
+        let mut handle = SshHandle { sender };
+
+        let output = handle
+            .exec_with_timeout(SshAction::Sleep { duration_ms: 10 }, Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert!(output.success());
+
+        let error = handle
+            .exec(SshAction::Exec {
+                cmd: "true".into(),
+                expected_exit_code: None,
+                env: vec![],
+            })
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("SSH worker unexpectedly died"));
+    }
+
+    #[test]
+    fn buffered_copy() {
+        /// A writer recording the sizes of all writes.
+        #[derive(Default)]
+        struct Recorder {
+            data: Vec<u8>,
+            writes: Vec<usize>,
+        }
+
+        impl Write for Recorder {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.data.extend_from_slice(buf);
+                self.writes.push(buf.len());
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let data = b"0123456789";
+
+        let mut recorder = Recorder::default();
+        copy_buffered(&mut &data[..], &mut recorder, NonZeroUsize::new(4)).unwrap();
+        assert_eq!(recorder.data, data);
+        assert_eq!(recorder.writes, [4, 4, 2]);
+
+        let mut recorder = Recorder::default();
+        copy_buffered(&mut &data[..], &mut recorder, None).unwrap();
+        assert_eq!(recorder.data, data);
+    }
+
+    #[test]
+    fn incremental_truncation() {
+        let (mut buf, mut truncated) = (Vec::new(), false);
+        append_limited(&mut buf, &mut truncated, b"ab", Some(3));
+        append_limited(&mut buf, &mut truncated, b"c", Some(3));
+        assert_eq!(buf, b"abc");
+        assert!(!truncated);
+        append_limited(&mut buf, &mut truncated, b"d", Some(3));
+        assert_eq!(buf, b"abc");
+        assert!(truncated);
+
+        let (mut buf, mut truncated) = (Vec::new(), false);
+        append_limited(&mut buf, &mut truncated, b"abc", None);
+        append_limited(&mut buf, &mut truncated, b"def", None);
+        assert_eq!(buf, b"abcdef");
+        assert!(!truncated);
+    }
+
+    #[test]
+    fn output_truncation() {
+        let mut buf = Vec::new();
+        assert!(!read_limited(&b"abc"[..], None, &mut buf).unwrap());
+        assert_eq!(buf, b"abc");
+
+        let mut buf = Vec::new();
+        assert!(!read_limited(&b"abc"[..], Some(3), &mut buf).unwrap());
+        assert_eq!(buf, b"abc");
+
+        let mut buf = Vec::new();
+        assert!(read_limited(&b"abcd"[..], Some(3), &mut buf).unwrap());
+        assert_eq!(buf, b"abc");
+
+        let mut buf = Vec::new();
+        assert!(read_limited(&b"a"[..], Some(0), &mut buf).unwrap());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(shell_quote("ls -la"), "'ls -la'");
+        assert_eq!(shell_quote("a && b || c"), "'a && b || c'");
+        assert_eq!(shell_quote("echo 'x'"), "'echo '\\''x'\\'''");
+        assert_eq!(shell_quote(""), "''");
+    }
+
+    #[tokio::test]
+    async fn worker_permits() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let permits = Arc::new(Semaphore::new(1));
+        let options = SshOptions {
+            worker_permits: Some(permits.clone()),
+            ..Default::default()
+        };
+        for _ in 0..3 {
+            SshHandle::connect(
+                addr,
+                "root".into(),
+                SshAuth::Password("root".into()),
+                options.clone(),
+            )
+            .await
+            .err()
+            .expect("connection should be refused");
+        }
+        assert_eq!(permits.available_permits(), 1);
+
+        let _held = permits.clone().acquire_owned().await.unwrap();
+        let connect = SshHandle::connect(
+            addr,
+            "root".into(),
+            SshAuth::Password("root".into()),
+            options,
+        );
+        assert!(time::timeout(Duration::from_millis(100), connect)
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn auth_errors() {
+        let rejected = SshWorker::auth_error(
+            ssh2::Error::new(
+                ErrorCode::Session(LIBSSH2_ERROR_AUTHENTICATION_FAILED),
+                "Authentication failed",
+            ),
+            "root",
+        );
+        assert!(is_permanent(&rejected));
+        assert!(rejected
+            .to_string()
+            .contains("SSH authentication failed for user root"));
+
+        let transient = SshWorker::auth_error(
+            ssh2::Error::new(ErrorCode::Session(-7), "Unable to send userauth request"),
+            "root",
+        );
+        assert!(!is_permanent(&transient));
+    }
+
     #[ignore]
     #[tokio::test]
     async fn ls_and_poweroff() {
@@ -244,30 +1152,42 @@ mod test {
                 .create(env.base_image(), Image::Qcow2(image.as_path()))
                 .await
                 .expect("failed to build the image");
-            let qemu = env
+            let mut qemu = env
                 .spawner(1)
-                .spawn(image.into())
+                .spawn(image.into(), vec![])
                 .await
                 .expect("failed to spawn the QEMU process");
 
             let ssh_addr = qemu.ssh().await.expect("failed to get the ssh address");
 
-            let mut ssh_handle = SshHandle::new(ssh_addr, "root".into(), "root".into(), None)
-                .await
-                .expect("failed to get the ssh handle");
+            let mut ssh_handle = SshHandle::new(
+                ssh_addr,
+                "root".into(),
+                SshAuth::Password("root".into()),
+                Default::default(),
+            )
+            .await
+            .expect("failed to get the ssh handle");
 
             ssh_handle
-                .exec(SshAction::Exec { cmd: "ls".into() })
+                .exec(SshAction::Exec {
+                    cmd: "ls".into(),
+                    expected_exit_code: None,
+                    env: vec![],
+                })
                 .await
                 .expect("ls failed");
             ssh_handle
                 .exec(SshAction::Exec {
                     cmd: "/sbin/poweroff".into(),
+                    expected_exit_code: None,
+                    env: vec![],
                 })
                 .await
                 .ok();
 
-            qemu.wait().await.expect("QEMU process failed");
+            let exit = qemu.wait().await.expect("QEMU process failed");
+            assert!(exit.clean());
         })
         .await
         .expect("timeout");
@@ -285,17 +1205,22 @@ mod test {
                 .create(env.base_image(), Image::Qcow2(image.as_path()))
                 .await
                 .expect("failed to build the image");
-            let qemu = env
+            let mut qemu = env
                 .spawner(1)
-                .spawn(image.into())
+                .spawn(image.into(), vec![])
                 .await
                 .expect("failed to spawn the QEMU process");
 
             let ssh_addr = qemu.ssh().await.expect("failed to get the ssh address");
 
-            let mut ssh_handle = SshHandle::new(ssh_addr, "root".into(), "root".into(), None)
-                .await
-                .expect("failed to get the ssh handle");
+            let mut ssh_handle = SshHandle::new(
+                ssh_addr,
+                "root".into(),
+                SshAuth::Password("root".into()),
+                Default::default(),
+            )
+            .await
+            .expect("failed to get the ssh handle");
 
             let file_path = env.base_path().join("file");
             fs::write(&file_path, b"content")
@@ -305,6 +1230,7 @@ mod test {
                 .exec(SshAction::Send {
                     from: file_path.clone(),
                     to: "dst".into(),
+                    mode: Some(0o700),
                 })
                 .await
                 .unwrap();
@@ -312,21 +1238,50 @@ mod test {
             let output = ssh_handle
                 .exec(SshAction::Exec {
                     cmd: "cat dst".into(),
+                    expected_exit_code: None,
+                    env: vec![],
                 })
                 .await
                 .unwrap();
             assert!(output.success());
             let stdout = output.stdout().expect("stdout should exist");
             assert!(String::from_utf8_lossy(stdout).contains("content"),);
+            let output = ssh_handle
+                .exec(SshAction::Exec {
+                    cmd: "stat -c %a dst".into(),
+                    expected_exit_code: None,
+                    env: vec![],
+                })
+                .await
+                .unwrap();
+            assert!(output.success());
+            let stdout = output.stdout().expect("stdout should exist");
+            assert_eq!(String::from_utf8_lossy(stdout).trim(), "700");
+            let downloaded_path = env.base_path().join("downloaded").join("file");
+            let output = ssh_handle
+                .exec(SshAction::Receive {
+                    from: "dst".into(),
+                    to: downloaded_path.clone(),
+                })
+                .await
+                .unwrap();
+            assert!(output.success());
+            let downloaded = fs::read(&downloaded_path)
+                .await
+                .expect("reading the downloaded file failed");
+            assert_eq!(downloaded, b"content");
             let output = ssh_handle
                 .exec(SshAction::Exec {
                     cmd: "/sbin/poweroff".into(),
+                    expected_exit_code: None,
+                    env: vec![],
                 })
                 .await
                 .unwrap();
             assert!(!output.success());
 
-            qemu.wait().await.expect("QEMU process failed");
+            let exit = qemu.wait().await.expect("QEMU process failed");
+            assert!(exit.clean());
         })
         .await
         .expect("timeout");
@@ -1,28 +1,62 @@
-use crate::{patch_validator::Patch, tester::RunReport};
-use std::{collections::HashMap, io, path::PathBuf};
+use crate::{
+    patch_validator::{Patch, ValidationError},
+    tester::{FailureKind, RunReport},
+};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    path::PathBuf,
+};
 
 /// Statistics from [Patch]es processing.
-#[derive(Default)]
+#[derive(Default, Serialize)]
 pub struct Stats {
+    /// Number of all solutions read from the input, both accepted and rejected.
+    pub total_solutions: usize,
     /// Number of solutions that were rejected by the [crate::patch_validator::PatchValidator].
     pub invalid_solutions: usize,
+    /// Number of rejected solutions by the kind of the [ValidationError] (see [ValidationError::kind]).
+    pub rejections: BTreeMap<&'static str, usize>,
     /// Number of solutions that were accepted by the [crate::patch_validator::PatchValidator].
     pub valid_solutions: usize,
+    /// Number of solutions that were skipped, because they had been processed in a previous run.
+    pub resumed_solutions: usize,
     /// Number of solutions that failed to build during the testing process.
     pub builds_failed: usize,
     /// Failures count by test.
     pub test_failures: HashMap<String, usize>,
+    /// Failures caused by timeouts count by test (included in `test_failures`).
+    pub test_timeouts: HashMap<String, usize>,
+    /// Failures caused by SSH errors count by test (included in `test_failures`).
+    pub ssh_failures: HashMap<String, usize>,
+    /// Number of tests that were run.
+    pub tests_run: usize,
+    /// Number of tests that were filtered out and not run.
+    pub tests_skipped: usize,
+    /// Number of tests that were not run because one of their dependencies did not pass.
+    pub tests_blocked: usize,
+    /// Total wall-clock time of processing the solutions (milliseconds).
+    /// See [RunReport::elapsed_ms].
+    pub processing_time_ms: u128,
     /// Solutions for which an internal error occurred during the testing process.
     pub internal_errors: Vec<PathBuf>,
     /// Solutions for which the report was not saved.
     pub missing_reports: Vec<PathBuf>,
+    /// Whether the run was interrupted before all solutions were processed.
+    pub interrupted: bool,
+    /// Version of the QEMU command used in the run, if known
+    /// (see [crate::environment::Environment::qemu_system_release]).
+    pub qemu_system_version: Option<String>,
+    /// Version of the qemu-img command used in the run, if known.
+    pub qemu_img_version: Option<String>,
 }
 
 impl Stats {
     /// # Returns
     /// Whether the whole run was successful (no errors occurred).
     pub fn success(&self) -> bool {
-        self.internal_errors.is_empty() && self.missing_reports.is_empty()
+        self.internal_errors.is_empty() && self.missing_reports.is_empty() && !self.interrupted
     }
 
     /// Updates this struct with info from a finished testing process.
@@ -30,6 +64,7 @@ impl Stats {
     /// patch - processed solution.
     /// result - processing result.
     pub fn patch_processed(&mut self, patch: &Patch, result: &io::Result<RunReport>) {
+        self.total_solutions += 1;
         self.valid_solutions += 1;
 
         match result {
@@ -38,9 +73,26 @@ impl Stats {
                     self.builds_failed += 1;
                 }
 
+                self.tests_run += report.tests().len();
+                self.tests_skipped += report.skipped().len();
+                self.tests_blocked += report.blocked().len();
+                self.processing_time_ms += report.elapsed_ms();
+
                 for (test, report) in report.tests() {
-                    if !report.success() {
-                        *self.test_failures.entry(test.clone()).or_default() += 1;
+                    let kind = match report.failure_kind() {
+                        Some(kind) => kind,
+                        None => continue,
+                    };
+
+                    *self.test_failures.entry(test.clone()).or_default() += 1;
+                    match kind {
+                        FailureKind::Timeout => {
+                            *self.test_timeouts.entry(test.clone()).or_default() += 1
+                        }
+                        FailureKind::Ssh => {
+                            *self.ssh_failures.entry(test.clone()).or_default() += 1
+                        }
+                        FailureKind::Other => {}
                     }
                 }
             }
@@ -50,6 +102,18 @@ impl Stats {
         }
     }
 
+    /// # Returns
+    /// Average wall-clock time of processing a solution (milliseconds),
+    /// if any solution was processed without an internal error.
+    pub fn average_processing_time_ms(&self) -> Option<u128> {
+        let processed = self
+            .valid_solutions
+            .checked_sub(self.internal_errors.len())
+            .filter(|processed| *processed > 0)?;
+
+        Some(self.processing_time_ms / processed as u128)
+    }
+
     /// Updates this struct with info that saving a report failed.
     /// # Arguments
     /// patch - solution for which the report was not saved.
@@ -58,7 +122,87 @@ impl Stats {
     }
 
     /// Updates this struct with info that a solution was rejected by the validator.
-    pub fn solution_rejected(&mut self) {
+    /// # Arguments
+    /// error - the reason of the rejection.
+    pub fn solution_rejected(&mut self, error: &ValidationError) {
+        self.total_solutions += 1;
         self.invalid_solutions += 1;
+        *self.rejections.entry(error.kind()).or_default() += 1;
+    }
+
+    /// Updates this struct with info that a solution was skipped,
+    /// because it had been processed in a previous run.
+    pub fn solution_resumed(&mut self) {
+        self.total_solutions += 1;
+        self.resumed_solutions += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        executor::{ActionReport, ExecutorReport},
+        patch_validator::PatchValidator,
+        ssh::SshAction,
+        tester::ScenarioReport,
+        Output,
+    };
+
+    #[tokio::test]
+    async fn failure_kinds() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ab123456.patch");
+        tokio::fs::write(&path, "").await.unwrap();
+        let patch = PatchValidator::default().validate(&path).await.unwrap();
+
+        let test = |output| {
+            let action = SshAction::Exec {
+                cmd: "./test".into(),
+                expected_exit_code: None,
+                env: vec![],
+            };
+            ScenarioReport::test(vec![ExecutorReport::test(
+                true,
+                vec![ActionReport::test(action, output)],
+            )])
+        };
+        let finished = |exit_code| Output::Finished {
+            exit_code,
+            stdout: vec![],
+            stderr: vec![],
+            stdout_truncated: false,
+            stderr_truncated: false,
+            env_prefixed: false,
+        };
+        let tests = HashMap::from([
+            ("ok".to_string(), test(finished(0))),
+            ("wrong".to_string(), test(finished(1))),
+            (
+                "timeout".to_string(),
+                test(Output::Timeout {
+                    stdout: vec![],
+                    stderr: vec![],
+                }),
+            ),
+            (
+                "ssh".to_string(),
+                ScenarioReport::test(vec![ExecutorReport::test(false, vec![])]),
+            ),
+        ]);
+        let report = RunReport::test(ScenarioReport::test(vec![]), tests);
+
+        let mut stats = Stats::default();
+        stats.patch_processed(&patch, &Ok(report));
+
+        assert_eq!(stats.tests_run, 4);
+        let mut failures = stats.test_failures.keys().cloned().collect::<Vec<_>>();
+        failures.sort_unstable();
+        assert_eq!(failures, ["ssh", "timeout", "wrong"]);
+        assert_eq!(
+            stats.test_timeouts,
+            HashMap::from([("timeout".to_string(), 1)])
+        );
+        assert_eq!(stats.ssh_failures, HashMap::from([("ssh".to_string(), 1)]));
     }
 }
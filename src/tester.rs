@@ -1,18 +1,23 @@
 use crate::{
-    executor::{stack::StackExecutor, ExecutorConfig, ExecutorReport},
+    executor::{stack::StackExecutor, ActionReport, ExecutorConfig, ExecutorReport},
     patch_validator::Patch,
     prepare_dir,
-    qemu::{Image, ImageBuilder, QemuSpawner},
+    qemu::{Drive, Image, ImageBuilder, ImageFormat, QemuSpawner},
     ssh::SshAction,
+    Output,
 };
 use futures::{stream::FuturesUnordered, StreamExt};
-use serde::Serialize;
+use serde::{de::IgnoredAny, Deserialize, Serialize};
 use std::{
-    collections::HashMap,
-    io,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::{self, Display, Formatter},
+    io, iter,
     path::{Path, PathBuf},
-    time::Duration,
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
 };
+use tokio::{fs, time};
 
 /// A single step during building or testing.
 #[derive(Debug)]
@@ -23,24 +28,55 @@ pub enum Step {
         action: SshAction,
         /// Timeout for this action.
         timeout: Duration,
+        /// Number of times this action is executed again if it fails,
+        /// before the [Step] is considered failed.
+        retries: usize,
     },
     /// Transfering the solution to the guest machine.
     TransferPatch {
         /// Path to the destination file on the guest machine.
         to: PathBuf,
+        /// Permissions of the destination file.
+        /// If [None], [crate::ssh::DEFAULT_FILE_MODE] is used.
+        mode: Option<i32>,
         /// Timeout for this transfer.
         timeout: Duration,
     },
+    /// Waiting without interacting with the guest machine.
+    Sleep {
+        /// Duration of the wait.
+        duration: Duration,
+    },
 }
 
 impl Step {
-    fn action(&self, patch: &Path) -> SshAction {
+    fn action(&self, patch: Option<&Path>) -> io::Result<SshAction> {
         match self {
-            Self::Action { action, .. } => action.clone(),
-            Self::TransferPatch { to, .. } => SshAction::Send {
-                from: patch.to_path_buf(),
-                to: to.clone(),
-            },
+            Self::Action { action, .. } => Ok(action.clone()),
+            Self::TransferPatch { to, mode, .. } => {
+                let from = patch.ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "patch transfer step in a scenario that is not run for a solution",
+                    )
+                })?;
+
+                Ok(SshAction::Send {
+                    from: from.to_path_buf(),
+                    to: to.clone(),
+                    mode: *mode,
+                })
+            }
+            Self::Sleep { duration } => Ok(SshAction::Sleep {
+                duration_ms: duration.as_millis().try_into().unwrap_or(u64::MAX),
+            }),
+        }
+    }
+
+    fn retries(&self) -> usize {
+        match self {
+            Self::Action { retries, .. } => *retries,
+            _ => 0,
         }
     }
 
@@ -48,17 +84,62 @@ impl Step {
         match self {
             Self::Action { timeout, .. } => *timeout,
             Self::TransferPatch { timeout, .. } => *timeout,
+            Self::Sleep { duration } => *duration,
         }
     }
 }
 
+/// A blank data disk attached to the guest machine.
+#[derive(Debug, Clone)]
+pub struct DataDisk {
+    /// Virtual size of the disk (megabytes).
+    pub size_mb: u64,
+    /// Interface of the disk (e.g. `virtio`, `ide`).
+    /// If [None], QEMU's default is used.
+    pub interface: Option<String>,
+}
+
+/// The expected outcome of a [Scenario].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Expect {
+    /// All [Step]s should succeed.
+    #[default]
+    Pass,
+    /// One of the [Step]s should fail.
+    /// Failures not caused by a [Step] (e.g. an SSH connection failure) are still treated as failures.
+    Fail,
+}
+
 /// A scenario for the build process or a single test.
 #[derive(Debug, Default)]
 pub struct Scenario {
     /// Number of allowed retries.
     pub retries: usize,
+    /// Delay before every retry.
+    pub retry_delay: Duration,
     /// Stacks of [Step]s to execute with reboots in-between.
     pub steps: Vec<Vec<Step>>,
+    /// Timeouts for the SSH connection at the start of every stack of `steps`.
+    /// Missing and [None] entries mean [ExecutorConfig::connection_timeout].
+    pub boot_timeouts: Vec<Option<Duration>>,
+    /// [Step]s to execute when one of the `steps` fails, before the reboot.
+    /// They are used to collect diagnostics and do not affect the result of the scenario.
+    pub on_failure: Vec<Step>,
+    /// Data disks attached in addition to the main image.
+    /// Fresh disks are created for every attempt.
+    pub disks: Vec<DataDisk>,
+    /// Names of the tests that must pass before this test is run.
+    /// Ignored for scenarios other than tests.
+    pub depends_on: Vec<String>,
+    /// The expected outcome.
+    pub expect: Expect,
+    /// Limit for the time of all attempts, including delays before retries.
+    /// When it elapses, the running attempt is aborted and the scenario fails.
+    /// [None] means no limit.
+    pub total_timeout: Option<Duration>,
+    /// Whether the remaining [Step]s of a stack should be executed after one of them fails.
+    /// The scenario still fails if any of them fails, and the next stacks are not executed.
+    pub continue_on_failure: bool,
 }
 
 /// A config for the whole build-and-test process.
@@ -66,56 +147,716 @@ pub struct Scenario {
 pub struct RunConfig {
     /// Common configuration for the whole process.
     pub execution: ExecutorConfig,
+    /// Configuration of the bootstrap process, run once before any [Patch] is processed.
+    pub bootstrap: Option<Scenario>,
     /// Build process configuration.
     pub build: Scenario,
     /// Test configurations.
     pub tests: HashMap<String, Scenario>,
+    /// Limit for retries consumed by all scenarios of a single [Patch].
+    /// [None] means no limit.
+    pub max_total_retries: Option<usize>,
+    /// Number of the most recent attempts of a [Scenario] whose images are kept.
+    /// Images of older attempts are removed as soon as a newer attempt finishes.
+    /// [None] means that images of all attempts are kept.
+    pub max_kept_attempts: Option<usize>,
+    /// Template of the names of files created for attempts of a [Scenario] (without extensions).
+    /// `{attempt}` is replaced with the number of the attempt and `{scenario}` with the name of the [Scenario].
+    pub attempt_name: String,
+}
+
+impl RunConfig {
+    /// Default value of [RunConfig::attempt_name].
+    pub const DEFAULT_ATTEMPT_NAME: &'static str = "attempt_{attempt}";
+
+    /// # Arguments
+    /// * scenario - name of the [Scenario].
+    /// * attempt - number of the attempt, starting from 1.
+    /// # Returns
+    /// The name of the files created for the attempt (see [RunConfig::attempt_name]).
+    pub fn attempt_name(&self, scenario: &str, attempt: usize) -> String {
+        self.attempt_name
+            .replace("{attempt}", &attempt.to_string())
+            .replace("{scenario}", scenario)
+    }
+}
+
+/// A pool of retries shared by all scenarios of a single [Patch].
+struct RetryBudget(Option<AtomicUsize>);
+
+impl RetryBudget {
+    fn new(limit: Option<usize>) -> Self {
+        Self(limit.map(AtomicUsize::new))
+    }
+
+    /// Consumes a single retry from this budget.
+    /// # Returns
+    /// Whether the retry was granted.
+    fn take(&self) -> bool {
+        match &self.0 {
+            Some(left) => left
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+                    left.checked_sub(1)
+                })
+                .is_ok(),
+            None => true,
+        }
+    }
+}
+
+/// A target for which a [Scenario] is run.
+#[derive(Clone, Copy)]
+enum Target<'a> {
+    /// A student's solution.
+    Patch(&'a Patch),
+    /// The build of a student's solution, producing the image its tests run on.
+    Build(&'a Patch),
+    /// The preparation of the base image shared by all solutions.
+    Bootstrap,
+}
+
+impl<'a> Target<'a> {
+    fn patch_path(self) -> Option<&'a Path> {
+        match self {
+            Self::Patch(patch) | Self::Build(patch) => Some(patch.path()),
+            Self::Bootstrap => None,
+        }
+    }
+
+    /// # Returns
+    /// Whether the image produced for this target becomes a backing image of other scenarios.
+    /// QEMU instances of such targets are never left running, as they would keep writing to the image.
+    fn produces_base(self) -> bool {
+        match self {
+            Self::Patch(_) => false,
+            Self::Build(_) | Self::Bootstrap => true,
+        }
+    }
+}
+
+impl Display for Target<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Patch(patch) | Self::Build(patch) => write!(f, "solution {}", patch),
+            Self::Bootstrap => f.write_str("the bootstrap"),
+        }
+    }
+}
+
+/// A rough classification of the reason a [Scenario] failed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FailureKind {
+    /// A [Step] or the whole [Scenario] timed out.
+    Timeout,
+    /// The SSH connection was not established or broke during a [Step].
+    Ssh,
+    /// Any other reason, e.g. a command exited with an unexpected code.
+    Other,
+}
+
+/// A description of the reason a [Scenario] failed.
+#[derive(Debug, Serialize)]
+pub struct ScenarioFailure {
+    /// Index of the stack of [Step]s in which the failure occurred.
+    pub phase: usize,
+    /// Index of the failed [Step] in the stack, if the failure was caused by a [Step].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub step: Option<usize>,
+    /// The failed action, if the failure was caused by a [Step].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub action: Option<SshAction>,
+    /// The reason of the failure.
+    pub reason: String,
+}
+
+impl Display for ScenarioFailure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match (self.step, self.action.as_ref()) {
+            (Some(step), Some(action)) => write!(
+                f,
+                "at step {} of phase {} ({}): {}",
+                step + 1,
+                self.phase + 1,
+                action,
+                self.reason
+            ),
+            _ => write!(f, "in phase {}: {}", self.phase + 1, self.reason),
+        }
+    }
 }
 
 /// A report from a single [Scenario].
 #[derive(Default, Serialize)]
-pub struct ScenarioReport(Vec<Vec<ExecutorReport>>);
+#[serde(transparent)]
+pub struct ScenarioReport {
+    attempts: Vec<Vec<ExecutorReport>>,
+    #[serde(skip)]
+    expect: Expect,
+    #[serde(skip)]
+    timed_out: Option<Duration>,
+    /// Number of the first attempts whose images were removed (see [RunConfig::max_kept_attempts]).
+    #[serde(skip)]
+    discarded: usize,
+}
 
 impl ScenarioReport {
+    fn new(expect: Expect) -> Self {
+        Self {
+            attempts: Default::default(),
+            expect,
+            timed_out: None,
+            discarded: 0,
+        }
+    }
+
     fn push_attempt(&mut self, attempt: Vec<ExecutorReport>) {
-        self.0.push(attempt);
+        self.attempts.push(attempt);
+    }
+
+    /// # Returns
+    /// The expected outcome of the scenario.
+    pub fn expect(&self) -> Expect {
+        self.expect
+    }
+
+    /// Removes images of the oldest attempts of the scenario, so that at most `max_kept` attempts keep their images.
+    /// The last attempt is never removed, so [ScenarioReport::last_image] remains valid.
+    /// Images of attempts with QEMU processes left running are not removed.
+    /// Errors are logged and ignored, as they do not affect the results.
+    async fn discard_attempts(&mut self, max_kept: usize) {
+        while self.attempts.len() - self.discarded > max_kept.max(1) {
+            let attempt = &self.attempts[self.discarded];
+            self.discarded += 1;
+            if attempt.iter().any(ExecutorReport::kept) {
+                continue;
+            }
+
+            let images = attempt.first().into_iter().flat_map(|report| {
+                iter::once(report.image()).chain(
+                    report
+                        .extra_drives()
+                        .iter()
+                        .map(|drive| drive.path.as_path()),
+                )
+            });
+            for image in images {
+                match fs::remove_file(image).await {
+                    Ok(()) => log::debug!("Removed image {} of an old attempt.", image.display()),
+                    Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+                    Err(error) => log::warn!(
+                        "Failed to remove image {} of an old attempt. Error: {}.",
+                        image.display(),
+                        error
+                    ),
+                }
+            }
+        }
+    }
+
+    /// # Returns
+    /// Images used by attempts of the scenario, excluding the removed ones.
+    fn images(&self) -> impl Iterator<Item = &Path> {
+        self.attempts[self.discarded..]
+            .iter()
+            .filter_map(|attempt| attempt.first())
+            .map(ExecutorReport::image)
+    }
+
+    /// # Returns
+    /// Total time of executing the actions in all attempts of the scenario (milliseconds).
+    /// Does not include booting and shutting down the guest machine.
+    pub fn total_elapsed_ms(&self) -> u128 {
+        self.attempts
+            .iter()
+            .flatten()
+            .flat_map(ExecutorReport::action_reports)
+            .map(ActionReport::elapsed_time_ms)
+            .sum()
+    }
+
+    /// # Returns
+    /// Whether a QEMU instance of the scenario was left running.
+    fn kept(&self) -> bool {
+        self.attempts.iter().flatten().any(ExecutorReport::kept)
+    }
+
+    /// # Returns
+    /// The moment the first QEMU process of the scenario was spawned.
+    fn first_spawn(&self) -> Option<Instant> {
+        self.attempts
+            .iter()
+            .flatten()
+            .filter_map(ExecutorReport::spawned_at)
+            .min()
     }
 
     fn last_image(&self) -> Option<&Path> {
-        let image = self.0.last()?.last()?.image();
+        let image = self.attempts.last()?.last()?.image();
 
         Some(image)
     }
 
     /// # Returns
-    /// Whether the scenario was successful.
+    /// Whether the scenario was successful, taking its expected outcome into account.
     pub fn success(&self) -> bool {
-        self.0
+        self.failure().is_none()
+    }
+
+    /// # Returns
+    /// The total timeout of the scenario, if it elapsed (see [Scenario::total_timeout]).
+    pub fn timed_out(&self) -> Option<Duration> {
+        self.timed_out
+    }
+
+    /// # Returns
+    /// The reason the last attempt of the scenario did not have the expected outcome, if it did not.
+    pub fn failure(&self) -> Option<ScenarioFailure> {
+        if let Some(total_timeout) = self.timed_out {
+            return Some(ScenarioFailure {
+                phase: 0,
+                step: None,
+                action: None,
+                reason: format!(
+                    "exceeded the total timeout of {}ms",
+                    total_timeout.as_millis()
+                ),
+            });
+        }
+
+        let failure = self.step_failure();
+
+        match self.expect {
+            Expect::Pass => failure,
+            Expect::Fail => match failure {
+                Some(failure) if failure.step.is_some() => None,
+                Some(failure) => Some(failure),
+                None => Some(ScenarioFailure {
+                    phase: self
+                        .attempts
+                        .last()
+                        .map(|reports| reports.len().saturating_sub(1))
+                        .unwrap_or_default(),
+                    step: None,
+                    action: None,
+                    reason: "all steps succeeded, but a failure was expected".into(),
+                }),
+            },
+        }
+    }
+
+    /// # Returns
+    /// The kind of the reason the last attempt of the scenario did not have the expected outcome,
+    /// if it did not (see [ScenarioReport::failure]).
+    pub fn failure_kind(&self) -> Option<FailureKind> {
+        self.failure()?;
+        if self.timed_out.is_some() {
+            return Some(FailureKind::Timeout);
+        }
+
+        let report = match self
+            .attempts
             .last()
-            .map(|reports| reports.iter().all(ExecutorReport::success))
-            .unwrap_or(true)
+            .and_then(|attempt| attempt.iter().find(|report| !report.success()))
+        {
+            Some(report) => report,
+            None => return Some(FailureKind::Other),
+        };
+        if !report.ssh_ok() {
+            return Some(FailureKind::Ssh);
+        }
+
+        let kind = report
+            .action_reports()
+            .iter()
+            .filter(|report| !report.diagnostic() && !report.retried())
+            .find(|report| !report.success())
+            .map(|report| match report.output() {
+                Output::Timeout { .. } => FailureKind::Timeout,
+                Output::Error { .. } => FailureKind::Ssh,
+                Output::Finished { .. } => FailureKind::Other,
+            })
+            .unwrap_or(FailureKind::Other);
+
+        Some(kind)
+    }
+
+    /// # Returns
+    /// The reason the last attempt of the scenario failed, if it did.
+    fn step_failure(&self) -> Option<ScenarioFailure> {
+        let (phase, report) = self
+            .attempts
+            .last()?
+            .iter()
+            .enumerate()
+            .find(|(_, report)| !report.success())?;
+
+        let failure = |reason: &str| ScenarioFailure {
+            phase,
+            step: None,
+            action: None,
+            reason: reason.into(),
+        };
+
+        if report.died_before_ssh() {
+            let reason = match report.qemu_exit() {
+                Some(exit) => format!("QEMU process exited before SSH was available ({})", exit),
+                None => "QEMU process exited before SSH was available".into(),
+            };
+            return Some(failure(&reason));
+        }
+        if !report.ssh_ok() {
+            return Some(match report.ssh_error() {
+                Some(error) => failure(&format!("SSH connection was not established: {}", error)),
+                None => failure("SSH connection was not established"),
+            });
+        }
+        if !report.ready_ok() {
+            return Some(failure("guest machine did not become ready"));
+        }
+        if report.no_actions() {
+            return Some(failure("no steps were executed"));
+        }
+
+        let failed_step = report
+            .action_reports()
+            .iter()
+            .filter(|report| !report.retried())
+            .enumerate()
+            .find(|(_, report)| !report.diagnostic() && !report.success());
+        if let Some((step, action_report)) = failed_step {
+            let reason = match action_report.output() {
+                Output::Finished { exit_code, .. } => match action_report.action() {
+                    SshAction::Exec {
+                        expected_exit_code: Some(expected),
+                        ..
+                    } => format!("exited with code {}, expected {}", exit_code, expected),
+                    _ => format!("exited with code {}", exit_code),
+                },
+                Output::Error { error } => error.to_string(),
+                Output::Timeout { .. } => "timed out".into(),
+            };
+
+            return Some(ScenarioFailure {
+                phase,
+                step: Some(step),
+                action: Some(action_report.action().clone()),
+                reason,
+            });
+        }
+
+        Some(failure("QEMU process did not exit cleanly"))
     }
 }
 
 /// A report from the whole build-and-test process.
 #[derive(Serialize)]
 pub struct RunReport {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    meta: Option<serde_json::Value>,
     build: ScenarioReport,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    build_failure: Option<ScenarioFailure>,
     tests: HashMap<String, ScenarioReport>,
+    #[serde(rename = "failed_tests", skip_serializing_if = "Vec::is_empty")]
+    failed: Vec<String>,
+    #[serde(rename = "skipped_tests", skip_serializing_if = "Vec::is_empty")]
+    skipped: Vec<String>,
+    #[serde(rename = "blocked_tests", skip_serializing_if = "BTreeMap::is_empty")]
+    blocked: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    queue_latency_ms: Option<u128>,
+    elapsed_ms: u128,
+    build_elapsed_ms: u128,
+    tests_elapsed_ms: BTreeMap<String, u128>,
 }
 
 impl RunReport {
+    /// # Returns
+    /// Metadata attached to the solution in the input.
+    pub fn meta(&self) -> Option<&serde_json::Value> {
+        self.meta.as_ref()
+    }
+
     /// # Returns
     /// Report from the build scenario.
     pub fn build(&self) -> &ScenarioReport {
         &self.build
     }
 
+    /// # Returns
+    /// The reason the build scenario failed, if it did.
+    pub fn build_failure(&self) -> Option<&ScenarioFailure> {
+        self.build_failure.as_ref()
+    }
+
     /// # Returns
     /// Reports from the test scenarios.
     pub fn tests(&self) -> &HashMap<String, ScenarioReport> {
         &self.tests
     }
+
+    /// # Returns
+    /// Names of the tests that failed.
+    pub fn failed(&self) -> &[String] {
+        &self.failed[..]
+    }
+
+    /// # Returns
+    /// Names of the tests that were filtered out and not run.
+    pub fn skipped(&self) -> &[String] {
+        &self.skipped[..]
+    }
+
+    /// # Returns
+    /// Time from the moment the solution was accepted for processing
+    /// to the moment its first QEMU process was spawned (milliseconds).
+    /// Includes waiting for the limit of concurrent QEMU processes.
+    pub fn queue_latency_ms(&self) -> Option<u128> {
+        self.queue_latency_ms
+    }
+
+    /// # Returns
+    /// Wall-clock time of processing the solution, from the moment it was accepted
+    /// to the moment all of its scenarios finished (milliseconds).
+    pub fn elapsed_ms(&self) -> u128 {
+        self.elapsed_ms
+    }
+
+    /// # Returns
+    /// Names of the tests that were not run because one of their dependencies did not pass,
+    /// mapped to the reasons.
+    pub fn blocked(&self) -> &BTreeMap<String, String> {
+        &self.blocked
+    }
+
+    /// # Returns
+    /// Whether a QEMU instance of any scenario was left running.
+    fn kept(&self) -> bool {
+        self.build.kept() || self.tests.values().any(ScenarioReport::kept)
+    }
+}
+
+/// Parts of a saved [RunReport] needed to rerun its failed tests.
+#[derive(Deserialize)]
+struct SavedRunReport {
+    build_failure: Option<IgnoredAny>,
+    #[serde(default)]
+    failed_tests: Vec<String>,
+    #[serde(default)]
+    blocked_tests: BTreeMap<String, IgnoredAny>,
+}
+
+/// Determines which tests should be rerun based on a previously saved [RunReport].
+/// # Arguments
+/// saved - the [RunReport] serialized as JSON.
+/// # Returns
+/// Names of the tests that failed or were blocked by failed dependencies.
+/// [None] if the build failed or the report could not be parsed, which means that all tests should be run.
+pub fn tests_to_rerun(saved: &[u8]) -> Option<HashSet<String>> {
+    let report: SavedRunReport = serde_json::from_slice(saved).ok()?;
+    if report.build_failure.is_some() {
+        return None;
+    }
+
+    Some(
+        report
+            .failed_tests
+            .into_iter()
+            .chain(report.blocked_tests.into_keys())
+            .collect(),
+    )
+}
+
+/// Naming scheme of the artifacts directories of [Patch]es.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ArtifactsLayout {
+    /// `<artifacts_root>/<id>`.
+    #[default]
+    Flat,
+    /// `<artifacts_root>/<first two characters of id>/<id>`.
+    Sharded,
+    /// `<artifacts_root>/<filename>`.
+    Filename,
+}
+
+impl ArtifactsLayout {
+    /// # Arguments
+    /// * root - root directory for artifacts.
+    /// * patch - the solution.
+    /// # Returns
+    /// The artifacts directory for the given [Patch].
+    pub fn dir(self, root: &Path, patch: &Patch) -> PathBuf {
+        match self {
+            Self::Flat => root.join(patch.id()),
+            Self::Sharded => {
+                // Ids shorter than two characters are their own shards.
+                let shard = patch.id().chars().take(2).collect::<String>();
+                root.join(shard).join(patch.id())
+            }
+            Self::Filename => root.join(patch.path().file_name().unwrap_or_default()),
+        }
+    }
+}
+
+impl FromStr for ArtifactsLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "flat" => Ok(Self::Flat),
+            "sharded" => Ok(Self::Sharded),
+            "filename" => Ok(Self::Filename),
+            other => Err(format!(
+                "invalid layout '{}', expected one of: flat, sharded, filename",
+                other
+            )),
+        }
+    }
+}
+
+/// Policy of keeping the artifacts directories of processed [Patch]es.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum KeepArtifacts {
+    /// Keep artifacts of all [Patch]es.
+    #[default]
+    All,
+    /// Keep artifacts only of [Patch]es that failed to build or failed any test.
+    Failed,
+    /// Do not keep any artifacts.
+    None,
+}
+
+impl KeepArtifacts {
+    /// # Arguments
+    /// * report - report from processing a [Patch].
+    /// # Returns
+    /// Whether the artifacts of the [Patch] should be kept.
+    /// Artifacts are always kept if a QEMU instance was left running on them.
+    pub fn keep(self, report: &RunReport) -> bool {
+        if report.kept() {
+            return true;
+        }
+
+        match self {
+            Self::All => true,
+            Self::Failed => {
+                !report.build().success() || report.tests().values().any(|test| !test.success())
+            }
+            Self::None => false,
+        }
+    }
+}
+
+impl FromStr for KeepArtifacts {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(Self::All),
+            "failed" => Ok(Self::Failed),
+            "none" => Ok(Self::None),
+            other => Err(format!(
+                "invalid policy '{}', expected one of: all, failed, none",
+                other
+            )),
+        }
+    }
+}
+
+/// Format of the result lines printed for processed [Patch]es.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ResultsFormat {
+    /// `<patch>;<OK | comma-separated failed tests | build failed ...>`.
+    #[default]
+    Legacy,
+    /// `<patch>,<build ok: true | false>,<failed test>,<failed test>...`,
+    /// with fields quoted as in RFC 4180 when needed.
+    Csv,
+    /// A JSON object with `id`, `build_ok` and `tests` (test names mapped to results).
+    Jsonl,
+}
+
+impl ResultsFormat {
+    /// # Arguments
+    /// * patch - the processed solution.
+    /// * report - the report from processing the solution.
+    /// # Returns
+    /// The result line for the given [Patch], including the line terminator.
+    pub fn line(self, patch: &Patch, report: &RunReport) -> String {
+        let mut failed_tests = report
+            .tests()
+            .iter()
+            .filter(|(_, report)| !report.success())
+            .map(|(name, _)| &name[..])
+            .collect::<Vec<_>>();
+        failed_tests.sort_unstable();
+
+        match self {
+            Self::Legacy => {
+                let report_col = if report.build().success() {
+                    if failed_tests.is_empty() {
+                        "OK".into()
+                    } else {
+                        failed_tests.join(",")
+                    }
+                } else {
+                    match report.build_failure() {
+                        Some(failure) => format!("build failed {}", failure),
+                        None => "build failed".into(),
+                    }
+                };
+
+                format!("{};{}\n", patch, report_col)
+            }
+            Self::Csv => {
+                let quote = |field: &str| {
+                    if field.contains([',', '"', '\r', '\n']) {
+                        format!("\"{}\"", field.replace('"', "\"\""))
+                    } else {
+                        field.to_string()
+                    }
+                };
+
+                let mut fields = vec![
+                    quote(&patch.to_string()),
+                    report.build().success().to_string(),
+                ];
+                fields.extend(failed_tests.into_iter().map(quote));
+
+                format!("{}\n", fields.join(","))
+            }
+            Self::Jsonl => {
+                let tests = report
+                    .tests()
+                    .iter()
+                    .map(|(name, report)| (name, report.success()))
+                    .collect::<BTreeMap<_, _>>();
+                let line = serde_json::json!({
+                    "id": patch.id(),
+                    "build_ok": report.build().success(),
+                    "tests": tests,
+                });
+
+                format!("{}\n", line)
+            }
+        }
+    }
+}
+
+impl FromStr for ResultsFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "legacy" => Ok(Self::Legacy),
+            "csv" => Ok(Self::Csv),
+            "jsonl" => Ok(Self::Jsonl),
+            other => Err(format!(
+                "invalid results format '{}', expected one of: legacy, csv, jsonl",
+                other
+            )),
+        }
+    }
 }
 
 /// A struct for executing build-and-test processes on [Patch]es.
@@ -126,65 +867,268 @@ pub struct PatchProcessor {
     pub builder: ImageBuilder,
     /// Path to the base QEMU image.
     pub base_image: PathBuf,
+    /// Format of the base QEMU image.
+    pub base_image_format: ImageFormat,
     /// Configuration for the process.
     pub run_config: RunConfig,
     /// Root directory for artifacts.
     pub artifacts_root: PathBuf,
+    /// Names of the tests to run.
+    /// If [None], all tests are run.
+    pub test_filter: Option<HashSet<String>>,
+    /// Path to the image produced by the bootstrap scenario.
+    /// If set, it is used instead of the base image.
+    pub bootstrapped_image: Option<PathBuf>,
+    /// Whether to compress images once they are no longer used.
+    pub compress_artifacts: bool,
+    /// Naming scheme of the artifacts directories of [Patch]es.
+    pub artifacts_layout: ArtifactsLayout,
+    /// Policy of keeping the artifacts directories of processed [Patch]es.
+    pub keep_artifacts: KeepArtifacts,
+    /// Whether every test should run on its own standalone copy of the build image,
+    /// instead of sharing the build image as the backing image.
+    pub isolate_tests: bool,
 }
 
 impl PatchProcessor {
+    /// Name of the standalone copy of the build image in the test directory,
+    /// used if [PatchProcessor::isolate_tests] is set.
+    const ISOLATED_IMAGE: &'static str = "isolated.qcow2";
+
     async fn run_scenario(
         &self,
-        patch: &Patch,
+        target: Target<'_>,
         base_image: Image<'_>,
         artifacts: &Path,
         scenario: &Scenario,
         name: &str,
+        budget: &RetryBudget,
     ) -> io::Result<ScenarioReport> {
-        let mut report = ScenarioReport::default();
+        let mut report = ScenarioReport::new(scenario.expect);
 
-        for i in 0..=scenario.retries {
-            log::info!(
-                "Initializing attempt {} of scenario {} for solution {}.",
-                i + 1,
-                name,
-                patch
-            );
+        // Dropping the attempts on timeout kills the running QEMU process.
+        let attempts = async {
+            for i in 0..=scenario.retries {
+                if i > 0 && !budget.take() {
+                    log::info!(
+                        "Retry limit for {} exhausted, scenario {} will not be retried.",
+                        target,
+                        name
+                    );
+                    break;
+                }
 
-            let dst = artifacts.join(format!("attempt_{}.qcow2", i + 1));
-            self.builder
-                .create(base_image, Image::Qcow2(dst.as_ref()))
-                .await?;
+                if i > 0 && !scenario.retry_delay.is_zero() {
+                    time::sleep(scenario.retry_delay).await;
+                }
 
-            let mut executor =
-                StackExecutor::new(&self.run_config.execution, &self.spawner, dst.as_os_str());
+                log::info!(
+                    "Initializing attempt {} of scenario {} for {}.",
+                    i + 1,
+                    name,
+                    target
+                );
 
-            for phase in &scenario.steps {
-                let iter = phase
-                    .iter()
-                    .map(|step| (step.action(patch.path()), step.timeout()));
+                let attempt_name = self.run_config.attempt_name(name, i + 1);
+                let dst = artifacts.join(format!("{}.qcow2", attempt_name));
+                self.builder
+                    .create(base_image, Image::Qcow2(dst.as_ref()))
+                    .await?;
 
-                let success = executor.open_stack().await?.run_until_failure(iter).await?;
-                if !success {
+                let mut extra_drives = Vec::with_capacity(scenario.disks.len());
+                for (j, disk) in scenario.disks.iter().enumerate() {
+                    let path = artifacts.join(format!("{}_disk_{}.qcow2", attempt_name, j + 1));
+                    self.builder.create_blank(&path, disk.size_mb).await?;
+                    extra_drives.push(Drive {
+                        path,
+                        format: ImageFormat::Qcow2,
+                        interface: disk.interface.clone(),
+                    });
+                }
+
+                let scratch_dir = artifacts.join(format!("{}_scratch", attempt_name));
+                prepare_dir(scratch_dir.as_path()).await?;
+
+                let mut executor = StackExecutor::new(
+                    &self.run_config.execution,
+                    &self.spawner,
+                    dst.as_os_str(),
+                    &extra_drives,
+                    scratch_dir.as_path(),
+                );
+                if target.produces_base() {
+                    executor.forbid_keeping();
+                }
+
+                let to_actions = |steps: &[Step]| {
+                    steps
+                        .iter()
+                        .map(|step| {
+                            Ok((
+                                step.action(target.patch_path())?,
+                                step.timeout(),
+                                step.retries(),
+                            ))
+                        })
+                        .collect::<io::Result<Vec<_>>>()
+                };
+                let on_failure = to_actions(&scenario.on_failure)?
+                    .into_iter()
+                    .map(|(action, timeout, _)| (action, timeout))
+                    .collect::<Vec<_>>();
+
+                for (j, phase) in scenario.steps.iter().enumerate() {
+                    let actions = to_actions(phase)?;
+
+                    let boot_timeout = scenario.boot_timeouts.get(j).copied().flatten();
+                    let mut stack = executor.open_stack_with_timeout(boot_timeout).await?;
+                    if j + 1 == scenario.steps.len() {
+                        stack.mark_last();
+                    }
+                    let steps = actions.len();
+                    let res = stack
+                        .run_until_failure(
+                            actions.into_iter(),
+                            on_failure.iter().cloned(),
+                            scenario.continue_on_failure,
+                        )
+                        .await?;
+                    if let Err(idx) = res {
+                        if idx < steps {
+                            log::info!(
+                                "Attempt {} of scenario {} failed for {} at step {} of stack {} ({:?}).",
+                                i + 1,
+                                name,
+                                target,
+                                idx + 1,
+                                j + 1,
+                                phase[idx],
+                            );
+                        } else {
+                            log::info!(
+                                "Attempt {} of scenario {} failed for {} after all steps of stack {}.",
+                                i + 1,
+                                name,
+                                target,
+                                j + 1,
+                            );
+                        }
+                        break;
+                    }
+                }
+
+                let attempt = executor.finish();
+                report.push_attempt(attempt);
+                if let Some(max_kept) = self.run_config.max_kept_attempts {
+                    report.discard_attempts(max_kept).await;
+                }
+
+                if report.success() {
+                    break;
+                }
+            }
+
+            io::Result::Ok(())
+        };
+        match scenario.total_timeout {
+            Some(total_timeout) => match time::timeout(total_timeout, attempts).await {
+                Ok(res) => res?,
+                Err(_) => {
                     log::info!(
-                        "Attempt {} of scenario {} failed for solution {}.",
-                        i + 1,
+                        "Scenario {} for {} exceeded its total timeout of {}ms.",
                         name,
-                        patch
+                        target,
+                        total_timeout.as_millis()
                     );
-                    break;
+                    report.timed_out = Some(total_timeout);
                 }
+            },
+            None => attempts.await?,
+        }
+
+        Ok(report)
+    }
+
+    /// Compresses images used by the scenario, if configured.
+    /// Errors are logged and ignored, as they do not affect the results.
+    /// # Arguments
+    /// report - report from the scenario.
+    /// backing - image the scenario was run on.
+    async fn compress_images(&self, report: &ScenarioReport, backing: Image<'_>) {
+        if !self.compress_artifacts || report.kept() {
+            return;
+        }
+
+        for image in report.images() {
+            if let Err(error) = self.builder.compress(image, backing).await {
+                log::warn!("Failed to compress an artifact. Error: {}.", error);
             }
+        }
+    }
+
+    /// # Returns
+    /// The image all solutions are built upon.
+    pub fn base(&self) -> Image<'_> {
+        match self.bootstrapped_image.as_ref() {
+            Some(image) => Image::Qcow2(image.as_path()),
+            None => Image::new(self.base_image.as_path(), self.base_image_format),
+        }
+    }
+
+    /// Runs the bootstrap scenario (if configured) on the base image.
+    /// If the scenario succeeds, the resulting image becomes the base image for all [Patch]es.
+    /// # Returns
+    /// A [ScenarioReport] from the bootstrap scenario, if it was run.
+    pub async fn bootstrap(&mut self) -> io::Result<Option<ScenarioReport>> {
+        let scenario = match self.run_config.bootstrap.as_ref() {
+            Some(scenario) => scenario,
+            None => return Ok(None),
+        };
 
-            let attempt = executor.finish();
-            report.push_attempt(attempt);
+        log::info!("Bootstrapping the base image.");
+        let root = self.artifacts_root.join("bootstrap");
+        prepare_dir(root.as_path()).await?;
 
-            if report.success() {
-                break;
+        let budget = RetryBudget::new(None);
+        let report = self
+            .run_scenario(
+                Target::Bootstrap,
+                self.base(),
+                root.as_path(),
+                scenario,
+                "bootstrap",
+                &budget,
+            )
+            .await?;
+
+        if report.success() {
+            if let Some(image) = report.last_image() {
+                self.builder.seal(image).await?;
             }
+            self.bootstrapped_image = report.last_image().map(Path::to_path_buf);
         }
 
-        Ok(report)
+        Ok(Some(report))
+    }
+
+    /// # Arguments
+    /// * patch - the processed solution.
+    /// * report - report from processing the solution.
+    /// # Returns
+    /// The artifacts directory of the [Patch], if it should be removed according to `keep_artifacts`.
+    pub fn artifacts_to_discard(&self, patch: &Patch, report: &RunReport) -> Option<PathBuf> {
+        if self.keep_artifacts.keep(report) {
+            None
+        } else {
+            Some(self.artifacts_layout.dir(&self.artifacts_root, patch))
+        }
+    }
+
+    fn should_run(&self, test: &str, filter: Option<&HashSet<String>>) -> bool {
+        [self.test_filter.as_ref(), filter]
+            .into_iter()
+            .flatten()
+            .all(|filter| filter.contains(test))
     }
 
     /// Executes the build-and-test process for a single [Patch].
@@ -193,53 +1137,150 @@ impl PatchProcessor {
     /// # Returns
     /// A [RunReport] from the process.
     pub async fn process(&self, patch: &Patch) -> io::Result<RunReport> {
-        let root = self.artifacts_root.join(patch.id());
+        self.process_filtered(patch, None).await
+    }
+
+    /// Executes the build-and-test process for a single [Patch],
+    /// running only the tests accepted by the given filter (in addition to the `test_filter`).
+    /// # Arguments
+    /// patch - the solution to process.
+    /// filter - names of the tests to run. If [None], no additional filtering is done.
+    /// # Returns
+    /// A [RunReport] from the process.
+    pub async fn process_filtered<'a>(
+        &'a self,
+        patch: &'a Patch,
+        filter: Option<&'a HashSet<String>>,
+    ) -> io::Result<RunReport> {
+        let accepted = Instant::now();
+
+        let root = self.artifacts_layout.dir(&self.artifacts_root, patch);
         prepare_dir(root.as_path()).await?;
 
+        let budget = RetryBudget::new(self.run_config.max_total_retries);
+
         log::info!("Building a test image for solution {}.", patch);
         let build_root = root.join("build");
         prepare_dir(build_root.as_path()).await?;
 
         let build = self
             .run_scenario(
-                patch,
-                Image::Raw(self.base_image.as_path()),
+                Target::Build(patch),
+                self.base(),
                 build_root.as_path(),
                 &self.run_config.build,
                 "build",
+                &budget,
             )
             .await?;
 
-        let tests = if build.success() {
+        let mut blocked = BTreeMap::new();
+        let tests = if build.success() && build.expect() == Expect::Fail {
+            log::info!(
+                "Build process failed as expected for solution {}, tests will not be run.",
+                patch
+            );
+
+            Default::default()
+        } else if build.success() {
             log::info!("Running tests for solution {}.", patch);
             let tests_root = root.join("tests");
             prepare_dir(tests_root.as_path()).await?;
 
-            let test_image = build
-                .last_image()
-                .map(Image::Qcow2)
-                .unwrap_or(Image::Raw(self.base_image.as_path()));
+            // Tests run concurrently on top of the build image, so it must not be modified from now on.
+            // Sealing is only advisory, the real guarantee is that no QEMU process has the image open.
+            if build.kept() {
+                return Err(io::Error::other(format!(
+                    "the build instance of solution {} is still running, its image cannot back the tests",
+                    patch
+                )));
+            }
+            if let Some(image) = build.last_image() {
+                self.builder.seal(image).await?;
+            }
+            let test_image = build.last_image().map(Image::Qcow2).unwrap_or(self.base());
 
-            let mut futs = FuturesUnordered::new();
-            for (test, scenario) in &self.run_config.tests {
+            let budget = &budget;
+            let tests_root = &tests_root;
+            let run_test = |test: &'a String, scenario: &'a Scenario| async move {
                 let test_root = tests_root.join(test);
-                futs.push(async move {
-                    prepare_dir(test_root.as_path()).await?;
-                    let report = self
-                        .run_scenario(patch, test_image, test_root.as_path(), scenario, test)
-                        .await?;
-                    Ok::<_, io::Error>((test.clone(), report))
-                });
-            }
+                prepare_dir(test_root.as_path()).await?;
 
-            let mut tests = HashMap::new();
-            while let Some(result) = futs.next().await {
-                match result {
-                    Ok((test, report)) => {
+                let isolated = test_root.join(Self::ISOLATED_IMAGE);
+                let test_image = if self.isolate_tests {
+                    self.builder.flatten(test_image, &isolated).await?;
+                    self.builder.seal(&isolated).await?;
+                    Image::Qcow2(isolated.as_path())
+                } else {
+                    test_image
+                };
+
+                let report = self
+                    .run_scenario(
+                        Target::Patch(patch),
+                        test_image,
+                        test_root.as_path(),
+                        scenario,
+                        test,
+                        budget,
+                    )
+                    .await?;
+                self.compress_images(&report, test_image).await;
+                Ok::<_, io::Error>((test.clone(), report))
+            };
+
+            let mut pending = self
+                .run_config
+                .tests
+                .iter()
+                .filter(|(test, _)| self.should_run(test, filter))
+                .collect::<Vec<_>>();
+            let mut futs = FuturesUnordered::new();
+            let mut tests: HashMap<String, ScenarioReport> = HashMap::new();
+            loop {
+                // Start all tests with resolved dependencies.
+                // Dependencies that were filtered out are treated as satisfied.
+                let mut i = 0;
+                while i < pending.len() {
+                    let (test, scenario) = pending[i];
+                    let mut dependencies = scenario
+                        .depends_on
+                        .iter()
+                        .filter(|dependency| self.should_run(dependency, filter));
+
+                    let failed = dependencies.clone().find(|dependency| {
+                        blocked.contains_key(*dependency)
+                            || tests
+                                .get(*dependency)
+                                .map(|report| !report.success())
+                                .unwrap_or(false)
+                    });
+                    if let Some(dependency) = failed {
+                        log::info!(
+                            "Test {} will not be run for solution {}, dependency {} did not pass.",
+                            test,
+                            patch,
+                            dependency
+                        );
+                        blocked.insert(
+                            test.clone(),
+                            format!("dependency {} did not pass", dependency),
+                        );
+                        pending.swap_remove(i);
+                    } else if dependencies.all(|dependency| tests.contains_key(dependency)) {
+                        futs.push(run_test(test, scenario));
+                        pending.swap_remove(i);
+                    } else {
+                        i += 1;
+                    }
+                }
+
+                match futs.next().await {
+                    Some(Ok((test, report))) => {
                         log::info!("Received report from test {} for solution {}.", test, patch);
-                        tests.insert(test.clone(), report);
+                        tests.insert(test, report);
                     }
-                    Err(error) => {
+                    Some(Err(error)) => {
                         log::error!(
                             "An unexpected error occurred when running tests for solution {}. Error: {}.",
                             patch,
@@ -247,9 +1288,14 @@ impl PatchProcessor {
                         );
                         return Err(error);
                     }
+                    None => break,
                 }
             }
 
+            for (test, _) in pending {
+                blocked.insert(test.clone(), "unresolved dependencies".into());
+            }
+
             tests
         } else {
             log::info!("Build process failed for solution {}.", patch);
@@ -257,7 +1303,80 @@ impl PatchProcessor {
             Default::default()
         };
 
-        Ok(RunReport { build, tests })
+        self.compress_images(&build, self.base()).await;
+
+        let queue_latency_ms = build
+            .first_spawn()
+            .or_else(|| tests.values().filter_map(ScenarioReport::first_spawn).min())
+            .map(|spawned_at| spawned_at.duration_since(accepted).as_millis());
+
+        let mut skipped = self
+            .run_config
+            .tests
+            .keys()
+            .filter(|test| !self.should_run(test, filter))
+            .cloned()
+            .collect::<Vec<_>>();
+        skipped.sort_unstable();
+
+        let mut failed = tests
+            .iter()
+            .filter(|(_, report)| !report.success())
+            .map(|(test, _)| test.clone())
+            .collect::<Vec<_>>();
+        failed.sort_unstable();
+
+        let tests_elapsed_ms = tests
+            .iter()
+            .map(|(test, report)| (test.clone(), report.total_elapsed_ms()))
+            .collect();
+
+        Ok(RunReport {
+            meta: patch.meta().cloned(),
+            build_failure: build.failure(),
+            build_elapsed_ms: build.total_elapsed_ms(),
+            build,
+            tests,
+            failed,
+            skipped,
+            blocked,
+            queue_latency_ms,
+            elapsed_ms: accepted.elapsed().as_millis(),
+            tests_elapsed_ms,
+        })
+    }
+}
+
+#[cfg(test)]
+impl ScenarioReport {
+    /// # Returns
+    /// A report from a single attempt of a [Scenario] expected to pass, for tests.
+    pub fn test(attempt: Vec<ExecutorReport>) -> Self {
+        Self {
+            attempts: vec![attempt],
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+impl RunReport {
+    /// # Returns
+    /// A report with the given build and tests reports, for tests.
+    pub fn test(build: ScenarioReport, tests: HashMap<String, ScenarioReport>) -> Self {
+        Self {
+            meta: None,
+            build_failure: build.failure(),
+            build,
+            tests,
+            failed: vec![],
+            skipped: vec![],
+            blocked: Default::default(),
+            queue_latency_ms: None,
+            elapsed_ms: 0,
+            build_elapsed_ms: 0,
+            tests_elapsed_ms: Default::default(),
+        }
     }
 }
 
@@ -265,8 +1384,273 @@ impl PatchProcessor {
 mod test {
     use super::*;
     use crate::{patch_validator::PatchValidator, test_util::Env};
+    use regex::Regex;
+    use test_case::test_case;
     use tokio::{fs, time};
 
+    #[test]
+    fn patch_transfer_without_patch() {
+        let step = Step::TransferPatch {
+            to: "solution.patch".into(),
+            mode: None,
+            timeout: Duration::from_secs(1),
+        };
+
+        step.action(None)
+            .expect_err("patch transfer without a patch should fail");
+        let action = step
+            .action(Some("ab123456.patch".as_ref()))
+            .expect("patch transfer with a patch should not fail");
+        assert!(
+            matches!(action, SshAction::Send { from, to, .. } if from == Path::new("ab123456.patch") && to == Path::new("solution.patch"))
+        );
+    }
+
+    #[test]
+    fn scenario_failure_display() {
+        let failure = ScenarioFailure {
+            phase: 0,
+            step: Some(2),
+            action: Some(SshAction::Exec {
+                cmd: "./build.sh".into(),
+                expected_exit_code: None,
+                env: vec![],
+            }),
+            reason: "exited with code 1".into(),
+        };
+        assert_eq!(
+            failure.to_string(),
+            "at step 3 of phase 1 (command './build.sh'): exited with code 1"
+        );
+
+        let failure = ScenarioFailure {
+            phase: 1,
+            step: None,
+            action: None,
+            reason: "SSH connection was not established".into(),
+        };
+        assert_eq!(
+            failure.to_string(),
+            "in phase 2: SSH connection was not established"
+        );
+    }
+
+    #[tokio::test]
+    async fn results_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ab123456.patch");
+        fs::write(&path, "").await.unwrap();
+        let patch = PatchValidator::default().validate(&path).await.unwrap();
+
+        let failed = || ScenarioReport {
+            timed_out: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        let report = RunReport::test(
+            Default::default(),
+            HashMap::from([
+                ("a,\"b\"".to_string(), failed()),
+                ("c".to_string(), failed()),
+                ("d".to_string(), Default::default()),
+            ]),
+        );
+
+        assert_eq!(
+            ResultsFormat::Legacy.line(&patch, &report),
+            format!("{};a,\"b\",c\n", path.display())
+        );
+        assert_eq!(
+            ResultsFormat::Csv.line(&patch, &report),
+            format!("{},true,\"a,\"\"b\"\"\",c\n", path.display())
+        );
+
+        let line = ResultsFormat::Jsonl.line(&patch, &report);
+        assert!(line.ends_with('\n'));
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "id": "ab123456",
+                "build_ok": true,
+                "tests": {"a,\"b\"": false, "c": false, "d": true},
+            })
+        );
+
+        assert_eq!("jsonl".parse(), Ok(ResultsFormat::Jsonl));
+        assert!("json".parse::<ResultsFormat>().is_err());
+    }
+
+    #[tokio::test]
+    async fn artifacts_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ab123456.patch");
+        fs::write(&path, "").await.unwrap();
+        let patch = PatchValidator::default().validate(&path).await.unwrap();
+
+        let root = Path::new("/artifacts");
+        assert_eq!(
+            ArtifactsLayout::Flat.dir(root, &patch),
+            Path::new("/artifacts/ab123456")
+        );
+        assert_eq!(
+            ArtifactsLayout::Sharded.dir(root, &patch),
+            Path::new("/artifacts/ab/ab123456")
+        );
+        assert_eq!(
+            ArtifactsLayout::Filename.dir(root, &patch),
+            Path::new("/artifacts/ab123456.patch")
+        );
+        assert!("nested".parse::<ArtifactsLayout>().is_err());
+    }
+
+    #[test_case("a.patch", "/artifacts/a/a")]
+    #[test_case("żółw.patch", "/artifacts/żó/żółw")]
+    #[test_case("x.1.patch", "/artifacts/x./x.1")]
+    #[tokio::test]
+    async fn artifacts_layout_sharded(filename: &str, expected: &str) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(filename);
+        fs::write(&path, "").await.unwrap();
+        let patch = PatchValidator::with_pattern(Regex::new(r".+\.patch").unwrap())
+            .validate(&path)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            ArtifactsLayout::Sharded.dir(Path::new("/artifacts"), &patch),
+            Path::new(expected)
+        );
+    }
+
+    #[tokio::test]
+    async fn attempts_retention() {
+        let run_config = RunConfig {
+            execution: ExecutorConfig::test(),
+            bootstrap: None,
+            build: Default::default(),
+            tests: Default::default(),
+            max_total_retries: None,
+            max_kept_attempts: Some(2),
+            attempt_name: "{scenario}_try_{attempt}".into(),
+        };
+        assert_eq!(run_config.attempt_name("build", 3), "build_try_3");
+
+        let dir = tempfile::tempdir().unwrap();
+        let image = |i| {
+            dir.path()
+                .join(format!("{}.qcow2", run_config.attempt_name("test", i)))
+        };
+        let mut report = ScenarioReport::default();
+        for i in 1..=3 {
+            fs::write(image(i), "").await.unwrap();
+            report.push_attempt(vec![
+                ExecutorReport::test(false, vec![]).with_image(image(i))
+            ]);
+            report.discard_attempts(2).await;
+        }
+
+        assert!(!image(1).exists());
+        assert!(image(2).exists());
+        assert!(image(3).exists());
+        assert_eq!(report.attempts.len(), 3);
+        assert_eq!(
+            report.images().collect::<Vec<_>>(),
+            [image(2).as_path(), image(3).as_path()]
+        );
+        assert_eq!(report.last_image(), Some(image(3).as_path()));
+    }
+
+    #[test]
+    fn keep_artifacts() {
+        let scenario = |exit_code| {
+            let action = SshAction::Exec {
+                cmd: "./test".into(),
+                expected_exit_code: None,
+                env: vec![],
+            };
+            let output = Output::Finished {
+                exit_code,
+                stdout: vec![],
+                stderr: vec![],
+                stdout_truncated: false,
+                stderr_truncated: false,
+                env_prefixed: false,
+            };
+            ScenarioReport::test(vec![ExecutorReport::test(
+                true,
+                vec![ActionReport::test(action, output)],
+            )])
+        };
+
+        let passed = RunReport::test(scenario(0), HashMap::from([("a".into(), scenario(0))]));
+        let test_failed = RunReport::test(scenario(0), HashMap::from([("a".into(), scenario(1))]));
+        let build_failed = RunReport::test(scenario(1), HashMap::new());
+
+        assert!(KeepArtifacts::All.keep(&passed));
+        assert!(!KeepArtifacts::Failed.keep(&passed));
+        assert!(KeepArtifacts::Failed.keep(&test_failed));
+        assert!(KeepArtifacts::Failed.keep(&build_failed));
+        assert!(!KeepArtifacts::None.keep(&build_failed));
+
+        assert_eq!("failed".parse(), Ok(KeepArtifacts::Failed));
+        assert!("some".parse::<KeepArtifacts>().is_err());
+    }
+
+    #[test]
+    fn expected_failure() {
+        let report = ScenarioReport::new(Expect::Pass);
+        assert!(report.success());
+
+        let report = ScenarioReport::new(Expect::Fail);
+        assert!(!report.success());
+        assert_eq!(
+            report.failure().unwrap().reason,
+            "all steps succeeded, but a failure was expected"
+        );
+    }
+
+    #[test]
+    fn total_timeout() {
+        for expect in [Expect::Pass, Expect::Fail] {
+            let mut report = ScenarioReport::new(expect);
+            report.timed_out = Some(Duration::from_secs(60));
+            assert!(!report.success());
+            assert_eq!(
+                report.failure().unwrap().reason,
+                "exceeded the total timeout of 60000ms"
+            );
+        }
+    }
+
+    #[test]
+    fn rerun_from_saved_report() {
+        let saved = br#"{"build": [], "tests": {}, "failed_tests": ["a", "b"], "blocked_tests": {"c": "dependency b did not pass"}}"#;
+        assert_eq!(
+            tests_to_rerun(saved),
+            Some(HashSet::from(["a".into(), "b".into(), "c".into()]))
+        );
+
+        let saved = br#"{"build": [], "tests": {}}"#;
+        assert_eq!(tests_to_rerun(saved), Some(HashSet::new()));
+
+        let saved = br#"{"build": [], "build_failure": {"phase": 0, "reason": "SSH connection was not established"}, "tests": {}}"#;
+        assert_eq!(tests_to_rerun(saved), None);
+
+        assert_eq!(tests_to_rerun(b"not json"), None);
+    }
+
+    #[test]
+    fn retry_budget() {
+        let unlimited = RetryBudget::new(None);
+        assert!((0..100).all(|_| unlimited.take()));
+
+        let limited = RetryBudget::new(Some(2));
+        assert!(limited.take());
+        assert!(limited.take());
+        assert!(!limited.take());
+        assert!(!limited.take());
+    }
+
     #[ignore]
     #[tokio::test]
     async fn concurrent_tests() {
@@ -291,14 +1675,18 @@ mod test {
             spawner: env.spawner(3),
             builder: env.builder(),
             base_image: env.base_image().path().into(),
+            base_image_format: env.base_image().format(),
             run_config: RunConfig {
                 execution: ExecutorConfig::test(),
+                bootstrap: None,
                 build: Scenario {
                     retries: 0,
                     steps: vec![vec![Step::TransferPatch {
                         to: "patch".into(),
+                        mode: None,
                         timeout: Duration::from_secs(1),
                     }]],
+                    ..Default::default()
                 },
                 tests: HashMap::from([(
                     "test".into(),
@@ -307,13 +1695,26 @@ mod test {
                         steps: vec![vec![Step::Action {
                             action: SshAction::Exec {
                                 cmd: "./patch".into(),
+                                expected_exit_code: None,
+                                env: vec![],
                             },
                             timeout: Duration::from_secs(1),
+                            retries: 0,
                         }]],
+                        ..Default::default()
                     },
                 )]),
+                max_total_retries: None,
+                max_kept_attempts: None,
+                attempt_name: RunConfig::DEFAULT_ATTEMPT_NAME.into(),
             },
             artifacts_root: env.base_path().join("artifacts"),
+            test_filter: None,
+            bootstrapped_image: None,
+            compress_artifacts: false,
+            isolate_tests: false,
+            artifacts_layout: Default::default(),
+            keep_artifacts: Default::default(),
         };
 
         let proc = &processor;
@@ -341,12 +1742,105 @@ mod test {
         }
 
         assert!(report_0.tests().get("test").unwrap().success());
-        assert_eq!(report_0.tests().get("test").unwrap().0.len(), 1);
+        assert_eq!(report_0.tests().get("test").unwrap().attempts.len(), 1);
+        let test_elapsed_ms = report_0.tests().get("test").unwrap().total_elapsed_ms();
+        assert_eq!(report_0.tests_elapsed_ms["test"], test_elapsed_ms);
+        assert!(report_0.elapsed_ms() >= report_0.build().total_elapsed_ms() + test_elapsed_ms);
 
         assert!(!report_1.tests().get("test").unwrap().success());
-        assert_eq!(report_1.tests().get("test").unwrap().0.len(), 2);
+        assert_eq!(report_1.tests().get("test").unwrap().attempts.len(), 2);
 
         assert!(!report_2.tests().get("test").unwrap().success());
-        assert_eq!(report_2.tests().get("test").unwrap().0.len(), 2);
+        assert_eq!(report_2.tests().get("test").unwrap().attempts.len(), 2);
+    }
+
+    #[ignore]
+    #[tokio::test]
+    async fn shared_build_image() {
+        let env = Env::read();
+
+        let path = env.base_path().join("aa000000.patch");
+        fs::write(&path, "").await.expect("failed to write file");
+        let patch = PatchValidator::default()
+            .validate(&path)
+            .await
+            .expect("failed to validate patch");
+
+        // Every test writes to its disk, which would corrupt a shared build image if it was writable.
+        let tests = (0..8)
+            .map(|i| {
+                let scenario = Scenario {
+                    retries: 0,
+                    steps: vec![vec![Step::Action {
+                        action: SshAction::Exec {
+                            cmd: format!(
+                                "dd if=/dev/zero of=/root/test_{} bs=1k count=1024 && sync",
+                                i
+                            ),
+                            expected_exit_code: Some(0),
+                            env: vec![],
+                        },
+                        timeout: Duration::from_secs(20),
+                        retries: 0,
+                    }]],
+                    ..Default::default()
+                };
+                (format!("test_{}", i), scenario)
+            })
+            .collect();
+
+        let processor = PatchProcessor {
+            spawner: env.spawner(8),
+            builder: env.builder(),
+            base_image: env.base_image().path().into(),
+            base_image_format: env.base_image().format(),
+            run_config: RunConfig {
+                execution: ExecutorConfig::test(),
+                bootstrap: None,
+                build: Scenario {
+                    retries: 0,
+                    steps: vec![vec![Step::TransferPatch {
+                        to: "patch".into(),
+                        mode: None,
+                        timeout: Duration::from_secs(1),
+                    }]],
+                    ..Default::default()
+                },
+                tests,
+                max_total_retries: None,
+                max_kept_attempts: None,
+                attempt_name: RunConfig::DEFAULT_ATTEMPT_NAME.into(),
+            },
+            artifacts_root: env.base_path().join("artifacts"),
+            test_filter: None,
+            bootstrapped_image: None,
+            compress_artifacts: false,
+            isolate_tests: false,
+            artifacts_layout: Default::default(),
+            keep_artifacts: Default::default(),
+        };
+
+        let report = time::timeout(Duration::from_secs(300), processor.process(&patch))
+            .await
+            .expect("timeout")
+            .expect("testing failed");
+
+        assert!(report.build().success());
+        assert_eq!(report.tests().len(), 8);
+        assert!(report.tests().values().all(ScenarioReport::success));
+
+        let build_image = report.build().last_image().unwrap();
+        assert!(fs::metadata(build_image)
+            .await
+            .unwrap()
+            .permissions()
+            .readonly());
+        let output = tokio::process::Command::new(&env.builder().cmd)
+            .arg("check")
+            .arg(build_image)
+            .output()
+            .await
+            .expect("failed to run qemu-img");
+        assert!(output.status.success(), "build image is corrupted");
     }
 }